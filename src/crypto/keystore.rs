@@ -0,0 +1,353 @@
+//! 密钥库
+//!
+//! Mifare Classic密钥、NTAG密码和数据加密密钥按应用分成命名的密钥集，保存在用口令加密的文件中，
+//! 其他操作按名称引用密钥，不需要每次请求都传递密钥。
+//! 密钥可以按AN10922(AES-128)由主密钥和卡片UID分散，每张卡片的密钥都不同:
+//! 分散输入 M = UID || AID || SystemIdentifier，D = 0x01 || M，D不足32字节时补0x80和0，
+//! 最后一块异或CMAC子密钥K2(不需要补位时异或K1)，AES-CBC-MAC的结果为分散后的密钥。
+//!
+//! 加密文件为JSON: {"version": 1, "iterations": N, "salt": hex, "nonce": hex, "data": hex}，
+//! 口令经PBKDF2-HMAC-SHA256得到AES-256-GCM密钥，data为密钥库明文JSON加密后的结果。
+
+use aes::cipher::BlockEncrypt;
+use aes::Aes128;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::rand_core::RngCore;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::path::Path;
+use crate::ntag::Card;
+use super::{card_uid, PayloadKeys};
+
+const STORE_VERSION: u8 = 1;
+/// 默认PBKDF2迭代次数
+pub const DEFAULT_ITERATIONS: u32 = 600_000;
+const SALT_LEN: usize = 16;
+/// AN10922分散输入M的最大长度
+const MAX_DIVERSIFY_INPUT: usize = 31;
+
+/// 密钥库中的一个密钥
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeyEntry{
+    /// 密钥，diversify为true时是16字节的AES-128主密钥
+    #[serde(with = "crate::hex_serde")]
+    key: Vec<u8>,
+    /// 按卡片UID分散
+    #[serde(default)]
+    diversify: bool,
+    /// 分散后取前len字节，例如Mifare Classic密钥6字节、NTAG密码4字节
+    len: Option<usize>,
+}
+
+/// 一个应用的密钥集
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeySet{
+    /// 名称 -> 密钥
+    #[serde(default)]
+    keys: BTreeMap<String, KeyEntry>,
+    /// 分散输入中的应用标识
+    #[serde(default, with = "crate::hex_serde")]
+    aid: Vec<u8>,
+    /// 分散输入中的系统标识
+    #[serde(default, with = "crate::hex_serde")]
+    system_id: Vec<u8>,
+    /// 数据加密和签名的密钥，格式同 --payload-keys
+    payload: Option<PayloadKeys>,
+}
+
+/// 密钥集概要，不包含密钥的值
+#[derive(Debug, Clone, Serialize)]
+pub struct KeySetInfo{
+    pub name: String,
+    pub keys: Vec<KeyInfo>,
+    /// 是否包含数据加密和签名的密钥
+    pub payload: bool,
+}
+
+/// 密钥概要，不包含密钥的值
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyInfo{
+    pub name: String,
+    pub diversify: bool,
+    pub len: usize,
+}
+
+impl KeyEntry{
+    fn len(&self) -> usize{
+        match (self.diversify, self.len){
+            (_, Some(len)) => len,
+            (true, None) => 16,
+            (false, None) => self.key.len(),
+        }
+    }
+
+    fn check(&self) -> Result<()>{
+        if self.diversify && self.key.len() != 16{
+            return Err(anyhow!("分散的主密钥需要16字节"));
+        }
+        let max = if self.diversify{ 16 }else{ self.key.len() };
+        if self.len() == 0 || self.len() > max{
+            return Err(anyhow!("len需要在1~{}之间", max));
+        }
+        Ok(())
+    }
+}
+
+impl KeySet{
+    /// 数据加密和签名的密钥
+    pub fn payload(&self) -> Option<&PayloadKeys>{
+        self.payload.as_ref()
+    }
+
+    /// 按名称取密钥，需要分散的密钥使用uid分散
+    pub fn key(&self, name: &str, uid: &[u8]) -> Result<Vec<u8>>{
+        let entry = self.keys.get(name).ok_or_else(|| anyhow!("密钥不存在:{}", name))?;
+        if !entry.diversify{
+            return Ok(entry.key[..entry.len()].to_vec());
+        }
+        let mut input = uid.to_vec();
+        input.extend_from_slice(&self.aid);
+        input.extend_from_slice(&self.system_id);
+        let master: [u8; 16] = entry.key.as_slice().try_into().expect("加载时已检查长度");
+        Ok(diversify_aes128(&master, &input)?[..entry.len()].to_vec())
+    }
+
+    /// 按名称取当前卡片的密钥
+    pub fn card_key(&self, card: &mut Card, name: &str) -> Result<Vec<u8>>{
+        let uid = card_uid(card)?;
+        self.key(name, &uid)
+    }
+}
+
+/// 加密的密钥库文件
+#[derive(Debug, Deserialize, Serialize)]
+struct SealedStore{
+    version: u8,
+    iterations: u32,
+    #[serde(with = "crate::hex_serde")]
+    salt: Vec<u8>,
+    #[serde(with = "crate::hex_serde")]
+    nonce: Vec<u8>,
+    #[serde(with = "crate::hex_serde")]
+    data: Vec<u8>,
+}
+
+/// 密钥库，密钥集名称 -> 密钥集
+#[derive(Debug, Clone, Deserialize)]
+#[serde(transparent)]
+pub struct KeyStore{
+    sets: BTreeMap<String, KeySet>,
+}
+
+fn store_key(passphrase: &str, salt: &[u8], iterations: u32) -> [u8; 32]{
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, iterations, &mut key);
+    key
+}
+
+impl KeyStore{
+    /// 解析明文JSON并检查每个密钥
+    pub fn parse(text: &str) -> Result<KeyStore>{
+        let store: KeyStore = serde_json::from_str(text).context("密钥库格式错误")?;
+        for (set_name, set) in &store.sets{
+            for (name, entry) in &set.keys{
+                entry.check().with_context(|| format!("密钥{}/{}错误", set_name, name))?;
+            }
+        }
+        Ok(store)
+    }
+
+    /// 用口令加密明文JSON，返回加密文件的内容
+    pub fn seal(text: &str, passphrase: &str, iterations: u32) -> Result<String>{
+        KeyStore::parse(text)?;
+        let mut salt = vec![0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let key = store_key(passphrase, &salt, iterations);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let data = Aes256Gcm::new(&key.into()).encrypt(&nonce, text.as_bytes()).map_err(|_| anyhow!("加密失败"))?;
+        let sealed = SealedStore{ version: STORE_VERSION, iterations, salt, nonce: nonce.to_vec(), data };
+        Ok(serde_json::to_string_pretty(&sealed)?)
+    }
+
+    /// 读取加密的密钥库文件
+    pub fn open(path: impl AsRef<Path>, passphrase: &str) -> Result<KeyStore>{
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("读取密钥库失败 {}", path.display()))?;
+        let sealed: SealedStore = serde_json::from_str(&text).with_context(|| format!("密钥库格式错误 {}", path.display()))?;
+        if sealed.version != STORE_VERSION{
+            return Err(anyhow!("不支持的密钥库版本:{}", sealed.version));
+        }
+        if sealed.nonce.len() != 12{
+            return Err(anyhow!("密钥库已损坏 {}", path.display()));
+        }
+        let key = store_key(passphrase, &sealed.salt, sealed.iterations);
+        let data = Aes256Gcm::new(&key.into()).decrypt(sealed.nonce.as_slice().into(), sealed.data.as_slice())
+            .map_err(|_| anyhow!("密钥库口令错误或文件已损坏 {}", path.display()))?;
+        KeyStore::parse(&String::from_utf8(data)?)
+    }
+
+    /// 按名称取密钥集
+    pub fn set(&self, name: &str) -> Result<&KeySet>{
+        self.sets.get(name).ok_or_else(|| anyhow!("密钥集不存在:{}", name))
+    }
+
+    /// 所有密钥集的概要
+    pub fn info(&self) -> Vec<KeySetInfo>{
+        self.sets.iter().map(|(name, set)| KeySetInfo{
+            name: name.clone(),
+            keys: set.keys.iter().map(|(name, entry)| KeyInfo{ name: name.clone(), diversify: entry.diversify, len: entry.len() }).collect(),
+            payload: set.payload.is_some(),
+        }).collect()
+    }
+}
+
+/// CMAC子密钥的左移: 最高位为1时异或0x87
+fn shift_subkey(block: &[u8; 16]) -> [u8; 16]{
+    let mut out = [0u8; 16];
+    for i in 0..16{
+        out[i] = block[i] << 1 | block.get(i + 1).map(|b| b >> 7).unwrap_or(0);
+    }
+    if block[0] & 0x80 != 0{
+        out[15] ^= 0x87;
+    }
+    out
+}
+
+/// AN10922 AES-128密钥分散，input为M(UID || AID || SystemIdentifier)，最多31字节
+pub fn diversify_aes128(master: &[u8; 16], input: &[u8]) -> Result<[u8; 16]>{
+    if input.len() > MAX_DIVERSIFY_INPUT{
+        return Err(anyhow!("分散输入过长 最多{}字节", MAX_DIVERSIFY_INPUT));
+    }
+    let cipher = Aes128::new(master.into());
+    let encrypt = |block: [u8; 16]| -> [u8; 16]{
+        let mut block = block.into();
+        cipher.encrypt_block(&mut block);
+        block.into()
+    };
+    let k1 = shift_subkey(&encrypt([0u8; 16]));
+    let k2 = shift_subkey(&k1);
+
+    let mut data = vec![0x01];
+    data.extend_from_slice(input);
+    let subkey = if data.len() < 32{
+        data.push(0x80);
+        data.resize(32, 0);
+        k2
+    }else{
+        k1
+    };
+    for (byte, key) in data[16..].iter_mut().zip(subkey){
+        *byte ^= key;
+    }
+    let mut mac = [0u8; 16];
+    for chunk in data.chunks(16){
+        for (m, b) in mac.iter_mut().zip(chunk){
+            *m ^= b;
+        }
+        mac = encrypt(mac);
+    }
+    Ok(mac)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const MASTER: &str = "00112233445566778899aabbccddeeff";
+    const UID: &str = "04782e21801d80";
+
+    fn store_text() -> String{
+        format!(r#"{{"shop": {{
+            "aid": "3042f5",
+            "system_id": "4e585020416275",
+            "keys": {{
+                "sector": {{ "key": "{}", "diversify": true, "len": 6 }},
+                "aes": {{ "key": "{}", "diversify": true }},
+                "static": {{ "key": "a0a1a2a3a4a5", "len": 4 }}
+            }}
+        }}}}"#, MASTER, MASTER)
+    }
+
+    fn master() -> [u8; 16]{
+        hex::decode(MASTER).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn an10922_reference_vector(){
+        //AN10922 2.2.1 AES-128密钥分散示例
+        let input = hex::decode(format!("{}3042f54e585020416275", UID)).unwrap();
+        let key = diversify_aes128(&master(), &input).unwrap();
+        assert_eq!(hex::encode_upper(key), "A8DD63A3B89D54B37CA802473FDA9175");
+    }
+
+    #[test]
+    fn diversify_input_length(){
+        //D正好32字节时不补位，使用K1
+        let full = diversify_aes128(&master(), &[0x5A; MAX_DIVERSIFY_INPUT]).unwrap();
+        let padded = diversify_aes128(&master(), &[0x5A; MAX_DIVERSIFY_INPUT - 1]).unwrap();
+        assert_ne!(full, padded);
+        assert!(diversify_aes128(&master(), &[0; MAX_DIVERSIFY_INPUT + 1]).is_err());
+    }
+
+    #[test]
+    fn cmac_subkeys(){
+        //RFC 4493 的子密钥示例
+        let l = hex::decode("7df76b0c1ab899b33e42f047b91b546f").unwrap().try_into().unwrap();
+        let k1 = shift_subkey(&l);
+        assert_eq!(hex::encode(k1), "fbeed618357133667c85e08f7236a8de");
+        assert_eq!(hex::encode(shift_subkey(&k1)), "f7ddac306ae266ccf90bc11ee46d513b");
+    }
+
+    #[test]
+    fn key_set_diversifies_by_uid(){
+        let store = KeyStore::parse(&store_text()).unwrap();
+        let set = store.set("shop").unwrap();
+        let uid = hex::decode(UID).unwrap();
+        assert_eq!(hex::encode_upper(set.key("aes", &uid).unwrap()), "A8DD63A3B89D54B37CA802473FDA9175");
+        assert_eq!(hex::encode_upper(set.key("sector", &uid).unwrap()), "A8DD63A3B89D");
+        assert_ne!(set.key("sector", &[4, 1, 2, 3, 4, 5, 6]).unwrap(), set.key("sector", &uid).unwrap());
+        assert_eq!(set.key("static", &uid).unwrap(), [0xA0, 0xA1, 0xA2, 0xA3]);
+        assert!(set.key("missing", &uid).is_err());
+        assert!(store.set("other").is_err());
+
+        let info = store.info();
+        let lens: Vec<(&str, usize)> = info[0].keys.iter().map(|key| (key.name.as_str(), key.len)).collect();
+        assert_eq!(lens, [("aes", 16), ("sector", 6), ("static", 4)]);
+    }
+
+    #[test]
+    fn bad_entries_are_rejected(){
+        for entry in [
+            r#"{ "key": "0011", "diversify": true }"#,
+            format!(r#"{{ "key": "{}", "diversify": true, "len": 17 }}"#, MASTER).as_str(),
+            r#"{ "key": "a0a1a2", "len": 4 }"#,
+            r#"{ "key": "a0a1a2", "len": 0 }"#,
+        ]{
+            assert!(KeyStore::parse(&format!(r#"{{"s": {{"keys": {{"k": {}}}}}}}"#, entry)).is_err(), "{}", entry);
+        }
+    }
+
+    #[test]
+    fn sealed_store_round_trip(){
+        let sealed = KeyStore::seal(&store_text(), "口令", 1000).unwrap();
+        assert!(!sealed.contains(MASTER));
+        let path = std::env::temp_dir().join(format!("xelc-keystore-test-{}.json", std::process::id()));
+        std::fs::write(&path, &sealed).unwrap();
+        let opened = KeyStore::open(&path, "口令");
+        let wrong = KeyStore::open(&path, "other");
+        let mut file: serde_json::Value = serde_json::from_str(&sealed).unwrap();
+        file["version"] = 2.into();
+        std::fs::write(&path, file.to_string()).unwrap();
+        let version = KeyStore::open(&path, "口令");
+        std::fs::remove_file(&path).unwrap();
+
+        let uid = hex::decode(UID).unwrap();
+        assert_eq!(opened.unwrap().set("shop").unwrap().key("sector", &uid).unwrap(), [0xA8, 0xDD, 0x63, 0xA3, 0xB8, 0x9D]);
+        assert!(wrong.is_err());
+        assert!(version.is_err());
+        assert!(KeyStore::seal("{\"s\": {\"keys\": {\"k\": {\"key\": \"00\", \"len\": 2}}}}", "口令", 1000).is_err());
+    }
+}
@@ -0,0 +1,34 @@
+//! 卡片数据的加密和签名、密钥库、防克隆令牌
+
+mod keystore;
+mod payload;
+mod rolling;
+mod sign;
+
+pub use keystore::{diversify_aes128, KeyInfo, KeySet, KeySetInfo, KeyStore, DEFAULT_ITERATIONS};
+pub use payload::{open_frame, seal_frame, Cipher, Opened, PayloadKeys};
+pub use rolling::{roll_token, RollingEntry, RollingLedger, RollingOutcome};
+pub use sign::{check_signature, sign_frame, Signature, SIGNATURE_LEN};
+
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::ntag::Card;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// 按UID派生每张卡片的密钥: HMAC-SHA256(master, label || uid)
+pub(crate) fn derive_key(master: &[u8], label: &[u8], uid: &[u8]) -> [u8; 32]{
+    let mut mac = HmacSha256::new_from_slice(master).expect("HMAC接受任意长度的密钥");
+    mac.update(label);
+    mac.update(uid);
+    mac.finalize().into_bytes().into()
+}
+
+/// 当前卡片的UID: 绑定的UID、最近读取的UID，都没有时立即读取
+pub(crate) fn card_uid(card: &mut Card) -> Result<Vec<u8>>{
+    if let Some(uid) = card.bound_uid().or(card.cached_uid()){
+        return Ok(uid.to_vec());
+    }
+    card.read_uid()?.ok_or_else(|| anyhow!("无卡片"))
+}
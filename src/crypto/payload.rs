@@ -0,0 +1,264 @@
+//! 数据加密
+//!
+//! 每张卡片的密钥由主密钥和UID派生，加密后的数据保存为带长度头的数据(内容类型encrypted):
+//! 算法(1) | 密钥编号(1) | 原内容类型(1) | nonce(12) | 密文和16字节认证标签。
+//! 前3字节和UID作为附加数据参与认证，数据被修改或复制到其他卡片时解密失败。
+//! 密钥编号保存在卡片上，更换主密钥后旧卡片仍然可以用旧密钥读取，重新写入时使用当前密钥。
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::Aes256Gcm;
+use anyhow::{anyhow, Context, Result};
+use chacha20poly1305::ChaCha20Poly1305;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use crate::ntag::Card;
+use crate::storage::{encode_frame, ContentType, Frame};
+use super::{card_uid, derive_key};
+
+const HEADER_LEN: usize = 3;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// 派生卡片密钥时使用的标签
+const KEY_LABEL: &[u8] = b"xelc-payload";
+
+/// 加密算法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum Cipher{
+    #[default]
+    #[serde(rename = "aes-gcm")]
+    AesGcm,
+    #[serde(rename = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+}
+
+impl Cipher{
+    fn to_u8(self) -> u8{
+        match self{
+            Cipher::AesGcm => 1,
+            Cipher::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    fn from_u8(code: u8) -> Option<Cipher>{
+        match code{
+            1 => Some(Cipher::AesGcm),
+            2 => Some(Cipher::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    fn seal(self, key: &[u8; 32], nonce: &[u8], payload: Payload) -> Result<Vec<u8>>{
+        let sealed = match self{
+            Cipher::AesGcm => Aes256Gcm::new(key.into()).encrypt(nonce.into(), payload),
+            Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new(key.into()).encrypt(nonce.into(), payload),
+        };
+        sealed.map_err(|_| anyhow!("加密失败"))
+    }
+
+    fn open(self, key: &[u8; 32], nonce: &[u8], payload: Payload) -> Result<Vec<u8>>{
+        let opened = match self{
+            Cipher::AesGcm => Aes256Gcm::new(key.into()).decrypt(nonce.into(), payload),
+            Cipher::ChaCha20Poly1305 => ChaCha20Poly1305::new(key.into()).decrypt(nonce.into(), payload),
+        };
+        opened.map_err(|_| anyhow!("解密失败 数据被篡改、复制自其他卡片或密钥错误"))
+    }
+}
+
+/// 密钥文件格式
+#[derive(Debug, Deserialize)]
+struct KeysFile{
+    #[serde(default)]
+    cipher: Cipher,
+    current: u8,
+    /// 密钥编号 -> 32字节主密钥(hex)
+    keys: BTreeMap<u8, String>,
+}
+
+/// 数据加密和签名的主密钥，按编号保存，新数据使用current加密或签名
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "KeysFile")]
+pub struct PayloadKeys{
+    cipher: Cipher,
+    current: u8,
+    keys: BTreeMap<u8, [u8; 32]>,
+}
+
+impl std::fmt::Debug for PayloadKeys{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result{
+        f.debug_struct("PayloadKeys").field("cipher", &self.cipher).field("current", &self.current)
+            .field("keys", &self.keys.keys().collect::<Vec<_>>()).finish()
+    }
+}
+
+/// 解密后的数据
+#[derive(Debug, Clone)]
+pub struct Opened{
+    pub frame: Frame,
+    /// 加密使用的密钥编号，数据没有加密时为空
+    pub key_id: Option<u8>,
+}
+
+impl TryFrom<KeysFile> for PayloadKeys{
+    type Error = anyhow::Error;
+
+    fn try_from(file: KeysFile) -> Result<PayloadKeys>{
+        let mut keys = BTreeMap::new();
+        for (id, key) in file.keys{
+            let key = hex::decode(key).ok().and_then(|key| <[u8; 32]>::try_from(key).ok())
+                .ok_or_else(|| anyhow!("密钥{}需要32字节(64个hex字符)", id))?;
+            keys.insert(id, key);
+        }
+        PayloadKeys::new(file.cipher, file.current, keys)
+    }
+}
+
+impl PayloadKeys{
+    /// 创建，current必须在keys中
+    pub fn new(cipher: Cipher, current: u8, keys: BTreeMap<u8, [u8; 32]>) -> Result<PayloadKeys>{
+        if !keys.contains_key(&current){
+            return Err(anyhow!("当前密钥编号{}不存在", current));
+        }
+        Ok(PayloadKeys{ cipher, current, keys })
+    }
+
+    /// 从JSON文件加载: {"cipher": "aes-gcm", "current": 2, "keys": {"1": "hex", "2": "hex"}}
+    pub fn load(path: impl AsRef<Path>) -> Result<PayloadKeys>{
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("读取密钥文件失败 {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("密钥文件格式错误 {}", path.display()))
+    }
+
+    /// 当前密钥编号
+    pub fn current(&self) -> u8{
+        self.current
+    }
+
+    /// 编号对应的主密钥
+    pub(crate) fn master(&self, key_id: u8) -> Option<&[u8; 32]>{
+        self.keys.get(&key_id)
+    }
+
+    /// 用当前密钥加密，返回写入卡片的数据
+    pub fn seal(&self, uid: &[u8], content_type: ContentType, data: &[u8]) -> Result<Vec<u8>>{
+        let key = derive_key(&self.keys[&self.current], KEY_LABEL, uid);
+        let header = [self.cipher.to_u8(), self.current, content_type.to_u8()];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut aad = header.to_vec();
+        aad.extend_from_slice(uid);
+        let sealed = self.cipher.seal(&key, &nonce, Payload{ msg: data, aad: &aad })?;
+        let mut bytes = Vec::with_capacity(HEADER_LEN + NONCE_LEN + sealed.len());
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&sealed);
+        Ok(bytes)
+    }
+
+    /// 解密，数据被修改、UID不符或密钥错误时返回失败
+    pub fn open(&self, uid: &[u8], bytes: &[u8]) -> Result<Opened>{
+        if bytes.len() < HEADER_LEN + NONCE_LEN + TAG_LEN{
+            return Err(anyhow!("数据已损坏 加密数据过短"));
+        }
+        let cipher = Cipher::from_u8(bytes[0]).ok_or_else(|| anyhow!("未知的加密算法:{}", bytes[0]))?;
+        let key_id = bytes[1];
+        let content_type = ContentType::from_u8(bytes[2]).ok_or_else(|| anyhow!("数据已损坏 未知的内容类型:{}", bytes[2]))?;
+        let master = self.keys.get(&key_id).ok_or_else(|| anyhow!("没有编号为{}的密钥", key_id))?;
+        let key = derive_key(master, KEY_LABEL, uid);
+        let mut aad = bytes[..HEADER_LEN].to_vec();
+        aad.extend_from_slice(uid);
+        let nonce = &bytes[HEADER_LEN..HEADER_LEN + NONCE_LEN];
+        let data = cipher.open(&key, nonce, Payload{ msg: &bytes[HEADER_LEN + NONCE_LEN..], aad: &aad })?;
+        Ok(Opened{ frame: Frame{ content_type, data, signed: false }, key_id: Some(key_id) })
+    }
+}
+
+/// 解密读取到的带长度头的数据，没有加密时原样返回
+pub fn open_frame(card: &mut Card, keys: Option<&PayloadKeys>, frame: Frame) -> Result<Opened>{
+    if frame.content_type != ContentType::Encrypted{
+        return Ok(Opened{ frame, key_id: None });
+    }
+    let keys = keys.ok_or_else(|| anyhow!("数据已加密，服务器没有配置密钥"))?;
+    let uid = card_uid(card)?;
+    keys.open(&uid, &frame.data)
+}
+
+/// 用当前卡片的密钥加密，返回写入卡片的带长度头的数据
+pub fn seal_frame(card: &mut Card, keys: &PayloadKeys, content_type: ContentType, data: &[u8]) -> Result<Vec<u8>>{
+    let uid = card_uid(card)?;
+    encode_frame(ContentType::Encrypted, &keys.seal(&uid, content_type, data)?)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const UID: [u8; 7] = [0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+
+    fn keys(cipher: Cipher, current: u8) -> PayloadKeys{
+        let keys = [(1, [0x11; 32]), (2, [0x22; 32])].into_iter().collect();
+        PayloadKeys::new(cipher, current, keys).unwrap()
+    }
+
+    #[test]
+    fn seal_open_round_trip(){
+        for cipher in [Cipher::AesGcm, Cipher::ChaCha20Poly1305]{
+            let keys = keys(cipher, 2);
+            let sealed = keys.seal(&UID, ContentType::Json, br#"{"a":1}"#).unwrap();
+            assert_eq!(sealed.len(), HEADER_LEN + NONCE_LEN + 7 + TAG_LEN);
+            assert_eq!(sealed[..HEADER_LEN], [cipher.to_u8(), 2, ContentType::Json.to_u8()]);
+            let opened = keys.open(&UID, &sealed).unwrap();
+            assert_eq!(opened.key_id, Some(2));
+            assert_eq!(opened.frame.content_type, ContentType::Json);
+            assert_eq!(opened.frame.data, br#"{"a":1}"#);
+        }
+    }
+
+    #[test]
+    fn nonce_is_random(){
+        let keys = keys(Cipher::AesGcm, 1);
+        assert_ne!(keys.seal(&UID, ContentType::Binary, b"x").unwrap(), keys.seal(&UID, ContentType::Binary, b"x").unwrap());
+    }
+
+    #[test]
+    fn tampered_data_fails(){
+        let keys = keys(Cipher::AesGcm, 1);
+        let sealed = keys.seal(&UID, ContentType::Text, b"hello").unwrap();
+        for i in 0..sealed.len(){
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 0x01;
+            assert!(keys.open(&UID, &tampered).is_err(), "{}", i);
+        }
+        assert!(keys.open(&UID, &sealed[..HEADER_LEN + NONCE_LEN + TAG_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn copied_to_other_card_fails(){
+        let keys = keys(Cipher::ChaCha20Poly1305, 1);
+        let sealed = keys.seal(&UID, ContentType::Binary, b"data").unwrap();
+        let mut other = UID;
+        other[6] ^= 1;
+        assert!(keys.open(&other, &sealed).is_err());
+    }
+
+    #[test]
+    fn old_key_still_opens(){
+        let sealed = keys(Cipher::AesGcm, 1).seal(&UID, ContentType::Binary, b"old").unwrap();
+        let rotated = keys(Cipher::AesGcm, 2);
+        assert_eq!(rotated.open(&UID, &sealed).unwrap().key_id, Some(1));
+        let removed = PayloadKeys::new(Cipher::AesGcm, 2, [(2, [0x22; 32])].into_iter().collect()).unwrap();
+        assert!(removed.open(&UID, &sealed).is_err());
+        //用其他主密钥冒充编号1
+        let wrong = PayloadKeys::new(Cipher::AesGcm, 1, [(1, [0x33; 32])].into_iter().collect()).unwrap();
+        assert!(wrong.open(&UID, &sealed).is_err());
+    }
+
+    #[test]
+    fn keys_file_is_checked(){
+        let key = "00".repeat(32);
+        let keys: PayloadKeys = serde_json::from_str(&format!(r#"{{"cipher":"chacha20-poly1305","current":3,"keys":{{"3":"{}"}}}}"#, key)).unwrap();
+        assert_eq!((keys.cipher, keys.current()), (Cipher::ChaCha20Poly1305, 3));
+        assert!(serde_json::from_str::<PayloadKeys>(&format!(r#"{{"current":1,"keys":{{"3":"{}"}}}}"#, key)).is_err());
+        assert!(serde_json::from_str::<PayloadKeys>(r#"{"current":1,"keys":{"1":"0011"}}"#).is_err());
+        assert!(!format!("{:?}", keys).contains(&key));
+    }
+}
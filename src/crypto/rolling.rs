@@ -0,0 +1,157 @@
+//! 防克隆滚动令牌
+//!
+//! 每次刷卡在保留页写入新的令牌 HMAC-SHA256(卡片密钥, 计数器 u32 LE) 的前4字节，卡片密钥由主密钥和UID派生，
+//! 服务器按UID记录计数器。复制出的卡片和原卡片只有一张能匹配服务器记录的令牌，
+//! 任意一张刷过之后，另一张再刷时令牌不一致，报告疑似克隆。
+//!
+//! 写入新令牌前先记录"写入中"，写入中途卡片离开时，下次刷卡新旧令牌都接受。
+
+use anyhow::{anyhow, Context, Result};
+use hmac::Mac;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use crate::ntag::{now_ms, Card, PAGE_SIZE};
+use super::{card_uid, derive_key, HmacSha256, PayloadKeys};
+
+/// 派生令牌密钥时使用的标签
+const KEY_LABEL: &[u8] = b"xelc-rolling";
+
+/// 一张卡片的令牌记录
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RollingEntry{
+    /// 卡片上当前令牌的计数器
+    pub counter: u32,
+    /// 当前令牌使用的密钥编号
+    pub key_id: u8,
+    /// 正在写入计数器+1的令牌时为写入使用的密钥编号
+    pub pending: Option<u8>,
+    /// 检测到令牌不一致的次数
+    pub clones: u32,
+    /// 最近一次刷卡时间(UNIX毫秒)
+    pub updated_at: u64,
+}
+
+/// 一次刷卡的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum RollingOutcome{
+    /// 第一次刷卡，已写入第一个令牌
+    Enrolled,
+    /// 令牌正确，已写入计数器为counter的新令牌
+    Rolled{ counter: u32 },
+    /// 令牌不一致，卡片可能被复制，卡片没有修改
+    CloneDetected{ expected: u32 },
+}
+
+/// 服务器记录的令牌，UID(hex) -> 记录，修改后立即保存到文件
+#[derive(Debug, Default)]
+pub struct RollingLedger{
+    path: Option<PathBuf>,
+    entries: BTreeMap<String, RollingEntry>,
+}
+
+impl RollingLedger{
+    /// 从JSON文件加载，文件不存在时为空，之后的修改保存到这个文件
+    pub fn load(path: impl AsRef<Path>) -> Result<RollingLedger>{
+        let path = path.as_ref();
+        let entries = match std::fs::read_to_string(path){
+            Ok(text) => serde_json::from_str(&text).with_context(|| format!("令牌记录格式错误 {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(anyhow!("读取令牌记录失败 {} {:?}", path.display(), err)),
+        };
+        Ok(RollingLedger{ path: Some(path.to_path_buf()), entries })
+    }
+
+    fn save(&self) -> Result<()>{
+        if let Some(path) = &self.path{
+            let text = serde_json::to_string_pretty(&self.entries)?;
+            std::fs::write(path, text).with_context(|| format!("保存令牌记录失败 {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// 所有记录
+    pub fn entries(&self) -> &BTreeMap<String, RollingEntry>{
+        &self.entries
+    }
+
+    /// 删除一张卡片的记录，下次刷卡时重新登记，记录不存在时返回false
+    pub fn reset(&mut self, uid: &[u8]) -> Result<bool>{
+        let removed = self.entries.remove(&hex::encode(uid)).is_some();
+        if removed{
+            self.save()?;
+        }
+        Ok(removed)
+    }
+}
+
+fn token(keys: &PayloadKeys, key_id: u8, uid: &[u8], counter: u32) -> Result<[u8; PAGE_SIZE]>{
+    let master = keys.master(key_id).ok_or_else(|| anyhow!("没有编号为{}的密钥", key_id))?;
+    let key = derive_key(master, KEY_LABEL, uid);
+    let mut mac = HmacSha256::new_from_slice(&key).expect("HMAC接受任意长度的密钥");
+    mac.update(&counter.to_le_bytes());
+    let tag = mac.finalize().into_bytes();
+    Ok([tag[0], tag[1], tag[2], tag[3]])
+}
+
+fn lock(ledger: &Mutex<RollingLedger>) -> Result<MutexGuard<'_, RollingLedger>>{
+    ledger.lock().map_err(|err| anyhow!("令牌记录锁定失败:{:?}", err))
+}
+
+/// 保存一张卡片的记录
+fn update(ledger: &Mutex<RollingLedger>, name: &str, entry: RollingEntry) -> Result<()>{
+    let mut ledger = lock(ledger)?;
+    ledger.entries.insert(name.to_string(), entry);
+    ledger.save()
+}
+
+/// 检查保留页上的令牌并写入下一个令牌。
+/// 只在查找和保存记录时锁定ledger，和卡片通信期间 /rolling 等请求不会被阻塞
+pub fn roll_token(card: &mut Card, keys: &PayloadKeys, ledger: &Mutex<RollingLedger>, page: u8) -> Result<RollingOutcome>{
+    card.bound(None, |card| {
+        let uid = card_uid(card)?;
+        let name = hex::encode(&uid);
+        let actual = card.read_pages(page, 1)?;
+        let actual = &actual[..PAGE_SIZE];
+
+        let entry = lock(ledger)?.entries.get(&name).cloned();
+        let (counter, key_id) = match &entry{
+            None => (0, keys.current()),
+            Some(entry) => {
+                let pending = match entry.pending{
+                    Some(pending) => token(keys, pending, &uid, entry.counter + 1)? == actual,
+                    None => false,
+                };
+                if token(keys, entry.key_id, &uid, entry.counter)? == actual{
+                    (entry.counter, entry.key_id)
+                }else if pending{
+                    (entry.counter + 1, entry.pending.expect("pending已检查"))
+                }else{
+                    let mut ledger = lock(ledger)?;
+                    //锁定期间记录可能已被删除
+                    if let Some(entry) = ledger.entries.get_mut(&name){
+                        entry.clones += 1;
+                        entry.updated_at = now_ms();
+                        ledger.save()?;
+                    }
+                    return Ok(RollingOutcome::CloneDetected{ expected: entry.counter });
+                }
+            }
+        };
+        let enrolled = entry.is_none();
+
+        //先记录写入中，卡片离开后下次刷卡仍然能识别新令牌
+        let next = counter.checked_add(1).ok_or_else(|| anyhow!("令牌计数器已用完"))?;
+        let next_key = keys.current();
+        let clones = entry.map(|entry| entry.clones).unwrap_or(0);
+        update(ledger, &name, RollingEntry{ counter, key_id, pending: Some(next_key), clones, updated_at: now_ms() })?;
+
+        let next_token = token(keys, next_key, &uid, next)?;
+        card.write_page(page, &next_token)?;
+        card.verify_bytes(page as usize * PAGE_SIZE, &next_token)?;
+
+        update(ledger, &name, RollingEntry{ counter: next, key_id: next_key, pending: None, clones, updated_at: now_ms() })?;
+        Ok(if enrolled{ RollingOutcome::Enrolled }else{ RollingOutcome::Rolled{ counter: next } })
+    })
+}
@@ -0,0 +1,97 @@
+//! 数据签名
+//!
+//! 数据保持明文，其他设备仍然可以读取。签名紧跟在带长度头的数据之后:
+//! 魔数"XS"(2) | 密钥编号(1) | HMAC-SHA256前16字节，HMAC的密钥由主密钥和UID派生，
+//! 覆盖数据头和数据，数据被修改或复制到其他卡片时签名无效。
+//! 数据头中标记了是否有签名，不签名的写入会清除标记，不会把卡片上残留的旧签名当作签名。
+
+use anyhow::Result;
+use hmac::Mac;
+use serde::{Deserialize, Serialize};
+use crate::ntag::{Card, CardType, PAGE_SIZE, USER_START_PAGE};
+use crate::storage::{mark_signed, Frame};
+use super::{card_uid, derive_key, HmacSha256, PayloadKeys};
+
+const SIGNATURE_MAGIC: [u8; 2] = *b"XS";
+const MAC_LEN: usize = 16;
+/// 签名长度
+pub const SIGNATURE_LEN: usize = 3 + MAC_LEN;
+/// 派生签名密钥时使用的标签
+const KEY_LABEL: &[u8] = b"xelc-sign";
+/// 数据在卡片上的字节地址
+const FRAME_OFFSET: usize = USER_START_PAGE as usize * PAGE_SIZE;
+
+/// 签名检查结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Signature{
+    /// 签名正确
+    Valid,
+    /// 签名错误，数据被修改、复制自其他卡片或密钥不存在
+    Invalid,
+    /// 没有签名
+    Unsigned,
+    /// 有签名，服务器没有配置密钥，没有检查
+    Unverified,
+}
+
+fn mac(master: &[u8], uid: &[u8], frame: &[u8]) -> HmacSha256{
+    let key = derive_key(master, KEY_LABEL, uid);
+    let mut mac = HmacSha256::new_from_slice(&key).expect("HMAC接受任意长度的密钥");
+    mac.update(frame);
+    mac
+}
+
+/// 在带长度头的数据(frame为encode_frame的结果)的头部标记签名，并在后面加上当前卡片的签名
+pub fn sign_frame(card: &mut Card, keys: &PayloadKeys, frame: &[u8]) -> Result<Vec<u8>>{
+    let uid = card_uid(card)?;
+    let mut bytes = frame.to_vec();
+    mark_signed(&mut bytes)?;
+    let tag = mac(keys.master(keys.current()).expect("当前密钥总是存在"), &uid, &bytes).finalize().into_bytes();
+    bytes.extend_from_slice(&SIGNATURE_MAGIC);
+    bytes.push(keys.current());
+    bytes.extend_from_slice(&tag[..MAC_LEN]);
+    Ok(bytes)
+}
+
+/// 检查紧跟在数据后面的签名，frame为read_frame读取到的数据。
+/// 数据头没有签名标记或签名无法读取时为Unsigned，服务器没有配置密钥时为Unverified
+pub fn check_signature(card: &mut Card, keys: Option<&PayloadKeys>, frame: &Frame) -> Result<Signature>{
+    if !frame.signed{
+        return Ok(Signature::Unsigned);
+    }
+    let keys = match keys{
+        Some(keys) => keys,
+        None => return Ok(Signature::Unverified),
+    };
+    let bytes = frame.to_bytes()?;
+    let offset = FRAME_OFFSET + bytes.len();
+    //签名超出NTAG用户数据区时不可能有签名
+    if card.card_type() == CardType::UltraLight{
+        if let Some((_, end)) = card.cached_model().ok().and_then(|model| model.user_pages()){
+            if offset + SIGNATURE_LEN > end as usize * PAGE_SIZE{
+                return Ok(Signature::Unsigned);
+            }
+        }
+    }
+    let signature = match card.read_bytes(offset, SIGNATURE_LEN){
+        Ok(signature) => signature,
+        Err(_) => {
+            card.check_deadline()?;
+            return Ok(Signature::Unsigned);
+        }
+    };
+    //数据头标记了签名，签名却不完整
+    if signature[..2] != SIGNATURE_MAGIC{
+        return Ok(Signature::Invalid);
+    }
+    let master = match keys.master(signature[2]){
+        Some(master) => master,
+        None => return Ok(Signature::Invalid),
+    };
+    let uid = card_uid(card)?;
+    Ok(match mac(master, &uid, &bytes).verify_truncated_left(&signature[3..]){
+        Ok(()) => Signature::Valid,
+        Err(_) => Signature::Invalid,
+    })
+}
@@ -0,0 +1,323 @@
+//! 卡片完整内容的导出与恢复
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use crate::ntag::{Card, CardType, PageKind, TagModel, PAGE_SIZE};
+
+/// JSON导出格式标识
+pub const DUMP_FORMAT: &str = "xelc-dump";
+/// JSON导出格式版本
+pub const DUMP_VERSION: u32 = 1;
+/// 无法识别型号时最多读取的页数
+const MAX_PAGES: u16 = 256;
+/// 型号已知时每次读取的页数
+const BLOCK_PAGES: u16 = 16;
+
+/// 一页的导出数据
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PageDump{
+    pub page: u16,
+    #[serde(with = "crate::hex_serde")]
+    pub data: Vec<u8>,
+    pub kind: PageKind,
+    /// 是否读取成功，密码页和读保护页为false
+    pub readable: bool,
+    /// 是否已被锁定为只读
+    pub locked: bool,
+}
+
+/// 卡片完整内容
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Dump{
+    /// 固定为 xelc-dump
+    pub format: String,
+    pub version: u32,
+    pub card_type: CardType,
+    pub model: TagModel,
+    #[serde(with = "crate::hex_serde")]
+    pub uid: Vec<u8>,
+    pub page_size: usize,
+    pub pages: Vec<PageDump>,
+}
+
+impl Dump{
+    /// 读取卡片的所有页，读取失败的页标记为不可读，读取过程中更换卡片时返回错误。
+    /// 只支持NTAG/Ultralight: Mifare Classic的16字节块和扇区尾块不能按4字节的页读取
+    pub fn read(card: &mut Card) -> Result<Dump>{
+        if card.card_type() != CardType::UltraLight{
+            return Err(anyhow!("只支持导出NTAG/Ultralight卡片 当前卡片:{:?}", card.card_type()));
+        }
+        card.bound(None, |card| {
+            let uid = card.bound_uid().unwrap_or_default().to_vec();
+            let model = match card.detect_model(){
+                Ok(model) => model,
+                Err(err) => {
+                    card.check_deadline()?;
+                    warn!("标签型号识别失败 {:?}", err);
+                    TagModel::Unknown
+                }
+            };
+            let total = model.total_pages().unwrap_or(MAX_PAGES);
+            let mut raw: Vec<[u8; PAGE_SIZE]> = vec![];
+            let mut readable = vec![];
+            //型号已知时按块读取，整块读取失败再逐页读取，找出无法读取的页
+            let mut block = vec![];
+            //整块读取失败的范围，在此之前逐页读取
+            let mut single_until = 0;
+            for page in 0..total{
+                if block.is_empty() && model != TagModel::Unknown && page >= single_until{
+                    let count = (total - page).min(BLOCK_PAGES);
+                    match card.read_pages(page as u8, count as u8){
+                        Ok(data) => block = data.chunks(PAGE_SIZE).rev().map(|chunk| {
+                            let mut buf = [0u8; PAGE_SIZE];
+                            buf.copy_from_slice(chunk);
+                            buf
+                        }).collect(),
+                        Err(_) => {
+                            card.check_deadline()?;
+                            single_until = page + count;
+                        }
+                    }
+                }
+                let result = match block.pop(){
+                    Some(data) => Ok(data),
+                    None => card.read_page(page as u8),
+                };
+                match result{
+                    Ok(data) => {
+                        raw.push(data);
+                        readable.push(!matches!(model.page_kind(page), PageKind::Password | PageKind::Pack));
+                    }
+                    Err(err) => {
+                        card.check_deadline()?;
+                        //无法识别型号时，读取失败说明已经到了最后一页
+                        if model == TagModel::Unknown{
+                            break;
+                        }
+                        warn!("第{}页读取失败 {:?}", page, err);
+                        raw.push([0u8; PAGE_SIZE]);
+                        readable.push(false);
+                    }
+                }
+            }
+            Ok(Dump::from_pages(card.card_type(), model, uid, &raw, Some(readable)))
+        })
+    }
+
+    fn from_pages(card_type: CardType, model: TagModel, uid: Vec<u8>, raw: &[[u8; PAGE_SIZE]], readable: Option<Vec<bool>>) -> Dump{
+        let mut dump = Dump::from_blocks(card_type, model, uid, PAGE_SIZE, raw.iter().map(|page| page.to_vec()).collect());
+        if let Some(readable) = readable{
+            for (page, readable) in dump.pages.iter_mut().zip(readable){
+                page.readable = readable;
+            }
+        }
+        dump
+    }
+
+    /// 由从第0页开始的连续数据生成，除密码页外都视为可读，NTAG根据锁定字节计算锁定状态，Mifare Classic只有第0块锁定
+    pub fn from_blocks(card_type: CardType, model: TagModel, uid: Vec<u8>, page_size: usize, blocks: Vec<Vec<u8>>) -> Dump{
+        let locked = if model.is_classic(){
+            (0..blocks.len()).map(|block| block == 0).collect()
+        }else if page_size == PAGE_SIZE && model != TagModel::Iso15693{
+            let raw: Vec<[u8; PAGE_SIZE]> = blocks.iter().map(|data| {
+                let mut page = [0u8; PAGE_SIZE];
+                page.copy_from_slice(data);
+                page
+            }).collect();
+            model.locked_pages(&raw)
+        }else{
+            vec![false; blocks.len()]
+        };
+        let pages = blocks.into_iter().enumerate().map(|(page, data)| {
+            let kind = model.page_kind(page as u16);
+            PageDump{
+                page: page as u16,
+                data,
+                kind,
+                readable: !matches!(kind, PageKind::Password | PageKind::Pack),
+                locked: locked[page],
+            }
+        }).collect();
+        Dump{
+            format: DUMP_FORMAT.to_string(),
+            version: DUMP_VERSION,
+            card_type,
+            model,
+            uid,
+            page_size,
+            pages,
+        }
+    }
+
+    /// 原始二进制格式: 从第0页开始的所有页数据
+    pub fn to_bytes(&self) -> Vec<u8>{
+        self.pages.iter().flat_map(|page| page.data.iter().copied()).collect()
+    }
+
+    /// 从原始二进制格式读取，型号和UID从数据本身识别
+    pub fn from_bytes(card_type: CardType, data: &[u8]) -> Result<Dump>{
+        if data.is_empty() || !data.len().is_multiple_of(PAGE_SIZE){
+            return Err(anyhow!("数据长度必须是{}的整数倍", PAGE_SIZE));
+        }
+        let raw: Vec<[u8; PAGE_SIZE]> = data.chunks(PAGE_SIZE).map(|chunk| {
+            let mut page = [0u8; PAGE_SIZE];
+            page.copy_from_slice(chunk);
+            page
+        }).collect();
+        let model = raw.get(3).map(TagModel::from_cc).unwrap_or(TagModel::Unknown);
+        //7字节UID: 第0页前3字节 + 第1页4字节(第0页第4字节为校验字节)
+        let uid = if raw.len() >= 2{
+            let mut uid = raw[0][..3].to_vec();
+            uid.extend_from_slice(&raw[1]);
+            uid
+        }else{
+            vec![]
+        };
+        Ok(Dump::from_pages(card_type, model, uid, &raw, None))
+    }
+
+    /// 检查格式标识、版本和页编号，页编号必须从0开始连续且不超出型号的页数
+    pub fn check_format(&self) -> Result<()>{
+        if self.format != DUMP_FORMAT{
+            return Err(anyhow!("未知的导出格式:{}", self.format));
+        }
+        if self.version > DUMP_VERSION{
+            return Err(anyhow!("不支持的导出格式版本:{}", self.version));
+        }
+        if self.page_size == 0{
+            return Err(anyhow!("页大小错误:{}", self.page_size));
+        }
+        let max = self.model.total_pages().unwrap_or(MAX_PAGES) as usize;
+        if self.pages.len() > max{
+            return Err(anyhow!("页数超出范围:{} {:?}最多{}页", self.pages.len(), self.model, max));
+        }
+        for (index, page) in self.pages.iter().enumerate(){
+            if page.page as usize != index{
+                return Err(anyhow!("页编号错误:{} 应为{}，页编号必须从0开始连续且不重复", page.page, index));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 恢复参数
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RestoreOptions{
+    /// 是否恢复配置页CFG0、CFG1
+    pub config: bool,
+}
+
+/// 跳过的页
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedPage{
+    pub page: u16,
+    pub reason: String,
+}
+
+/// 恢复结果
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RestoreReport{
+    /// 已写入的页
+    pub written: Vec<u16>,
+    /// 跳过的页
+    pub skipped: Vec<SkippedPage>,
+}
+
+/// 读取目标卡片的锁定状态
+fn read_locks(card: &mut Card, model: TagModel, total: u16) -> Result<Vec<bool>>{
+    let mut raw = vec![[0u8; PAGE_SIZE]; total as usize];
+    let mut lock_pages = vec![2];
+    lock_pages.extend(model.dynamic_lock_page());
+    lock_pages.extend(model.config_page().map(|page| page + 1));
+    for page in lock_pages{
+        if page < total{
+            raw[page as usize] = card.read_page(page as u8)?;
+        }
+    }
+    Ok(model.locked_pages(&raw))
+}
+
+/// 把导出数据写回卡片，跳过只读、一次性写入、已锁定和导出时无法读取的页
+pub fn restore(card: &mut Card, dump: &Dump, options: RestoreOptions) -> Result<RestoreReport>{
+    dump.check_format()?;
+    if dump.page_size != PAGE_SIZE || !matches!(dump.model, TagModel::Unknown) && !dump.model.is_ntag(){
+        return Err(anyhow!("只支持恢复NTAG/Ultralight卡片 导出型号:{:?}", dump.model));
+    }
+    card.bound(None, |card| {
+        let model = card.detect_model()?;
+        if dump.model != TagModel::Unknown && model != TagModel::Unknown && dump.model != model{
+            return Err(anyhow!("卡片型号不兼容 导出:{:?} 目标:{:?}", dump.model, model));
+        }
+        let total = model.total_pages().unwrap_or((dump.pages.len() as u16).min(MAX_PAGES));
+        let locked = read_locks(card, model, total)?;
+
+        let mut report = RestoreReport::default();
+        for page in &dump.pages{
+            //页编号超过255时不能转换为u8，否则会回绕写入前面的页
+            let number = match u8::try_from(page.page){
+                Ok(number) => number,
+                Err(_) => {
+                    report.skipped.push(SkippedPage{ page: page.page, reason: "超出目标卡片".to_string() });
+                    continue;
+                }
+            };
+            let reason = if page.page >= total{
+                Some("超出目标卡片")
+            }else if !model.page_kind(page.page).restorable(){
+                Some("只读或一次性写入")
+            }else if model.page_kind(page.page) == PageKind::Config && !options.config{
+                Some("配置页，需要config=true")
+            }else if !page.readable{
+                Some("导出时无法读取")
+            }else if locked[page.page as usize]{
+                Some("目标卡片已锁定")
+            }else if page.data.len() != PAGE_SIZE{
+                Some("数据长度错误")
+            }else{
+                None
+            };
+            if let Some(reason) = reason{
+                report.skipped.push(SkippedPage{ page: page.page, reason: reason.to_string() });
+                continue;
+            }
+            if let Err(err) = card.check_writable(number){
+                report.skipped.push(SkippedPage{ page: page.page, reason: err.to_string() });
+                continue;
+            }
+            let mut data = [0u8; PAGE_SIZE];
+            data.copy_from_slice(&page.data);
+            card.write_page(number, &data).map_err(|err| anyhow!("{:?} 已写入{}页", err, report.written.len()))?;
+            report.written.push(page.page);
+        }
+        Ok(report)
+    })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn dump(model: TagModel, pages: usize) -> Dump{
+        Dump::from_blocks(CardType::UltraLight, model, vec![], PAGE_SIZE, vec![vec![0u8; PAGE_SIZE]; pages])
+    }
+
+    #[test]
+    fn page_numbers_are_checked(){
+        assert!(dump(TagModel::Ntag213, 45).check_format().is_ok());
+        assert!(dump(TagModel::Ntag213, 46).check_format().is_err());
+        assert!(dump(TagModel::Unknown, 256).check_format().is_ok());
+        assert!(dump(TagModel::Unknown, 257).check_format().is_err());
+
+        //超过255的页编号会在写入时回绕到前面的页
+        let mut wrapped = dump(TagModel::Unknown, 8);
+        wrapped.pages[7].page = 260;
+        assert!(wrapped.check_format().is_err());
+        let mut duplicated = dump(TagModel::Unknown, 8);
+        duplicated.pages[5].page = 4;
+        assert!(duplicated.check_format().is_err());
+        let mut reordered = dump(TagModel::Unknown, 8);
+        reordered.pages.swap(2, 3);
+        assert!(reordered.check_format().is_err());
+    }
+}
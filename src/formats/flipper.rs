@@ -0,0 +1,324 @@
+//! Flipper Zero .nfc 文本格式(Version 4)，兼容导入旧版本的NTAG文件
+
+use anyhow::{anyhow, Result};
+use std::fmt::Write;
+use crate::dump::Dump;
+use crate::ntag::{CardType, TagModel};
+use super::{build_dump, check_blocks, classic_model, classic_uid, ntag_model, Family, Notes};
+
+const FILETYPE: &str = "Flipper NFC device";
+const VERSION: u32 = 4;
+
+/// 字节数组按 "04 A1 B2" 的形式输出，不可读的字节输出 "??"
+fn hex_line(data: &[u8], readable: bool) -> String{
+    data.iter()
+        .map(|b| if readable{ format!("{:02X}", b) }else{ String::from("??") })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// 解析 "04 A1 ??" 形式的字节数组，"??" 为空
+fn parse_hex(value: &str) -> Result<Vec<Option<u8>>>{
+    value.split_whitespace().map(|byte| {
+        if byte == "??"{
+            Ok(None)
+        }else{
+            u8::from_str_radix(byte, 16).map(Some).map_err(|err| anyhow!("字节格式错误:{} {:?}", byte, err))
+        }
+    }).collect()
+}
+
+/// 解析字节数组，不允许 "??"
+fn parse_bytes(field: &str, value: &str) -> Result<Vec<u8>>{
+    parse_hex(value)?.into_iter().collect::<Option<Vec<u8>>>().ok_or_else(|| anyhow!("{} 包含未知字节", field))
+}
+
+/// NTAG的GET_VERSION应答
+fn ntag_version(model: TagModel) -> [u8; 8]{
+    match model{
+        TagModel::Ntag213 => [0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x0F, 0x03],
+        TagModel::Ntag215 => [0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x11, 0x03],
+        TagModel::Ntag216 => [0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x13, 0x03],
+        _ => [0u8; 8],
+    }
+}
+
+fn ntag_type_name(model: TagModel) -> &'static str{
+    match model{
+        TagModel::Ntag213 => "NTAG213",
+        TagModel::Ntag215 => "NTAG215",
+        TagModel::Ntag216 => "NTAG216",
+        _ => "Mifare Ultralight",
+    }
+}
+
+pub(super) fn export(dump: &Dump, notes: &mut Notes) -> Result<String>{
+    let family = Family::of(dump)?;
+    let mut out = String::new();
+    writeln!(out, "Filetype: {}", FILETYPE)?;
+    writeln!(out, "Version: {}", VERSION)?;
+    writeln!(out, "# Device type can be ISO14443-3A, ISO14443-3B, ISO14443-4A, ISO15693-3, NTAG/Ultralight, Mifare Classic, Mifare DESFire, SLIX")?;
+    let device_type = match family{
+        Family::Ntag => "NTAG/Ultralight",
+        Family::Classic => "Mifare Classic",
+        Family::Iso15693 => "ISO15693-3",
+    };
+    writeln!(out, "Device type: {}", device_type)?;
+    writeln!(out, "# UID is common for all formats")?;
+    writeln!(out, "UID: {}", hex_line(&dump.uid, true))?;
+    match family{
+        Family::Ntag => {
+            if !dump.model.is_ntag(){
+                notes.add("NTAG/Ultralight type", "型号无法识别，按Mifare Ultralight导出");
+            }
+            writeln!(out, "# ISO14443-3A specific data")?;
+            writeln!(out, "ATQA: 00 44")?;
+            writeln!(out, "SAK: 00")?;
+            writeln!(out, "# NTAG/Ultralight specific data")?;
+            writeln!(out, "Data format version: 2")?;
+            writeln!(out, "NTAG/Ultralight type: {}", ntag_type_name(dump.model))?;
+            writeln!(out, "Signature: {}", hex_line(&[0u8; 32], true))?;
+            notes.add("Signature", "镜像中没有原厂签名，以0填充");
+            writeln!(out, "Mifare version: {}", hex_line(&ntag_version(dump.model), true))?;
+            for counter in 0..3{
+                writeln!(out, "Counter {}: 0", counter)?;
+                writeln!(out, "Tearing {}: 00", counter)?;
+            }
+            notes.add("Counter", "镜像中没有计数器和防撕裂标志，以0填充");
+            let total = dump.model.total_pages().map(|total| total as usize).unwrap_or(dump.pages.len());
+            writeln!(out, "Pages total: {}", total)?;
+            writeln!(out, "Pages read: {}", dump.pages.len())?;
+            for page in &dump.pages{
+                if !page.readable{
+                    notes.add(format!("Page {}", page.page), "导出时无法读取，以0填充");
+                }
+                writeln!(out, "Page {}: {}", page.page, hex_line(&page.data, true))?;
+            }
+            writeln!(out, "Failed authentication attempts: 0")?;
+        }
+        Family::Classic => {
+            let four_k = dump.model == TagModel::MifareClassic4k || dump.pages.len() > 64;
+            writeln!(out, "# ISO14443-3A specific data")?;
+            writeln!(out, "ATQA: {}", if four_k{ "00 02" }else{ "00 04" })?;
+            writeln!(out, "SAK: {}", if four_k{ "18" }else{ "08" })?;
+            writeln!(out, "# Mifare Classic specific data")?;
+            writeln!(out, "Mifare Classic type: {}", if four_k{ "4K" }else{ "1K" })?;
+            writeln!(out, "Data format version: 2")?;
+            writeln!(out, "# Mifare Classic blocks, '??' means unknown data")?;
+            for block in &dump.pages{
+                writeln!(out, "Block {}: {}", block.page, hex_line(&block.data, block.readable))?;
+            }
+        }
+        Family::Iso15693 => {
+            writeln!(out, "# ISO15693-3 specific data")?;
+            writeln!(out, "DSFID: 00")?;
+            writeln!(out, "AFI: 00")?;
+            writeln!(out, "IC Reference: 00")?;
+            writeln!(out, "Lock DSFID: false")?;
+            writeln!(out, "Lock AFI: false")?;
+            notes.add("DSFID", "镜像中没有DSFID、AFI和IC Reference，以0填充");
+            writeln!(out, "Block Count: {}", dump.pages.len())?;
+            writeln!(out, "Block Size: {:02X}", dump.page_size)?;
+            let content: Vec<u8> = dump.to_bytes();
+            writeln!(out, "Data Content: {}", hex_line(&content, true))?;
+            let security: Vec<u8> = dump.pages.iter().map(|page| page.locked as u8).collect();
+            writeln!(out, "Security Status: {}", hex_line(&security, true))?;
+        }
+    }
+    Ok(out)
+}
+
+/// 文件中的字段，按出现顺序保存
+struct Fields(Vec<(String, String)>);
+
+impl Fields{
+    fn parse(text: &str) -> Result<Fields>{
+        let mut fields = vec![];
+        for line in text.lines(){
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#'){
+                continue;
+            }
+            let (key, value) = line.split_once(':').ok_or_else(|| anyhow!("无法解析的行:{}", line))?;
+            fields.push((key.trim().to_string(), value.trim().to_string()));
+        }
+        Ok(Fields(fields))
+    }
+
+    fn get(&self, key: &str) -> Option<&str>{
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    fn require(&self, key: &str) -> Result<&str>{
+        self.get(key).ok_or_else(|| anyhow!("缺少字段:{}", key))
+    }
+
+    /// 取出 "Page 4: ..." 这类带编号的字段
+    fn numbered(&self, prefix: &str) -> Result<Vec<(usize, &str)>>{
+        let mut list = vec![];
+        for (key, value) in &self.0{
+            if let Some(number) = key.strip_prefix(prefix){
+                let number = number.trim().parse::<usize>().map_err(|err| anyhow!("字段编号错误:{} {:?}", key, err))?;
+                list.push((number, value.as_str()));
+            }
+        }
+        Ok(list)
+    }
+
+    /// 记录未使用的字段
+    fn note_ignored(&self, used: &[&str], prefixes: &[&str], notes: &mut Notes){
+        for (key, _) in &self.0{
+            if used.contains(&key.as_str()) || prefixes.iter().any(|prefix| key.starts_with(prefix)){
+                continue;
+            }
+            notes.add(key.clone(), "页模型中无法表示，已忽略");
+        }
+    }
+}
+
+pub(super) fn import(text: &str, notes: &mut Notes) -> Result<Dump>{
+    let fields = Fields::parse(text)?;
+    let filetype = fields.require("Filetype")?;
+    if filetype != FILETYPE{
+        return Err(anyhow!("不是Flipper NFC文件:{}", filetype));
+    }
+    let device_type = fields.require("Device type")?;
+    let uid = parse_bytes("UID", fields.require("UID")?)?;
+    let common = ["Filetype", "Version", "Device type", "UID", "Data format version"];
+    match device_type{
+        "NTAG/Ultralight" | "Mifare Ultralight" | "NTAG203" | "NTAG213" | "NTAG215" | "NTAG216" => {
+            //旧版本文件直接在Device type中写型号
+            let type_name = fields.get("NTAG/Ultralight type").unwrap_or(device_type);
+            let declared = match type_name{
+                "NTAG213" => TagModel::Ntag213,
+                "NTAG215" => TagModel::Ntag215,
+                "NTAG216" => TagModel::Ntag216,
+                _ => TagModel::Unknown,
+            };
+            let mut pages: Vec<Option<Vec<u8>>> = vec![];
+            let total = match fields.get("Pages total"){
+                Some(total) => total.parse::<usize>()?,
+                None => 0,
+            };
+            check_blocks(declared, "Pages total", total)?;
+            pages.resize(total, None);
+            for (page, value) in fields.numbered("Page ")?{
+                check_blocks(declared, "Page", page.saturating_add(1))?;
+                if page >= pages.len(){
+                    pages.resize(page + 1, None);
+                }
+                pages[page] = Some(parse_bytes(&format!("Page {}", page), value)?);
+            }
+            let model = match declared{
+                TagModel::Unknown => ntag_model(&pages),
+                declared => declared,
+            };
+            let mut used = common.to_vec();
+            used.extend(["NTAG/Ultralight type", "Pages total", "Pages read"]);
+            fields.note_ignored(&used, &["Page "], notes);
+            build_dump(CardType::UltraLight, model, uid, 4, pages)
+        }
+        "Mifare Classic" => {
+            let declared = match fields.get("Mifare Classic type"){
+                Some("1K") => TagModel::MifareClassic1k,
+                _ => TagModel::MifareClassic4k,
+            };
+            let mut blocks: Vec<Option<Vec<u8>>> = vec![];
+            let mut partial = vec![];
+            for (block, value) in fields.numbered("Block ")?{
+                check_blocks(declared, "Block", block.saturating_add(1))?;
+                if block >= blocks.len(){
+                    blocks.resize(block + 1, None);
+                }
+                let bytes = parse_hex(value)?;
+                if bytes.iter().all(|b| b.is_none()){
+                    continue;
+                }
+                //部分字节未知(通常是未破解的密钥)时按0填充
+                if bytes.iter().any(|b| b.is_none()){
+                    partial.push(block);
+                }
+                blocks[block] = Some(bytes.into_iter().map(|b| b.unwrap_or(0)).collect());
+            }
+            let model = match fields.get("Mifare Classic type"){
+                Some("4K") => TagModel::MifareClassic4k,
+                Some("1K") => TagModel::MifareClassic1k,
+                Some(other) => {
+                    notes.add("Mifare Classic type", format!("不支持的型号{}，按块数识别", other));
+                    classic_model(blocks.len())
+                }
+                None => classic_model(blocks.len()),
+            };
+            if let Some(total) = model.total_pages(){
+                blocks.resize(blocks.len().max(total as usize), None);
+            }
+            for block in partial{
+                notes.add(format!("Block {}", block), "包含未知字节('??')，以0填充");
+            }
+            let uid = if uid.is_empty(){ classic_uid(&blocks) }else{ uid };
+            let mut used = common.to_vec();
+            used.push("Mifare Classic type");
+            fields.note_ignored(&used, &["Block "], notes);
+            build_dump(CardType::Mifare, model, uid, 16, blocks)
+        }
+        "ISO15693-3" | "SLIX" | "SLIX-S" | "SLIX-L" | "SLIX2" => {
+            let count = fields.require("Block Count")?.parse::<usize>()?;
+            let size = usize::from_str_radix(fields.require("Block Size")?, 16)?;
+            let content = parse_bytes("Data Content", fields.require("Data Content")?)?;
+            if size == 0 || count.checked_mul(size) != Some(content.len()){
+                return Err(anyhow!("Data Content长度{}与块数{}、块大小{}不符", content.len(), count, size));
+            }
+            let blocks = content.chunks(size).map(|chunk| Some(chunk.to_vec())).collect();
+            let mut dump = build_dump(CardType::ISO15693, TagModel::Iso15693, uid, size, blocks)?;
+            if let Some(security) = fields.get("Security Status"){
+                for (page, status) in dump.pages.iter_mut().zip(parse_bytes("Security Status", security)?){
+                    page.locked = status != 0;
+                }
+            }
+            let mut used = common.to_vec();
+            used.extend(["Block Count", "Block Size", "Data Content", "Security Status"]);
+            fields.note_ignored(&used, &[], notes);
+            Ok(dump)
+        }
+        _ => Err(anyhow!("不支持的Flipper设备类型:{}", device_type)),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn ntag_file(body: &str) -> String{
+        format!("Filetype: Flipper NFC device\nVersion: 4\nDevice type: NTAG/Ultralight\nUID: 04 A1 B2 C3 D4 E5 F6\n{}", body)
+    }
+
+    #[test]
+    fn rejects_oversized_pages_total(){
+        let text = ntag_file("Pages total: 18446744073709551615\n");
+        assert!(import(&text, &mut Notes::default()).is_err());
+        let text = ntag_file("NTAG/Ultralight type: NTAG213\nPages total: 4294967296\n");
+        assert!(import(&text, &mut Notes::default()).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_page_number(){
+        let text = ntag_file("Page 18446744073709551615: 00 00 00 00\n");
+        assert!(import(&text, &mut Notes::default()).is_err());
+        let text = ntag_file("NTAG/Ultralight type: NTAG213\nPage 45: 00 00 00 00\n");
+        assert!(import(&text, &mut Notes::default()).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_block_number(){
+        let text = "Filetype: Flipper NFC device\nVersion: 4\nDevice type: Mifare Classic\nUID: 01 02 03 04\n\
+            Block 4294967296: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n";
+        assert!(import(text, &mut Notes::default()).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_iso15693_block_count(){
+        let text = "Filetype: Flipper NFC device\nVersion: 4\nDevice type: ISO15693-3\nUID: E0 04 01 02 03 04 05 06\n\
+            Block Count: 18446744073709551615\nBlock Size: 04\nData Content: 00 00 00 00\n";
+        assert!(import(text, &mut Notes::default()).is_err());
+    }
+}
@@ -0,0 +1,396 @@
+//! 常见NFC导出格式的导入导出
+//!
+//! 所有格式都先转换成 [`Dump`] 的页(块)模型，格式中无法表示的字段不会静默丢弃，
+//! 而是在 [`Converted::unrepresentable`] 中逐项列出。
+
+mod flipper;
+mod ndef;
+mod proxmark;
+mod raw;
+
+pub(crate) use ndef::ndef_tlv;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use crate::dump::Dump;
+use crate::ntag::{CardType, TagModel};
+
+/// 卡片镜像格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat{
+    /// 本服务的JSON导出格式
+    Json,
+    /// 从第0页(块)开始的原始二进制 .bin
+    Bin,
+    /// Flipper Zero .nfc 文本
+    Nfc,
+    /// Proxmark3 JSON
+    Pm3,
+    /// NDEF消息 .ndef，只包含用户数据区中的NDEF消息
+    Ndef,
+}
+
+impl FromStr for ImageFormat{
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self>{
+        match s.to_ascii_lowercase().as_str(){
+            "json" => Ok(ImageFormat::Json),
+            "bin" => Ok(ImageFormat::Bin),
+            "nfc" => Ok(ImageFormat::Nfc),
+            "pm3" => Ok(ImageFormat::Pm3),
+            "ndef" => Ok(ImageFormat::Ndef),
+            _ => Err(anyhow!("未知的镜像格式:{} 可选 json, bin, nfc, pm3, ndef", s))
+        }
+    }
+}
+
+impl ImageFormat{
+    /// HTTP Content-Type
+    pub fn content_type(&self) -> &'static str{
+        match self{
+            ImageFormat::Json | ImageFormat::Pm3 => "application/json",
+            ImageFormat::Nfc => "text/plain; charset=utf-8",
+            ImageFormat::Bin | ImageFormat::Ndef => "application/octet-stream",
+        }
+    }
+
+    /// 文件扩展名
+    pub fn extension(&self) -> &'static str{
+        match self{
+            ImageFormat::Json => "json",
+            ImageFormat::Bin => "bin",
+            ImageFormat::Nfc => "nfc",
+            ImageFormat::Pm3 => "json",
+            ImageFormat::Ndef => "ndef",
+        }
+    }
+}
+
+/// 无法在目标格式中表示的字段
+#[derive(Debug, Clone, Serialize)]
+pub struct Unrepresentable{
+    /// 字段名，使用源格式中的名称
+    pub field: String,
+    pub reason: String,
+}
+
+/// 转换结果
+#[derive(Debug, Clone, Serialize)]
+pub struct Converted<T>{
+    pub value: T,
+    /// 转换时丢弃或填充了默认值的字段
+    pub unrepresentable: Vec<Unrepresentable>,
+}
+
+/// 导入参数，用于格式本身不包含卡片类型的 .bin 和 .ndef
+#[derive(Debug, Clone, Copy)]
+pub struct ImportOptions{
+    pub card_type: CardType,
+    /// 每块字节数，默认Mifare为16，其他为4
+    pub block_size: Option<usize>,
+}
+
+impl Default for ImportOptions{
+    fn default() -> Self{
+        ImportOptions{ card_type: CardType::UltraLight, block_size: None }
+    }
+}
+
+/// 转换过程中记录无法表示的字段
+#[derive(Debug, Default)]
+struct Notes(Vec<Unrepresentable>);
+
+impl Notes{
+    fn add(&mut self, field: impl Into<String>, reason: impl Into<String>){
+        self.0.push(Unrepresentable{ field: field.into(), reason: reason.into() });
+    }
+
+    fn finish<T>(self, value: T) -> Converted<T>{
+        Converted{ value, unrepresentable: self.0 }
+    }
+}
+
+/// 把卡片镜像导出为指定格式
+pub fn export(dump: &Dump, format: ImageFormat) -> Result<Converted<Vec<u8>>>{
+    let mut notes = Notes::default();
+    let data = match format{
+        ImageFormat::Json => serde_json::to_vec_pretty(dump)?,
+        ImageFormat::Bin => raw::export(dump, &mut notes),
+        ImageFormat::Nfc => flipper::export(dump, &mut notes)?.into_bytes(),
+        ImageFormat::Pm3 => serde_json::to_vec_pretty(&proxmark::export(dump, &mut notes)?)?,
+        ImageFormat::Ndef => ndef::export(dump, &mut notes)?,
+    };
+    Ok(notes.finish(data))
+}
+
+/// 从指定格式导入卡片镜像
+pub fn import(data: &[u8], format: ImageFormat, options: ImportOptions) -> Result<Converted<Dump>>{
+    let mut notes = Notes::default();
+    let dump = match format{
+        ImageFormat::Json => {
+            //可以直接使用 /dump 的完整应答
+            let mut value: serde_json::Value = serde_json::from_slice(data)?;
+            if let Some(data) = value.get_mut("data"){
+                value = data.take();
+            }
+            let dump: Dump = serde_json::from_value(value)?;
+            check_blocks(dump.model, "页数", dump.pages.len())?;
+            dump
+        }
+        ImageFormat::Bin => raw::import(data, options, &mut notes)?,
+        ImageFormat::Nfc => flipper::import(std::str::from_utf8(data)?, &mut notes)?,
+        ImageFormat::Pm3 => proxmark::import(&serde_json::from_slice(data)?, &mut notes)?,
+        ImageFormat::Ndef => ndef::import(data, options, &mut notes)?,
+    };
+    //所有格式都检查页编号，避免恢复时写入错误的页
+    dump.check_format()?;
+    Ok(notes.finish(dump))
+}
+
+/// 按卡片类型确定的镜像族
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Family{
+    Ntag,
+    Classic,
+    Iso15693,
+}
+
+impl Family{
+    fn of(dump: &Dump) -> Result<Family>{
+        if dump.model.is_classic() || dump.model == TagModel::Unknown && dump.card_type == CardType::Mifare{
+            Ok(Family::Classic)
+        }else if dump.model == TagModel::Iso15693 || dump.model == TagModel::Unknown && dump.card_type == CardType::ISO15693{
+            Ok(Family::Iso15693)
+        }else if dump.model.is_ntag() || dump.page_size == 4{
+            Ok(Family::Ntag)
+        }else{
+            Err(anyhow!("无法确定卡片类型 {:?} {:?} 页大小{}", dump.card_type, dump.model, dump.page_size))
+        }
+    }
+}
+
+/// 镜像最多的页(块)数，Mifare Classic 4K为256块
+const MAX_BLOCKS: usize = 256;
+
+/// 检查文件中的页(块)编号或总数，超出型号的页数时返回错误，避免按文件中的数值分配内存
+fn check_blocks(model: TagModel, field: &str, count: usize) -> Result<()>{
+    let max = model.total_pages().map(|total| total as usize).unwrap_or(MAX_BLOCKS);
+    if count > max{
+        return Err(anyhow!("{}超出范围:{} {:?}最多{}页", field, count, model, max));
+    }
+    Ok(())
+}
+
+/// Mifare Classic按块数确定型号
+fn classic_model(blocks: usize) -> TagModel{
+    if blocks > 64{ TagModel::MifareClassic4k }else{ TagModel::MifareClassic1k }
+}
+
+/// NTAG按CC确定型号
+fn ntag_model(blocks: &[Option<Vec<u8>>]) -> TagModel{
+    match blocks.get(3){
+        Some(Some(cc)) if cc.len() == 4 => TagModel::from_cc(&[cc[0], cc[1], cc[2], cc[3]]),
+        _ => TagModel::Unknown,
+    }
+}
+
+/// NTAG的7字节UID: 第0页前3字节 + 第1页4字节
+fn ntag_uid(blocks: &[Option<Vec<u8>>]) -> Vec<u8>{
+    match (blocks.first(), blocks.get(1)){
+        (Some(Some(page0)), Some(Some(page1))) if page0.len() >= 3 => {
+            let mut uid = page0[..3].to_vec();
+            uid.extend_from_slice(page1);
+            uid
+        }
+        _ => vec![],
+    }
+}
+
+/// Mifare Classic第0块中的UID，BCC校验通过时为4字节，否则按7字节处理
+fn classic_uid(blocks: &[Option<Vec<u8>>]) -> Vec<u8>{
+    match blocks.first(){
+        Some(Some(block0)) if block0.len() >= 7 => {
+            if block0[0] ^ block0[1] ^ block0[2] ^ block0[3] == block0[4]{
+                block0[..4].to_vec()
+            }else{
+                block0[..7].to_vec()
+            }
+        }
+        _ => vec![],
+    }
+}
+
+/// 由从第0块开始的数据生成镜像，缺失的块以0填充并标记为不可读
+fn build_dump(card_type: CardType, model: TagModel, uid: Vec<u8>, page_size: usize, blocks: Vec<Option<Vec<u8>>>) -> Result<Dump>{
+    check_blocks(model, "页数", blocks.len())?;
+    for (block, data) in blocks.iter().enumerate(){
+        if let Some(data) = data{
+            if data.len() != page_size{
+                return Err(anyhow!("第{}块数据长度错误:{} 应为{}", block, data.len(), page_size));
+            }
+        }
+    }
+    let missing: Vec<bool> = blocks.iter().map(|data| data.is_none()).collect();
+    let blocks = blocks.into_iter().map(|data| data.unwrap_or_else(|| vec![0u8; page_size])).collect();
+    let mut dump = Dump::from_blocks(card_type, model, uid, page_size, blocks);
+    for (page, missing) in dump.pages.iter_mut().zip(missing){
+        if missing{
+            page.readable = false;
+        }
+    }
+    Ok(dump)
+}
+
+/// 镜像中不可读的页，导出时以0填充
+fn note_unreadable(dump: &Dump, notes: &mut Notes){
+    for page in dump.pages.iter().filter(|page| !page.readable){
+        notes.add(format!("Page {}", page.page), "导出时无法读取，以0填充");
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use serde_json::json;
+
+    const NTAG_UID: [u8; 7] = [0x04, 0xA1, 0xB2, 0xC3, 0xD4, 0xE5, 0xF6];
+
+    /// NTAG213镜像，用户数据区为递增的字节
+    fn ntag213() -> Dump{
+        let mut blocks: Vec<Vec<u8>> = (0..45u8).map(|page| vec![page, page.wrapping_mul(3), 0x5A, !page]).collect();
+        blocks[0] = vec![0x04, 0xA1, 0xB2, 0x04 ^ 0xA1 ^ 0xB2 ^ 0x88];
+        blocks[1] = vec![0xC3, 0xD4, 0xE5, 0xF6];
+        blocks[2] = vec![0xC3 ^ 0xD4 ^ 0xE5 ^ 0xF6, 0x48, 0, 0];
+        blocks[3] = vec![0xE1, 0x10, 0x12, 0x00];
+        Dump::from_blocks(CardType::UltraLight, TagModel::Ntag213, NTAG_UID.to_vec(), 4, blocks)
+    }
+
+    /// Mifare Classic 1K镜像，第0块包含4字节UID和BCC
+    fn classic1k() -> Dump{
+        let mut blocks: Vec<Vec<u8>> = (0..64u8).map(|block| vec![block; 16]).collect();
+        blocks[0] = vec![0x01, 0x02, 0x03, 0x04, 0x04, 0x08, 0x04, 0x00, 0x62, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69];
+        for trailer in (3..64).step_by(4){
+            blocks[trailer] = [[0xFF; 6].as_slice(), &[0xFF, 0x07, 0x80, 0x69], &[0xFF; 6]].concat();
+        }
+        Dump::from_blocks(CardType::Mifare, TagModel::MifareClassic1k, vec![1, 2, 3, 4], 16, blocks)
+    }
+
+    fn round_trip(dump: &Dump, format: ImageFormat) -> Dump{
+        let options = ImportOptions{ card_type: dump.card_type, block_size: None };
+        let exported = export(dump, format).unwrap().value;
+        import(&exported, format, options).unwrap().value
+    }
+
+    fn assert_same(a: &Dump, b: &Dump){
+        assert_eq!(a.model, b.model);
+        assert_eq!(a.uid, b.uid);
+        assert_eq!(a.page_size, b.page_size);
+        assert_eq!(a.to_bytes(), b.to_bytes());
+    }
+
+    #[test]
+    fn ntag_round_trips(){
+        let dump = ntag213();
+        for format in [ImageFormat::Json, ImageFormat::Bin, ImageFormat::Nfc, ImageFormat::Pm3]{
+            assert_same(&dump, &round_trip(&dump, format));
+        }
+    }
+
+    #[test]
+    fn classic_round_trips(){
+        let dump = classic1k();
+        for format in [ImageFormat::Json, ImageFormat::Bin, ImageFormat::Nfc, ImageFormat::Pm3]{
+            assert_same(&dump, &round_trip(&dump, format));
+        }
+    }
+
+    #[test]
+    fn iso15693_round_trips_through_flipper(){
+        let blocks = (0..8u8).map(|block| vec![block, 1, 2, 3]).collect();
+        let mut dump = Dump::from_blocks(CardType::ISO15693, TagModel::Iso15693, vec![0xE0, 4, 1, 2, 3, 4, 5, 6], 4, blocks);
+        dump.pages[2].locked = true;
+        let imported = round_trip(&dump, ImageFormat::Nfc);
+        assert_same(&dump, &imported);
+        assert!(imported.pages[2].locked && !imported.pages[3].locked);
+    }
+
+    #[test]
+    fn unreadable_classic_blocks_export_as_unknown(){
+        let mut dump = classic1k();
+        dump.pages[7].readable = false;
+        let converted = export(&dump, ImageFormat::Nfc).unwrap();
+        let text = String::from_utf8(converted.value).unwrap();
+        assert!(text.contains(&format!("Block 7: {}", ["??"; 16].join(" "))));
+        let imported = import(text.as_bytes(), ImageFormat::Nfc, ImportOptions::default()).unwrap().value;
+        assert!(!imported.pages[7].readable);
+        assert!(imported.pages[6].readable);
+    }
+
+    #[test]
+    fn exports_list_unrepresentable_fields(){
+        let converted = export(&ntag213(), ImageFormat::Pm3).unwrap();
+        let fields: Vec<&str> = converted.unrepresentable.iter().map(|note| note.field.as_str()).collect();
+        assert!(fields.contains(&"Signature"));
+        assert!(fields.contains(&"Counter"));
+    }
+
+    #[test]
+    fn ndef_round_trips(){
+        for len in [0, 3, 254, 255, 300]{
+            let message: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut dump = ntag213();
+            let tlv = ndef_tlv(&message).unwrap();
+            assert_eq!(tlv.len() % 4, 0);
+            for (i, chunk) in tlv.chunks(4).enumerate().take(36){
+                dump.pages[4 + i].data = chunk.to_vec();
+            }
+            if tlv.len() > 36 * 4{
+                assert!(export(&dump, ImageFormat::Ndef).is_err());
+                continue;
+            }
+            assert_eq!(export(&dump, ImageFormat::Ndef).unwrap().value, message, "{}", len);
+            let imported = import(&message, ImageFormat::Ndef, ImportOptions::default()).unwrap().value;
+            assert_eq!(export(&imported, ImageFormat::Ndef).unwrap().value, message);
+        }
+    }
+
+    #[test]
+    fn ndef_tlv_length_header(){
+        assert_eq!(ndef_tlv(&[0xD1; 2]).unwrap(), [0x03, 2, 0xD1, 0xD1, 0xFE, 0, 0, 0]);
+        let tlv = ndef_tlv(&[0; 255]).unwrap();
+        assert_eq!(tlv[..4], [0x03, 0xFF, 0x00, 0xFF]);
+        assert_eq!(tlv[4 + 255], 0xFE);
+        assert!(ndef_tlv(&vec![0; u16::MAX as usize]).is_err());
+    }
+
+    #[test]
+    fn oversized_imports_are_rejected(){
+        //超过256页的.bin和.ndef
+        assert!(import(&[0; 257 * 4], ImageFormat::Bin, ImportOptions::default()).is_err());
+        assert!(import(&[0; 256 * 4], ImageFormat::Bin, ImportOptions::default()).is_ok());
+        assert!(import(&[0; 2000], ImageFormat::Ndef, ImportOptions::default()).is_err());
+        //JSON中的页数超出型号，或页编号不连续
+        let mut dump = serde_json::to_value(ntag213()).unwrap();
+        let pages = dump["pages"].as_array_mut().unwrap();
+        pages.push(pages[44].clone());
+        pages[45]["page"] = 45.into();
+        assert!(import(dump.to_string().as_bytes(), ImageFormat::Json, ImportOptions::default()).is_err());
+        let mut dump = serde_json::to_value(ntag213()).unwrap();
+        dump["pages"][44]["page"] = 300.into();
+        assert!(import(dump.to_string().as_bytes(), ImageFormat::Json, ImportOptions::default()).is_err());
+        let mut dump = serde_json::to_value(ntag213()).unwrap();
+        dump["model"] = json!("Unknown");
+        dump["pages"][44]["page"] = 4.into();
+        assert!(import(dump.to_string().as_bytes(), ImageFormat::Json, ImportOptions::default()).is_err());
+    }
+
+    #[test]
+    fn bin_requires_whole_blocks(){
+        assert!(import(&[0; 6], ImageFormat::Bin, ImportOptions::default()).is_err());
+        let options = ImportOptions{ card_type: CardType::Mifare, block_size: None };
+        assert!(import(&[0; 20], ImageFormat::Bin, options).is_err());
+    }
+}
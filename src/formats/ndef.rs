@@ -0,0 +1,82 @@
+//! NDEF消息 .ndef: 只包含NDEF消息本身，导入导出时按NFC Forum Type 2的TLV格式放在第4页开始的用户数据区
+
+use anyhow::{anyhow, Result};
+use crate::dump::Dump;
+use crate::ntag::{CardType, PageKind, TagModel, PAGE_SIZE, USER_START_PAGE};
+use super::{build_dump, Family, ImportOptions, Notes};
+
+const TLV_NULL: u8 = 0x00;
+const TLV_NDEF: u8 = 0x03;
+const TLV_TERMINATOR: u8 = 0xFE;
+
+pub(super) fn export(dump: &Dump, notes: &mut Notes) -> Result<Vec<u8>>{
+    if Family::of(dump)? != Family::Ntag{
+        return Err(anyhow!(".ndef只支持NTAG/Ultralight卡片"));
+    }
+    let user: Vec<u8> = dump.pages.iter()
+        .filter(|page| page.page >= USER_START_PAGE as u16 && matches!(page.kind, PageKind::User | PageKind::Unknown))
+        .flat_map(|page| page.data.iter().copied())
+        .collect();
+    let message = find_ndef(&user)?;
+    notes.add("UID", ".ndef只包含NDEF消息，UID、锁定字节、CC、配置页和NDEF以外的用户数据不会导出");
+    Ok(message)
+}
+
+/// 在用户数据区中查找NDEF TLV
+fn find_ndef(user: &[u8]) -> Result<Vec<u8>>{
+    let mut pos = 0;
+    while pos < user.len(){
+        let tlv = user[pos];
+        pos += 1;
+        match tlv{
+            TLV_NULL => continue,
+            TLV_TERMINATOR => break,
+            _ => ()
+        }
+        let (len, header) = match user.get(pos){
+            Some(0xFF) if pos + 2 < user.len() => (u16::from_be_bytes([user[pos + 1], user[pos + 2]]) as usize, 3),
+            Some(len) => (*len as usize, 1),
+            None => break,
+        };
+        pos += header;
+        if pos + len > user.len(){
+            return Err(anyhow!("TLV长度{}超出用户数据区", len));
+        }
+        if tlv == TLV_NDEF{
+            return Ok(user[pos..pos + len].to_vec());
+        }
+        pos += len;
+    }
+    Err(anyhow!("用户数据区中没有NDEF消息"))
+}
+
+/// NDEF消息加上TLV头和结束符，补齐到整页
+pub(crate) fn ndef_tlv(message: &[u8]) -> Result<Vec<u8>>{
+    if message.len() > u16::MAX as usize - 1{
+        return Err(anyhow!("NDEF消息过长:{}", message.len()));
+    }
+    let mut tlv = vec![TLV_NDEF];
+    if message.len() < 0xFF{
+        tlv.push(message.len() as u8);
+    }else{
+        tlv.push(0xFF);
+        tlv.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    }
+    tlv.extend_from_slice(message);
+    tlv.push(TLV_TERMINATOR);
+    tlv.resize(tlv.len().div_ceil(PAGE_SIZE) * PAGE_SIZE, 0);
+    Ok(tlv)
+}
+
+pub(super) fn import(data: &[u8], options: ImportOptions, notes: &mut Notes) -> Result<Dump>{
+    if matches!(options.card_type, CardType::Mifare | CardType::ISO15693){
+        return Err(anyhow!(".ndef只支持导入到NTAG/Ultralight卡片"));
+    }
+    let tlv = ndef_tlv(data)?;
+
+    //第0~3页不在.ndef中，标记为不可读，恢复时会跳过
+    let mut pages: Vec<Option<Vec<u8>>> = vec![None; USER_START_PAGE as usize];
+    pages.extend(tlv.chunks(PAGE_SIZE).map(|chunk| Some(chunk.to_vec())));
+    notes.add("UID", ".ndef中没有UID、锁定字节、CC和配置页，只导入第4页开始的NDEF TLV");
+    build_dump(options.card_type, TagModel::Unknown, vec![], PAGE_SIZE, pages)
+}
@@ -0,0 +1,203 @@
+//! Proxmark3 JSON: FileType为mfu(NTAG/Ultralight)、mfcard(Mifare Classic)或15693
+
+use anyhow::{anyhow, Result};
+use serde_json::{json, Map, Value};
+use crate::dump::Dump;
+use crate::ntag::{CardType, PageKind, TagModel};
+use super::{build_dump, check_blocks, classic_model, classic_uid, note_unreadable, ntag_model, Family, Notes};
+
+fn upper_hex(data: &[u8]) -> String{
+    hex::encode_upper(data)
+}
+
+fn blocks_json(dump: &Dump) -> Value{
+    let blocks: Map<String, Value> = dump.pages.iter()
+        .map(|page| (page.page.to_string(), Value::String(upper_hex(&page.data))))
+        .collect();
+    Value::Object(blocks)
+}
+
+pub(super) fn export(dump: &Dump, notes: &mut Notes) -> Result<Value>{
+    let uid = upper_hex(&dump.uid);
+    note_unreadable(dump, notes);
+    let value = match Family::of(dump)?{
+        Family::Ntag => {
+            notes.add("Signature", "镜像中没有原厂签名，以0填充");
+            notes.add("Counter", "镜像中没有计数器和防撕裂标志，以0填充");
+            let version = match dump.model{
+                TagModel::Ntag213 => "0004040201000F03",
+                TagModel::Ntag215 => "0004040201001103",
+                TagModel::Ntag216 => "0004040201001303",
+                _ => "0000000000000000",
+            };
+            json!({
+                "Created": "xelc-mini335te",
+                "FileType": "mfu",
+                "Card": {
+                    "UID": uid,
+                    "Version": version,
+                    "TBO_0": "0000",
+                    "TBO_1": "00",
+                    "Signature": upper_hex(&[0u8; 32]),
+                    "Counter0": "000000",
+                    "Tearing0": "00",
+                    "Counter1": "000000",
+                    "Tearing1": "00",
+                    "Counter2": "000000",
+                    "Tearing2": "00",
+                },
+                "blocks": blocks_json(dump),
+            })
+        }
+        Family::Classic => {
+            let four_k = dump.model == TagModel::MifareClassic4k || dump.pages.len() > 64;
+            let mut keys = Map::new();
+            let mut sector = 0;
+            for page in &dump.pages{
+                if dump.model.page_kind(page.page) == PageKind::SectorTrailer && page.data.len() == 16{
+                    keys.insert(sector.to_string(), json!({
+                        "KeyA": upper_hex(&page.data[..6]),
+                        "KeyB": upper_hex(&page.data[10..]),
+                        "AccessConditions": upper_hex(&page.data[6..10]),
+                    }));
+                    sector += 1;
+                }
+            }
+            json!({
+                "Created": "xelc-mini335te",
+                "FileType": "mfcard",
+                "Card": {
+                    "UID": uid,
+                    "ATQA": if four_k{ "0200" }else{ "0400" },
+                    "SAK": if four_k{ "18" }else{ "08" },
+                },
+                "blocks": blocks_json(dump),
+                "SectorKeys": keys,
+            })
+        }
+        Family::Iso15693 => {
+            notes.add("DSFID", "镜像中没有DSFID和AFI，以0填充");
+            json!({
+                "Created": "xelc-mini335te",
+                "FileType": "15693",
+                "Card": {
+                    "UID": uid,
+                    "DSFID": "00",
+                    "AFI": "00",
+                },
+                "blocks": blocks_json(dump),
+            })
+        }
+    };
+    Ok(value)
+}
+
+/// 读取blocks对象，缺失的块为空
+fn parse_blocks(value: &Value) -> Result<Vec<Option<Vec<u8>>>>{
+    let object = value.get("blocks").and_then(Value::as_object).ok_or_else(|| anyhow!("缺少blocks"))?;
+    let mut blocks: Vec<Option<Vec<u8>>> = vec![];
+    for (key, data) in object{
+        let block = key.parse::<usize>().map_err(|err| anyhow!("块编号错误:{} {:?}", key, err))?;
+        let data = data.as_str().ok_or_else(|| anyhow!("第{}块不是字符串", block))?;
+        check_blocks(TagModel::Unknown, "块编号", block.saturating_add(1))?;
+        if block >= blocks.len(){
+            blocks.resize(block + 1, None);
+        }
+        blocks[block] = Some(hex::decode(data).map_err(|err| anyhow!("第{}块格式错误 {:?}", block, err))?);
+    }
+    Ok(blocks)
+}
+
+/// 读取Card中的字符串字段
+fn card_field<'a>(value: &'a Value, key: &str) -> Option<&'a str>{
+    value.get("Card").and_then(|card| card.get(key)).and_then(Value::as_str)
+}
+
+/// 记录Card和顶层中未使用的字段
+fn note_ignored(value: &Value, used: &[&str], notes: &mut Notes){
+    if let Some(card) = value.get("Card").and_then(Value::as_object){
+        for key in card.keys().filter(|key| !used.contains(&key.as_str())){
+            notes.add(format!("Card.{}", key), "页模型中无法表示，已忽略");
+        }
+    }
+    if let Some(object) = value.as_object(){
+        for key in object.keys(){
+            if !matches!(key.as_str(), "Created" | "FileType" | "Card" | "blocks" | "SectorKeys"){
+                notes.add(key.clone(), "页模型中无法表示，已忽略");
+            }
+        }
+    }
+}
+
+pub(super) fn import(value: &Value, notes: &mut Notes) -> Result<Dump>{
+    let file_type = value.get("FileType").and_then(Value::as_str).ok_or_else(|| anyhow!("缺少FileType"))?;
+    let uid = match card_field(value, "UID"){
+        Some(uid) => hex::decode(uid)?,
+        None => vec![],
+    };
+    let blocks = parse_blocks(value)?;
+    match file_type{
+        "mfu" | "mfulc" => {
+            let model = match card_field(value, "Version").map(hex::decode){
+                Some(Ok(version)) if version.len() == 8 && version[2] == 0x04 => match version[6]{
+                    0x0F => TagModel::Ntag213,
+                    0x11 => TagModel::Ntag215,
+                    0x13 => TagModel::Ntag216,
+                    _ => ntag_model(&blocks),
+                },
+                _ => ntag_model(&blocks),
+            };
+            check_blocks(model, "块编号", blocks.len())?;
+            note_ignored(value, &["UID", "Version"], notes);
+            build_dump(CardType::UltraLight, model, uid, 4, blocks)
+        }
+        "mfcard" | "mfc v2" | "mfc v3" => {
+            let mut blocks = blocks;
+            let model = classic_model(blocks.len());
+            if let Some(total) = model.total_pages(){
+                blocks.resize(blocks.len().max(total as usize), None);
+            }
+            let uid = if uid.is_empty(){ classic_uid(&blocks) }else{ uid };
+            note_ignored(value, &["UID"], notes);
+            build_dump(CardType::Mifare, model, uid, 16, blocks)
+        }
+        "15693" | "iso15" => {
+            let size = blocks.iter().flatten().map(|block| block.len()).next().unwrap_or(4);
+            note_ignored(value, &["UID"], notes);
+            build_dump(CardType::ISO15693, TagModel::Iso15693, uid, size, blocks)
+        }
+        _ => Err(anyhow!("不支持的Proxmark3文件类型:{}", file_type)),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn rejects_oversized_block_number(){
+        for file_type in ["mfu", "mfcard", "15693"]{
+            let value = json!({
+                "FileType": file_type,
+                "Card": { "UID": "04A1B2C3D4E5F6" },
+                "blocks": { "18446744073709551614": "00000000" },
+            });
+            assert!(import(&value, &mut Notes::default()).is_err(), "{}", file_type);
+        }
+        let value = json!({
+            "FileType": "mfcard",
+            "blocks": { "4294967296": "00000000000000000000000000000000" },
+        });
+        assert!(import(&value, &mut Notes::default()).is_err());
+    }
+
+    #[test]
+    fn rejects_pages_beyond_model(){
+        let value = json!({
+            "FileType": "mfu",
+            "Card": { "UID": "04A1B2C3D4E5F6", "Version": "0004040201000F03" },
+            "blocks": { "45": "00000000" },
+        });
+        assert!(import(&value, &mut Notes::default()).is_err());
+    }
+}
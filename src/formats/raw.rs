@@ -0,0 +1,32 @@
+//! 原始二进制 .bin: 从第0页(块)开始依次排列，没有任何头部
+
+use anyhow::{anyhow, Result};
+use crate::dump::Dump;
+use crate::ntag::{CardType, TagModel};
+use super::{build_dump, classic_model, classic_uid, note_unreadable, ntag_model, ntag_uid, Family, ImportOptions, Notes};
+
+pub(super) fn export(dump: &Dump, notes: &mut Notes) -> Vec<u8>{
+    if Family::of(dump).ok() == Some(Family::Iso15693) && !dump.uid.is_empty(){
+        notes.add("UID", "ISO15693的UID不在数据块中，.bin中不包含");
+    }
+    note_unreadable(dump, notes);
+    dump.to_bytes()
+}
+
+pub(super) fn import(data: &[u8], options: ImportOptions, notes: &mut Notes) -> Result<Dump>{
+    let card_type = options.card_type;
+    let block_size = options.block_size.unwrap_or(if card_type == CardType::Mifare{ 16 }else{ 4 });
+    if block_size == 0 || data.is_empty() || !data.len().is_multiple_of(block_size){
+        return Err(anyhow!("数据长度{}必须是块大小{}的整数倍", data.len(), block_size));
+    }
+    let blocks: Vec<Option<Vec<u8>>> = data.chunks(block_size).map(|chunk| Some(chunk.to_vec())).collect();
+    let (model, uid) = match card_type{
+        CardType::Mifare => (classic_model(blocks.len()), classic_uid(&blocks)),
+        CardType::ISO15693 => {
+            notes.add("UID", "ISO15693的UID不在数据块中，.bin中没有UID");
+            (TagModel::Iso15693, vec![])
+        }
+        _ => (ntag_model(&blocks), ntag_uid(&blocks)),
+    };
+    build_dump(card_type, model, uid, block_size, blocks)
+}
@@ -0,0 +1,12 @@
+//! 字节数组按hex字符串序列化
+
+use serde::{Deserialize, Deserializer, Serializer};
+
+pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error>{
+    serializer.serialize_str(&hex::encode(data))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error>{
+    let s = String::deserialize(deserializer)?;
+    hex::decode(s.replace(' ', "")).map_err(serde::de::Error::custom)
+}
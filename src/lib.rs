@@ -0,0 +1,27 @@
+//! XELC-MINI335TE 读卡器驱动
+//!
+//! ```no_run
+//! use xelc_mini335te::{Reader, ReaderOptions};
+//!
+//! let reader = Reader::open("COM4", ReaderOptions::default())?;
+//! if let Some(uid) = reader.uid(){
+//!     println!("卡片编号: {}", hex::encode(uid));
+//! }
+//! let data = reader.read_pages(4, 4)?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+pub mod crypto;
+pub mod dump;
+pub mod formats;
+mod hex_serde;
+pub mod mirror;
+pub mod ntag;
+pub mod policy;
+pub mod storage;
+pub mod ticket;
+
+pub use dump::Dump;
+pub use ntag::{Card, CardType, DiffBase, PackageInfo, PageKind, Reader, ReaderOptions, ReaderState, ReaderStatus, TagModel, TapHook};
+pub use policy::{PagePolicy, WritePolicy};
+pub use storage::StorageOrder;
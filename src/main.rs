@@ -1,17 +1,215 @@
-mod ntag;
 use log::LevelFilter;
-use ntag::CardType;
+use xelc_mini335te::{Card, CardType, DiffBase, Dump, Reader, ReaderOptions, ReaderState, ReaderStatus, StorageOrder, WritePolicy};
+use xelc_mini335te::crypto::{check_signature, open_frame, roll_token, seal_frame, sign_frame, KeyStore, Opened, PayloadKeys, RollingLedger, RollingOutcome, Signature, DEFAULT_ITERATIONS};
+use xelc_mini335te::dump::{restore as restore_dump, RestoreOptions};
+use xelc_mini335te::formats::{self, Converted, ImageFormat, ImportOptions, Unrepresentable};
+use xelc_mini335te::mirror::{read_mirror, write_mirror, write_mirror_url, MirrorConfig, MirrorMode, MirrorUrl};
+use xelc_mini335te::storage::{delete_file, encode_frame, format_files, list_files, read_file, write_file, DEFAULT_MAX_FILES, migrate_legacy, migrate_layout, patch_document, read_document, read_fields, read_frame, read_record, write_document, write_fields, write_record, ContentType, DocumentWrite, LayoutOutcome, Layouts, MigrateOutcome, SlotLayout};
+use xelc_mini335te::ntag::{DEFAULT_TIMEOUT, PAGE_SIZE};
+use xelc_mini335te::ticket::{TicketCheck, TicketDb};
 use structopt::StructOpt;
 use tide::Request;
 use tide::Response;
 use tide::StatusCode;
 use tide::prelude::*;
 use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
 
 #[derive(Debug, StructOpt)]
 struct Cli {
     port: Option<u32>,
     ip: Option<String>,
+    /// /read、/write 默认的字节顺序: Natural 或 Legacy(旧版倒序)
+    #[structopt(long, default_value = "Natural")]
+    order: StorageOrder,
+    /// 刷卡时自动迁移旧版卡片，数据开头的固定内容(hex)
+    #[structopt(long)]
+    migrate_header: Option<String>,
+    /// 刷卡时自动迁移旧版卡片，数据长度
+    #[structopt(long)]
+    migrate_len: Option<usize>,
+    /// 所有写入默认回读校验
+    #[structopt(long)]
+    verify: bool,
+    /// /write 默认写入带长度头的数据
+    #[structopt(long)]
+    frame: bool,
+    /// /read_record、/write_record 双槽存储的起始页
+    #[structopt(long, default_value = "4")]
+    record_start: u8,
+    /// /read_record、/write_record 每个槽的数据容量(字节)
+    #[structopt(long, default_value = "64")]
+    record_capacity: usize,
+    /// 刷卡时缓存的起始页
+    #[structopt(long, default_value = "4")]
+    cache_start: u8,
+    /// 刷卡时缓存的页数，设置后 /read 等读取默认使用缓存
+    #[structopt(long)]
+    cache_pages: Option<u8>,
+    /// 字段布局文件(JSON)，/fields 按其中的字段读写
+    #[structopt(long)]
+    layout: Option<String>,
+    /// 刷卡时自动把旧版本布局的卡片升级到当前版本
+    #[structopt(long)]
+    layout_migrate: bool,
+    /// 写保护策略文件(JSON)，未指定时使用内置策略
+    #[structopt(long)]
+    policy: Option<String>,
+    /// 特权令牌，写入时提供token参数可以不受写保护策略限制
+    #[structopt(long)]
+    admin_token: Option<String>,
+    /// 数据加密密钥文件(JSON)，/read 自动解密加密的数据
+    #[structopt(long)]
+    payload_keys: Option<String>,
+    /// /write 默认加密数据，需要 --payload-keys
+    #[structopt(long)]
+    encrypt: bool,
+    /// /write 默认在数据后面写入签名，需要 --payload-keys
+    #[structopt(long)]
+    sign: bool,
+    /// 加密的密钥库文件，其他操作按名称引用其中的密钥
+    #[structopt(long)]
+    keystore: Option<String>,
+    /// 密钥库口令
+    #[structopt(long, env = "XELC_KEYSTORE_PASSPHRASE", hide_env_values = true)]
+    keystore_passphrase: Option<String>,
+    /// 用口令加密明文密钥库(JSON)，保存到 --keystore 后退出
+    #[structopt(long)]
+    keystore_seal: Option<String>,
+    /// 防克隆令牌的保留页，设置后每次刷卡写入新的令牌，需要 --payload-keys
+    #[structopt(long)]
+    rolling_page: Option<u8>,
+    /// 防克隆令牌的服务器记录文件(JSON)
+    #[structopt(long, default_value = "rolling.json")]
+    rolling_ledger: String,
+    /// 票券库文件(JSON)，设置后每次刷卡验票
+    #[structopt(long)]
+    tickets: Option<String>,
+    /// 验票通过时的蜂鸣器参数
+    #[structopt(long, default_value = "1")]
+    ticket_beep_ok: u8,
+    /// 验票不通过时的蜂鸣器参数
+    #[structopt(long, default_value = "3")]
+    ticket_beep_reject: u8,
+}
+
+/// 服务器配置
+#[derive(Debug, Clone, Default)]
+struct Config {
+    /// /read、/write 默认的字节顺序
+    order: StorageOrder,
+    /// 刷卡时自动迁移旧版卡片: (数据头, 数据长度)
+    migrate: Option<(Vec<u8>, usize)>,
+    /// 写入后默认回读校验
+    verify: bool,
+    /// /write 默认写入带长度头的数据
+    frame: bool,
+    /// 双槽存储的位置
+    record: SlotLayout,
+    /// 刷卡时缓存的页范围: (起始页, 页数)
+    cache: Option<(u8, u8)>,
+    /// 字段布局
+    layouts: Arc<Layouts>,
+    /// 刷卡时自动升级字段布局
+    layout_migrate: bool,
+    /// 写保护策略
+    policy: Arc<WritePolicy>,
+    /// 特权令牌
+    admin_token: Option<String>,
+    /// 数据加密密钥
+    payload_keys: Option<Arc<PayloadKeys>>,
+    /// /write 默认加密数据
+    encrypt: bool,
+    /// /write 默认写入签名
+    sign: bool,
+    /// 密钥库
+    keystore: Option<Arc<KeyStore>>,
+    /// 防克隆令牌的保留页
+    rolling_page: Option<u8>,
+    /// 防克隆令牌的服务器记录
+    rolling: Arc<Mutex<RollingLedger>>,
+    /// 票券库，设置后每次刷卡验票
+    tickets: Option<Arc<Mutex<TicketDb>>>,
+    /// 验票通过、不通过时的蜂鸣器参数
+    ticket_beep: (u8, u8),
+    /// 最近一次验票结果
+    last_ticket: Arc<Mutex<Option<TicketCheck>>>,
+}
+
+impl Config {
+    fn from_cli(args: &Cli) -> Result<Config> {
+        let migrate = match (&args.migrate_header, args.migrate_len) {
+            (Some(header), Some(len)) => Some((hex::decode(header).map_err(|err| anyhow!("--migrate-header不是hex字符串 {:?}", err))?, len)),
+            (None, None) => None,
+            _ => return Err(anyhow!("--migrate-header 和 --migrate-len 需要同时设置")),
+        };
+        let policy = match &args.policy {
+            Some(path) => WritePolicy::load(path)?,
+            None => WritePolicy::default(),
+        };
+        let payload_keys = match &args.payload_keys {
+            Some(path) => Some(Arc::new(PayloadKeys::load(path)?)),
+            None => None,
+        };
+        if (args.encrypt || args.sign) && payload_keys.is_none() {
+            return Err(anyhow!("--encrypt、--sign 需要同时设置 --payload-keys"));
+        }
+        let rolling = match args.rolling_page {
+            Some(_) if payload_keys.is_none() => return Err(anyhow!("--rolling-page 需要同时设置 --payload-keys")),
+            Some(_) => RollingLedger::load(&args.rolling_ledger)?,
+            None => RollingLedger::default(),
+        };
+        let tickets = match &args.tickets {
+            Some(path) => Some(Arc::new(Mutex::new(TicketDb::load(path)?))),
+            None => None,
+        };
+        let keystore = match &args.keystore {
+            Some(path) => Some(Arc::new(KeyStore::open(path, &keystore_passphrase(args)?)?)),
+            None => None,
+        };
+        let layouts = match &args.layout {
+            Some(path) => Layouts::load(path)?,
+            None => Layouts::default(),
+        };
+        Ok(Config {
+            order: args.order,
+            migrate,
+            verify: args.verify,
+            frame: args.frame,
+            record: SlotLayout { start_page: args.record_start, capacity: args.record_capacity },
+            cache: args.cache_pages.map(|pages| (args.cache_start, pages)),
+            layouts: Arc::new(layouts),
+            layout_migrate: args.layout_migrate,
+            policy: Arc::new(policy),
+            admin_token: args.admin_token.clone(),
+            payload_keys,
+            encrypt: args.encrypt,
+            sign: args.sign,
+            keystore,
+            rolling_page: args.rolling_page,
+            rolling: Arc::new(Mutex::new(rolling)),
+            tickets,
+            ticket_beep: (args.ticket_beep_ok, args.ticket_beep_reject),
+            last_ticket: Arc::new(Mutex::new(None)),
+        })
+    }
+}
+
+/// 启动参数或环境变量中的密钥库口令
+fn keystore_passphrase(args: &Cli) -> Result<String> {
+    args.keystore_passphrase.clone().ok_or_else(|| anyhow!("需要设置 --keystore-passphrase 或环境变量 XELC_KEYSTORE_PASSPHRASE"))
+}
+
+/// 加密明文密钥库，保存到 --keystore
+fn seal_keystore(args: &Cli, plain: &str) -> Result<()> {
+    let path = args.keystore.as_ref().ok_or_else(|| anyhow!("--keystore-seal 需要同时设置 --keystore"))?;
+    let text = std::fs::read_to_string(plain).map_err(|err| anyhow!("读取明文密钥库失败 {} {:?}", plain, err))?;
+    let sealed = KeyStore::seal(&text, &keystore_passphrase(args)?, DEFAULT_ITERATIONS)?;
+    std::fs::write(path, sealed)?;
+    println!("密钥库已保存: {}", path);
+    Ok(())
 }
 
 #[derive(Debug, Deserialize)]
@@ -19,23 +217,359 @@ struct OpenParams {
     port: String,
     card_type:Option<CardType>,
     delay: Option<u32>,
+    fast_read: Option<u8>,
+    read_cnt: Option<u8>,
+    read_sig: Option<u8>,
     debug: Option<bool>
 }
 
 #[derive(Debug, Deserialize)]
 struct WriteParam {
     data: String,
+    order: Option<StorageOrder>,
+    frame: Option<bool>,
+    content_type: Option<ContentType>,
+    encrypt: Option<bool>,
+    sign: Option<bool>,
+    keyset: Option<String>,
+    diff: Option<DiffBase>,
+    verify: Option<bool>,
+    expected_uid: Option<String>,
+    token: Option<String>,
+    timeout: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
 struct ReadParam {
-    len: u8,
+    len: Option<usize>,
+    order: Option<StorageOrder>,
+    cache: Option<bool>,
+    keyset: Option<String>,
+    expected_uid: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MigrateParam {
+    header: String,
+    len: usize,
+    token: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReadPagesParam {
+    start: u8,
+    count: u8,
+    cache: Option<bool>,
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WritePagesParam {
+    start: u8,
+    data: String,
+    diff: Option<DiffBase>,
+    verify: Option<bool>,
+    token: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReadBytesParam {
+    offset: usize,
+    len: usize,
+    cache: Option<bool>,
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WriteBytesParam {
+    offset: usize,
+    data: String,
+    diff: Option<DiffBase>,
+    verify: Option<bool>,
+    token: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RecordParam {
+    data: Option<String>,
+    start: Option<u8>,
+    capacity: Option<usize>,
+    verify: Option<bool>,
+    token: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FieldsParam {
+    /// 逗号分隔的字段名，为空时读取所有字段
+    names: Option<String>,
+    cache: Option<bool>,
+    verify: Option<bool>,
+    token: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DocumentParam {
+    deflate: Option<bool>,
+    verify: Option<bool>,
+    cache: Option<bool>,
+    token: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MirrorParam {
+    mode: Option<MirrorMode>,
+    page: Option<u8>,
+    byte: Option<u8>,
+    counter: Option<bool>,
+    counter_pwd: Option<bool>,
+    url: Option<String>,
+    token: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RollingParam {
+    uid: String,
+    token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TicketParam {
+    token: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyParam {
+    set: String,
+    name: String,
+    token: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileParam {
+    name: Option<String>,
+    data: Option<String>,
+    max_files: Option<u8>,
+    verify: Option<bool>,
+    token: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DumpParam {
+    format: Option<ImageFormat>,
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RestoreParam {
+    format: Option<ImageFormat>,
+    card_type: Option<CardType>,
+    block_size: Option<usize>,
+    config: Option<bool>,
+    token: Option<String>,
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConvertParam {
+    from: ImageFormat,
+    to: ImageFormat,
+    card_type: Option<CardType>,
+    block_size: Option<usize>,
+}
+
+/// 导出、恢复整张卡片的默认超时时间
+const DUMP_TIMEOUT: Duration = Duration::from_millis(30000);
+
+impl AppState{
+    /// 请求中的cache参数，未指定时配置了刷卡缓存就使用缓存
+    fn use_cache(&self, cache: Option<bool>) -> bool{
+        cache.unwrap_or(self.config.cache.is_some())
+    }
+
+    /// 请求中的verify参数，未指定时使用启动参数
+    fn verify(&self, verify: Option<bool>) -> bool{
+        verify.unwrap_or(self.config.verify)
+    }
+
+    /// 请求中的token参数对应的写保护策略，token和特权令牌一致时不限制
+    fn write_policy(&self, token: Option<String>) -> Result<Option<Arc<WritePolicy>>>{
+        match (token, &self.config.admin_token){
+            (None, _) => Ok(Some(self.config.policy.clone())),
+            (Some(token), Some(admin)) if token_eq(token.as_bytes(), admin.as_bytes()) => Ok(None),
+            (Some(_), _) => Err(anyhow!("特权令牌错误")),
+        }
+    }
+
+    /// 检查特权令牌，没有配置特权令牌或令牌错误时返回失败
+    fn require_admin(&self, token: Option<String>) -> Result<()>{
+        let admin = self.config.admin_token.as_ref().ok_or_else(|| anyhow!("服务器没有配置特权令牌 --admin-token"))?;
+        match token{
+            Some(token) if token_eq(token.as_bytes(), admin.as_bytes()) => Ok(()),
+            _ => Err(anyhow!("特权令牌错误")),
+        }
+    }
+
+    /// 数据加密和签名的密钥: 指定了keyset时使用密钥库中的密钥集，否则使用 --payload-keys
+    fn payload_keys(&self, keyset: Option<String>) -> Result<Option<Arc<PayloadKeys>>>{
+        match keyset{
+            Some(name) => {
+                let set = self.keystore()?.set(&name)?;
+                let keys = set.payload().ok_or_else(|| anyhow!("密钥集{}没有数据加密密钥", name))?;
+                Ok(Some(Arc::new(keys.clone())))
+            }
+            None => Ok(self.config.payload_keys.clone()),
+        }
+    }
+
+    /// 密钥库
+    fn keystore(&self) -> Result<&Arc<KeyStore>>{
+        self.config.keystore.as_ref().ok_or_else(|| anyhow!("服务器没有配置密钥库 --keystore"))
+    }
+
+    /// 请求中的双槽位置，未指定的部分使用启动参数
+    fn record_layout(&self, start: Option<u8>, capacity: Option<usize>) -> SlotLayout{
+        SlotLayout{
+            start_page: start.unwrap_or(self.config.record.start_page),
+            capacity: capacity.unwrap_or(self.config.record.capacity),
+        }
+    }
+}
+
+/// 请求中的expected_uid参数(hex)
+fn expected_uid_param(expected_uid: Option<String>) -> Result<Option<Vec<u8>>>{
+    expected_uid.map(|uid| hex::decode(uid).map_err(|err| anyhow!("expected_uid格式错误 {:?}", err))).transpose()
+}
+
+/// 比较令牌，耗时和第一个不同字节的位置无关
+fn token_eq(a: &[u8], b: &[u8]) -> bool{
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// 请求超时时间(毫秒)，未指定时使用默认值
+fn timeout_param(timeout: Option<u64>) -> Duration{
+    timeout.map(Duration::from_millis).unwrap_or(DEFAULT_TIMEOUT)
+}
+
+/// 服务器状态
+#[derive(Default)]
+struct AppState{
+    config: Config,
+    /// 当前读卡器，关闭后保留，用于报告状态
+    reader: Mutex<Option<Arc<Reader>>>,
+    /// 串口打开失败时的状态，打开失败后reader为空
+    failure: Mutex<Option<ReaderStatus>>,
+}
+
+type State = Arc<AppState>;
+
+impl AppState{
+    /// 取出已打开的读卡器
+    fn reader(&self) -> Result<Arc<Reader>>{
+        let reader = self.reader.lock().map_err(|err| anyhow!("reader锁定失败:{:?}", err))?;
+        match reader.as_ref(){
+            Some(reader) if reader.is_opened() => Ok(reader.clone()),
+            _ => Err(anyhow!("串口未打开"))
+        }
+    }
+
+    /// 根据配置给新打开的读卡器添加刷卡回调
+    fn install_hooks(&self, reader: &Reader) -> Result<()>{
+        if let Some((header, len)) = self.config.migrate.clone(){
+            reader.add_tap_hook(move |card: &mut Card, uid: &[u8]| {
+                let outcome = migrate_legacy(card, &header, len)?;
+                if outcome == MigrateOutcome::Migrated{
+                    warn!("旧版卡片已迁移 uid={}", hex::encode(uid));
+                }else{
+                    info!("旧版卡片迁移 uid={} {:?}", hex::encode(uid), outcome);
+                }
+                Ok(())
+            })?;
+        }
+        if self.config.layout_migrate{
+            let layouts = self.config.layouts.clone();
+            reader.add_tap_hook(move |card: &mut Card, uid: &[u8]| {
+                let layout = match layouts.get(card.card_type()){
+                    Ok(layout) => layout,
+                    Err(_) => return Ok(()),
+                };
+                match migrate_layout(card, layout)?{
+                    LayoutOutcome::Migrated{ from, to } => warn!("卡片布局已升级 uid={} 版本:{} -> {}", hex::encode(uid), from, to),
+                    outcome => info!("卡片布局 uid={} {:?}", hex::encode(uid), outcome),
+                }
+                Ok(())
+            })?;
+        }
+        if let (Some(page), Some(keys)) = (self.config.rolling_page, self.config.payload_keys.clone()){
+            let ledger = self.config.rolling.clone();
+            let policy = self.config.policy.clone();
+            reader.add_tap_hook(move |card: &mut Card, uid: &[u8]| {
+                match card.guarded(Some(policy.clone()), |card| roll_token(card, &keys, &ledger, page))?{
+                    RollingOutcome::CloneDetected{ expected } => {
+                        return Err(anyhow!("疑似克隆卡 uid={} 卡片上的令牌和服务器记录的第{}个令牌不一致", hex::encode(uid), expected));
+                    }
+                    outcome => info!("防克隆令牌 uid={} {:?}", hex::encode(uid), outcome),
+                }
+                Ok(())
+            })?;
+        }
+        if let Some(tickets) = self.config.tickets.clone(){
+            let last = self.config.last_ticket.clone();
+            let (beep_ok, beep_reject) = self.config.ticket_beep;
+            reader.add_tap_hook(move |card: &mut Card, uid: &[u8]| {
+                let validated = TicketDb::validate(&tickets, card);
+                //出错时也要替换上一张卡片的结果，否则 /tickets/last 仍然返回上一张卡片的accepted
+                let (check, error) = match validated{
+                    Ok(check) => (check, None),
+                    Err(err) => (TicketCheck::failed(uid, &err), Some(err)),
+                };
+                let accepted = check.result.accepted();
+                if accepted{
+                    info!("验票通过 uid={} 计数器:{:?}", hex::encode(uid), check.counter);
+                }else if error.is_none(){
+                    warn!("验票不通过 uid={} {:?} 计数器:{:?}", hex::encode(uid), check.result, check.counter);
+                }
+                *last.lock().map_err(|err| anyhow!("验票结果锁定失败:{:?}", err))? = Some(check);
+                card.buzzer(if accepted{ beep_ok }else{ beep_reject })?;
+                match error{
+                    Some(err) => Err(anyhow!("验票失败 {:?}", err)),
+                    None => Ok(()),
+                }
+            })?;
+        }
+        if let Some((start, count)) = self.config.cache{
+            reader.add_tap_hook(move |card: &mut Card, uid: &[u8]| {
+                card.read_pages(start, count)?;
+                info!("卡片已缓存 uid={} 第{}页起共{}页", hex::encode(uid), start, count);
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// 关闭并取出当前读卡器
+    fn take_reader(&self) -> Result<Option<Arc<Reader>>>{
+        let mut reader = self.reader.lock().map_err(|err| anyhow!("reader锁定失败:{:?}", err))?;
+        Ok(reader.take())
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 struct ServerResponse {
     success: bool,
     message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<serde_json::Value>,
 }
 
 impl ServerResponse{
@@ -46,10 +580,13 @@ impl ServerResponse{
         resp
     }
     fn success(message: &str) -> Response{
-        ServerResponse::to_tide_resp(ServerResponse{ success:true, message:message.to_string() })
+        ServerResponse::to_tide_resp(ServerResponse{ success:true, message:message.to_string(), data: None })
+    }
+    fn success_with_data(message: &str, data: serde_json::Value) -> Response{
+        ServerResponse::to_tide_resp(ServerResponse{ success:true, message:message.to_string(), data: Some(data) })
     }
     fn error(message: &str) -> Response{
-        ServerResponse::to_tide_resp(ServerResponse{ success:false, message:message.to_string() })
+        ServerResponse::to_tide_resp(ServerResponse{ success:false, message:message.to_string(), data: None })
     }
 }
 
@@ -68,25 +605,59 @@ fn main() -> Result<()>{
     env_logger::Builder::new().filter_level(LevelFilter::Warn).init();
     
     let args = Cli::from_args();
-    let port = args.port.clone().unwrap_or(8180);
+    if let Some(plain) = &args.keystore_seal {
+        return seal_keystore(&args, plain);
+    }
+    let config = Config::from_cli(&args)?;
+    let port = args.port.unwrap_or(8180);
     let ip = args.ip.unwrap_or(String::from("::"));
     
     async_std::task::block_on(async {
-        let mut app = tide::new();
+        let mut app = tide::with_state(Arc::new(AppState{ config, ..Default::default() }));
         app.at("/").get(help);
         app.at("/open").get(open);
         app.at("/isopen").get(is_opened);
         app.at("/close").get(close);
+        app.at("/status").get(status);
         app.at("/uid").get(get_current_uid);
         app.at("/read").get(read_data);
         app.at("/write").get(write_data);
+        app.at("/read_pages").get(read_pages);
+        app.at("/write_pages").get(write_pages);
+        app.at("/read_bytes").get(read_bytes);
+        app.at("/write_bytes").get(write_bytes);
+        app.at("/migrate").get(migrate);
+        app.at("/read_record").get(read_record_handler);
+        app.at("/write_record").get(write_record_handler);
+        app.at("/fields").get(get_fields).put(put_fields);
+        app.at("/fields/migrate").get(migrate_fields);
+        app.at("/files").get(files_list);
+        app.at("/files/read").get(files_read);
+        app.at("/files/write").get(files_write);
+        app.at("/files/delete").get(files_delete);
+        app.at("/files/format").get(files_format);
+        app.at("/document").get(get_document).put(put_document).patch(patch_document_handler);
+        app.at("/dump").get(dump);
+        app.at("/restore").post(restore);
+        app.at("/convert").post(convert);
+        app.at("/policy").get(policy);
+        app.at("/mirror").get(mirror_get);
+        app.at("/mirror/set").get(mirror_set);
+        app.at("/mirror/url").get(mirror_url);
+        app.at("/tickets").get(tickets_list);
+        app.at("/tickets/issue").get(tickets_issue);
+        app.at("/tickets/last").get(tickets_last);
+        app.at("/rolling").get(rolling_list);
+        app.at("/rolling/reset").get(rolling_reset);
+        app.at("/keys").get(keys_list);
+        app.at("/keys/derive").get(keys_derive);
         println!("服务器启动: {}:{}", ip, port);
         app.listen(&format!("{}:{}", ip, port)).await?;
         Ok(())
     })
 }
 
-async fn help(_req: Request<()>) -> tide::Result {
+async fn help(_req: Request<State>) -> tide::Result {
     Ok(r#"
 
     服务器启动:
@@ -95,8 +666,33 @@ async fn help(_req: Request<()>) -> tide::Result {
     port: 监听端口 默认 8180
     ip: 监听IP地址 默认 ::
 
+    --order: /read、/write 默认的字节顺序 Natural(默认) 或 Legacy(旧版倒序)
+    --migrate-header、--migrate-len: 刷卡时自动迁移旧版卡片，数据开头的固定内容(hex)和数据长度
+    --verify: 所有写入默认回读校验
+    --frame: /write 默认写入带长度头的数据
+    --record-start、--record-capacity: 双槽存储的起始页(默认 4)和每个槽的数据容量(默认 64 字节)
+    --cache-start、--cache-pages: 刷卡时读取并缓存的起始页(默认 4)和页数，卡片停留期间读取直接使用缓存
+    --layout: 字段布局文件(JSON)，/fields 按其中的字段读写
+    --layout-migrate: 刷卡时自动把旧版本布局的卡片升级到当前版本
+    --policy: 写保护策略文件(JSON)，未指定时禁止写入UID、锁定字节、CC、动态锁定字节、配置页、
+        密码页(NTAG/Ultralight)和扇区尾块(Mifare)
+    --admin-token: 特权令牌，写入时提供相同的token参数可以不受写保护策略限制
+    --payload-keys: 数据加密密钥文件(JSON)，/read 自动解密加密的数据
+    --encrypt: /write 默认加密数据，需要 --payload-keys
+    --sign: /write 默认在数据后面写入签名，需要 --payload-keys
+    --keystore: 加密的密钥库文件，其他操作按名称引用其中的密钥
+    --keystore-passphrase: 密钥库口令，也可以用环境变量 XELC_KEYSTORE_PASSPHRASE 设置
+    --keystore-seal: 用口令加密明文密钥库(JSON)，保存到 --keystore 后退出
+    --rolling-page: 防克隆令牌的保留页，设置后每次刷卡检查并写入新的令牌，需要 --payload-keys
+    --rolling-ledger: 防克隆令牌的服务器记录文件(JSON) 默认 rolling.json
+    --tickets: 票券库文件(JSON)，设置后每次刷卡验票，需要在 /open 时配置 read_cnt、read_sig
+    --ticket-beep-ok: 验票通过时的蜂鸣器参数 默认 1
+    --ticket-beep-reject: 验票不通过时的蜂鸣器参数 默认 3
+
     示例:
     xelc-mini335te-server 8180 127.0.0.1
+    xelc-mini335te-server 8180 127.0.0.1 --order Legacy
+    xelc-mini335te-server --keystore keys.enc --keystore-seal keys.json
 
 
     HTTP API:
@@ -106,51 +702,266 @@ async fn help(_req: Request<()>) -> tide::Result {
     可选参数：
         card_type： Mifare, UltraLight, CPU, ISO14443B, ISO15693, Other
         delay: 读取频率 默认 300 (毫秒)
+        fast_read: 读卡器固件FAST_READ命令的功能码(十进制)，配置后多页读取一次完成；
+            读卡器无应答时自动改用READ
+        read_cnt: 读卡器固件READ_CNT命令的功能码(十进制)，读取NTAG213/215/216的NFC计数器
+        read_sig: 读卡器固件READ_SIG命令的功能码(十进制)，读取32字节原厂签名
         debug: 调试输出 默认 false
 
     /close 关闭串口
 
     /isopen 检查串口是否打开
 
+    /status 读卡器状态: Closed, Opening, Open, Degraded, Closing, Error
+        data中包含串口名称、最近一次错误及各时间戳(UNIX毫秒)
+
     /uid 读取卡片UID
 
     /write?data= 写入数据 data是字节数组转base64的字符串
     
     /read?len= 读取数据 len是要读取的字节长度，读取后转换成base64字符串返回
+        没有len时读取带长度头的数据，返回写入时的完整数据，data中包含len和content_type；
+        没有数据头、数据已损坏(CRC不一致)时返回失败
+        加密的数据自动解密，data中还包含encrypted和key_id(加密使用的密钥编号)；
+        数据被篡改、复制自其他卡片或没有对应的密钥时返回失败
+        signature为签名检查结果: valid(正确)、invalid(数据被修改、复制自其他卡片或密钥不存在)、unsigned(没有签名)、
+        unverified(有签名，服务器没有配置密钥，只返回明文数据)
+
+    /read、/write 可选参数：
+        order: 字节顺序 Natural(原始顺序) 或 Legacy(旧版倒序)，默认使用启动参数 --order
+        expected_uid: 卡片UID(hex)，当前卡片不是这张卡时不读写，直接返回失败
+
+    /write 可选参数：
+        frame: 写入带长度头的数据(只支持Natural顺序) 默认使用启动参数 --frame
+        content_type: 内容类型 binary(默认)、text、json、cbor、cbordeflate，指定时总是写入带长度头的数据
+        encrypt: 用当前密钥加密后写入带长度头的数据 默认使用启动参数 --encrypt
+        sign: 在带长度头的数据后面写入签名，数据保持明文 默认使用启动参数 --sign
+
+    /read、/write 可选参数：
+        keyset: 使用密钥库中这个密钥集的数据加密密钥(payload)，默认使用 --payload-keys
+
+    /read、/read_pages、/read_bytes 可选参数：
+        cache: 是否使用刷卡缓存，配置了 --cache-pages 时默认 true
+            缓存在卡片离开或更换时清空，写入时同步更新；缓存不完整时仍然从卡片读取
+
+    /migrate?header=&len= 迁移旧版倒序存储的卡片
+        header: 数据开头的固定内容(hex，同 --migrate-header)，len: 数据长度
+        message为 Migrated(已迁移)、AlreadyNatural(已是原始顺序)、NotRecognized(无法识别，未修改)
+
+    多页读写开始时记录卡片UID，完成后再次检查，操作过程中更换或移走卡片时返回失败
+
+    /read_pages?start=4&count=4 从start页开始读取count页，按卡片上的字节顺序返回base64字符串
+
+    /write_pages?start=4&data= 从start页开始写入，data是base64字符串，长度必须是4的整数倍
+
+    /read_bytes?offset=16&len=6 从字节地址offset(页号*4+页内偏移)开始读取len个字节
+
+    /write_bytes?offset=16&data= 从字节地址offset开始写入，不会改动范围外的字节
+
+    /write_record?data= 防撕裂写入: 两个槽各保存一条带序号和CRC的记录，每次覆盖较旧的槽
+        写入中途卡片离开时，另一个槽中的上一条记录保持完整
+
+    /read_record 读取两个槽中序号最大的完整记录，data中包含slot、seq和data(base64)
+
+    GET /fields 按字段布局读取，data中为字段名到值的JSON对象
+        布局有版本时message为卡片上的版本号，旧版本的卡片按旧版本的字段读取
+        names: 逗号分隔的字段名，默认读取所有字段
+        cache: 同 /read
+    
+    PUT /fields 按字段布局写入，请求体为字段名到值的JSON对象，没有出现的字段保持不变
+        只写入内容变化的页；verify、token、timeout 同 /write
+        空白卡片(版本0)写入时同时写入当前版本号，旧版本的卡片需要先升级
+    
+    /fields/migrate 把旧版本布局的卡片升级到当前版本，所有字段一次写入并回读校验
+        message为 Migrated 1 -> 2(已升级)、Current(已是当前版本)、Blank(空白卡片)、Unversioned(布局没有版本)
+    
+    多个应用共用一张卡片时，可以在第4页开始的用户数据区建立文件目录，每个应用读写自己的文件(只支持NTAG/Ultralight)：
+    
+    /files/format 格式化，写入空目录，原有数据全部丢失
+        max_files: 最大文件数 默认 8，目录保存两份，每个文件在每份目录中占用16字节
+    
+    /files 列出文件，data中包含每个文件的name、start_page、pages、len、crc
+    
+    /files/read?name= 读取文件，返回base64字符串，CRC不一致时返回失败
+    
+    /files/write?name=&data= 写入文件，data是base64字符串，文件名最多10字节，同名文件被替换
+        先写到空闲的页再更新较旧的一份目录，写入中途卡片离开时原文件和其他文件保持完整
+    
+    /files/delete?name= 删除文件
+    
+    /files/write、/files/delete 可选参数：
+        verify、token、timeout 同 /write
+    
+    GET /document 读取卡片上的JSON文档，data中为文档，空白卡片为null
+        cache: 同 /read
+    
+    PUT /document 写入整个JSON文档，请求体为文档
+        按CBOR编码，deflate压缩后更短时保存压缩后的数据，存储格式为带长度头的数据
+    
+    PATCH /document 按JSON Merge Patch(RFC 7386)修改文档，请求体为patch，data中返回修改后的文档
+        在同一张卡片上读取、合并、写入，只写入内容变化的页
+    
+    PUT、PATCH /document 可选参数：
+        deflate: true总是压缩，false不压缩，默认选择更短的一种
+        verify: 写入后回读校验 默认 true
+        token、timeout 同 /write
+    
+    /read_record、/write_record 可选参数：
+        start: 起始页，capacity: 每个槽的数据容量，默认使用启动参数
+
+    /write、/write_pages、/write_bytes、/write_record 可选参数：
+        verify: 写入后回读校验 默认使用启动参数 --verify，不一致时返回失败和第一处不一致的页
+
+    /write、/write_pages、/write_bytes 可选参数：
+        diff: 差分写入，只写入内容变化的页，message中返回实际写入的页数
+            read: 写入前先读取卡片当前内容
+            cache: 使用卡片停留期间读写过的内容，缓存中没有的页再读取；卡片离开后缓存自动清空
+
+    /write、/write_pages、/write_bytes、/write_record、/fields、/fields/migrate、/files、/document、/migrate、/restore 可选参数：
+        token: 特权令牌(--admin-token)，不受写保护策略限制；令牌错误时直接返回失败
+        写入范围包含受保护的页时一页都不写，返回失败和受保护的页；差分写入中内容没有变化的页不检查
+
+    /policy 当前的写保护策略
+
+    /mirror 读取NTAG213/215/216的UID/NFC计数器镜像配置
+        data中包含mode(off、uid、counter、uidcounter)、page(MIRROR_PAGE)、byte(MIRROR_BYTE)、
+        counter_enabled(NFC_CNT_EN)、counter_password(NFC_CNT_PWD_PROT)
+
+    /mirror/set 修改镜像配置，没有指定的项保持不变，配置页的其他位不变
+        mode: off、uid、counter、uidcounter，page: 镜像起始页，byte: 起始页中的字节位置 0~3
+        counter: 打开NFC计数器，counter_pwd: READ_CNT需要先密码认证
+        镜像需要在用户数据区内；CFGLCK已设置时返回失败
+
+    /mirror/url?url= 从第4页开始写入NDEF URL，并把镜像配置到URL中占位符的位置
+        url: URL模板，占位符 {uid}(UID 14个字符)、{counter}(计数器 6个字符，同时打开计数器)
+            或 {uid}x{counter}(两者 21个字符)，例如 https://example.com/t?id={uid}x{counter}
+        counter_pwd: READ_CNT需要先密码认证 默认 false
+        data中返回镜像配置、卡片上的URL(镜像位置以0填充)和写入的页数
+
+    /mirror/set、/mirror/url 可选参数：
+        token: 默认写保护策略禁止写入配置页，需要特权令牌
+        timeout 同 /write
+
+    /rolling 防克隆令牌的服务器记录，data中为UID(hex)到counter、key_id、pending、clones、updated_at的对象
+        每次刷卡读取保留页上的令牌，和服务器记录一致时写入下一个令牌；不一致时不修改卡片，clones加1，
+        /status 中记录"疑似克隆卡"错误。原卡片和复制的卡片任意一张刷过之后，另一张再刷时就会被发现
+
+    /rolling/reset?uid=&token= 删除一张卡片的记录，下次刷卡时重新登记，需要特权令牌
+
+    /tickets 票券库中的所有票，data中为UID(hex)到signature、counter、issued_at、used_at的对象
+
+    /tickets/issue?token= 把当前卡片登记为一张未使用的票，记录原厂签名和NFC计数器，需要特权令牌
+        卡片需要先用 /mirror/set?counter=true 打开NFC计数器；已经登记过的卡片重新发票
+        timeout 同 /write
+
+    /tickets/last 最近一次验票结果，data中包含uid、result、counter、checked_at
+        每次刷卡读取NFC计数器和原厂签名，result为:
+        accepted 有效，已标记为已使用；used 已经使用过；unknown 不在票券库中；
+        replayed NFC计数器没有增加，重放的通信记录；cloned 原厂签名和发票时不同，UID被复制到了其他芯片
+        error 读取计数器或签名失败(卡片中途离开、不是NTAG213/215/216、没有配置read_cnt/read_sig)，data中包含error
+        accepted时蜂鸣器按 --ticket-beep-ok 鸣叫，其他结果按 --ticket-beep-reject 鸣叫
+
+    /keys 密钥库中的密钥集和密钥名称，data中包含每个密钥的name、diversify、len，不包含密钥的值
+
+    /keys/derive?set=&name=&token= 读取当前卡片UID，返回分散后的密钥(hex)
+        token: 特权令牌(--admin-token)，没有配置特权令牌或令牌错误时返回失败
+
+    /read、/write、/read_pages、/write_pages、/read_bytes、/write_bytes、/read_record、/write_record 可选参数：
+        timeout: 超时时间 默认 5000 (毫秒)，超时后命令被取消；读卡器忙时直接返回失败
+
+    /dump 导出整张卡片(UID、锁定字节、CC、用户数据、配置页)
+        format: json(默认)、bin、nfc(Flipper Zero)、pm3(Proxmark3 JSON)、ndef
+        json: data中包含型号、UID和每一页的数据(hex)、用途、是否可读、是否已锁定
+        其他格式直接返回文件，目标格式无法表示的字段名在 X-Unrepresentable 头中
+        timeout: 超时时间 默认 30000 (毫秒)
+        目前只能导出NTAG/Ultralight卡片
+
+    POST /restore 把导出数据写回型号相同的卡片，请求体为导出的文件
+        format: json(默认，可以直接提交 /dump 的应答)、bin、nfc、pm3、ndef
+        card_type、block_size: bin和ndef文件的卡片类型(默认 UltraLight)和块大小(默认Mifare为16，其他为4)
+        config: 是否恢复配置页 默认 false
+        timeout: 超时时间 默认 30000 (毫秒)
+        跳过UID、锁定字节、CC、密码页、已锁定的页、受写保护的页和导出时无法读取的页
+        data中返回写入的页、跳过的页和原因、导入时无法表示的字段(unrepresentable)
+        目前只能恢复NTAG/Ultralight卡片
+
+    POST /convert?from=nfc&to=pm3 转换镜像格式，不需要读卡器，请求体为源文件，返回目标文件
+        card_type、block_size 同 /restore
     
     "#.into())
 }
 
-/// HTTP 打开串口
-async fn open(req: Request<()>) -> tide::Result {
+/// HTTP 打开串口，如果串口已经打开，先关闭再重新打开
+async fn open(req: Request<State>) -> tide::Result {
     resp!(|| -> Result<String>{
-        let OpenParams { port, card_type, delay, debug } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
-        ntag::open(&port, card_type.unwrap_or(CardType::UltraLight), delay.unwrap_or(300), debug.unwrap_or(false))?;
-        Ok(String::from("OK"))
+        let OpenParams { port, card_type, delay, fast_read, read_cnt, read_sig, debug } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let state = req.state();
+        if let Some(reader) = state.take_reader()?{
+            reader.close()?;
+        }
+        let options = ReaderOptions{
+            card_type: card_type.unwrap_or(CardType::UltraLight),
+            poll_interval: Duration::from_millis(delay.unwrap_or(300) as u64),
+            fast_read,
+            read_cnt,
+            read_sig,
+            debug: debug.unwrap_or(false),
+            ..Default::default()
+        };
+        match Reader::open(&port, options){
+            Ok(reader) => {
+                state.install_hooks(&reader)?;
+                *state.failure.lock().map_err(|err| anyhow!("failure锁定失败:{:?}", err))? = None;
+                *state.reader.lock().map_err(|err| anyhow!("reader锁定失败:{:?}", err))? = Some(Arc::new(reader));
+                Ok(String::from("OK"))
+            }
+            Err(err) => {
+                let mut status = ReaderStatus::new(Some(port));
+                status.state = ReaderState::Error;
+                status.record_error(&format!("串口打开失败 {:?}", err));
+                *state.failure.lock().map_err(|err| anyhow!("failure锁定失败:{:?}", err))? = Some(status);
+                Err(err)
+            }
+        }
     }())
 }
 
 /// HTTP 关闭串口
-async fn close(_req: Request<()>) -> tide::Result {
+async fn close(req: Request<State>) -> tide::Result {
     resp!(|| -> Result<String>{
-        ntag::close();
+        let state = req.state();
+        let reader = state.reader.lock().map_err(|err| anyhow!("reader锁定失败:{:?}", err))?.clone();
+        if let Some(reader) = reader{
+            reader.close()?;
+        }
         Ok(String::from("OK"))
     }())
 }
 
 /// HTTP 串口是否已打开
-async fn is_opened(_req: Request<()>) -> tide::Result {
-    resp!(|| -> Result<String>{
-        Ok(format!("{}", ntag::is_opened()))
-    }())
+async fn is_opened(req: Request<State>) -> tide::Result {
+    resp!(Ok::<String, anyhow::Error>(format!("{}", req.state().reader().is_ok())))
+}
+
+/// HTTP 读卡器状态
+async fn status(req: Request<State>) -> tide::Result {
+    let state = req.state();
+    let reader = state.reader.lock().ok().and_then(|reader| reader.clone());
+    let failure = state.failure.lock().ok().and_then(|failure| failure.clone());
+    let status = match (reader, failure){
+        (Some(reader), _) => reader.status(),
+        (None, Some(failure)) => failure,
+        (None, None) => ReaderStatus::new(None),
+    };
+    Ok(ServerResponse::success_with_data(&format!("{:?}", status.state), json!(status)))
 }
 
 /// HTTP 读取当前卡片UID
-async fn get_current_uid(_req: Request<()>) -> tide::Result {
+async fn get_current_uid(req: Request<State>) -> tide::Result {
     resp!(|| -> Result<String>{
-        match ntag::get_current_uid()?{
+        match req.state().reader()?.uid(){
             Some(uid) => {
-                Ok(hex::encode(&uid).into())
+                Ok(hex::encode(&uid))
             }
             None => {
                 Err(anyhow!("无卡片"))
@@ -159,32 +970,682 @@ async fn get_current_uid(_req: Request<()>) -> tide::Result {
     }())
 }
 
-/// HTTP 读取数据
-async fn read_data(req: Request<()>) -> tide::Result {
-    resp!(|| -> Result<String>{
-        let ReadParam { len } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
-        let (cmd, success, data) = ntag::read_data(len)?;
+/// 读取到的带长度头的数据: 解密结果和签名检查结果
+type FrameRead = (Opened, Signature);
+
+/// HTTP 读取数据，没有len时读取带长度头的数据
+async fn read_data(req: Request<State>) -> tide::Result {
+    let result = || -> Result<(Vec<u8>, Option<FrameRead>)>{
+        let ReadParam { len, order, cache, keyset, expected_uid, timeout } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let order = order.unwrap_or(req.state().config.order);
+        if len.is_none() && order != StorageOrder::Natural{
+            return Err(anyhow!("{:?}顺序需要指定len", order));
+        }
+        let cache = req.state().use_cache(cache);
+        let expected_uid = expected_uid_param(expected_uid)?;
+        let keys = req.state().payload_keys(keyset)?;
+        let reader = req.state().reader()?;
+        let data = reader.execute(timeout_param(timeout), move |card| {
+            let read = |card: &mut Card| match len{
+                Some(len) => Ok((order.read(card, len)?, None)),
+                None => {
+                    let frame = read_frame(card)?;
+                    //签名覆盖卡片上保存的数据，加密的数据先检查签名再解密
+                    let signature = check_signature(card, keys.as_deref(), &frame)?;
+                    let mut opened = open_frame(card, keys.as_deref(), frame)?;
+                    Ok((std::mem::take(&mut opened.frame.data), Some((opened, signature))))
+                }
+            };
+            match (cache, expected_uid){
+                //服务端已经知道当前卡片UID时，比较UID不需要和卡片通信
+                (true, Some(expected)) => card.cached(|card| {
+                    match card.cached_uid(){
+                        Some(uid) if uid == expected.as_slice() => read(card),
+                        _ => card.bound(Some(&expected), read),
+                    }
+                }),
+                (true, None) => card.cached(read),
+                (false, expected) => card.bound(expected.as_deref(), read),
+            }
+        })
+            .map_err(|err| anyhow!("读取失败 {:?}", err))?;
         // warn!("读取:{:?}", data);
-        if success{
-            Ok(base64::encode(data))
-        }else{
-            Err(anyhow!("读取失败 cmd={}", cmd))
+        Ok(data)
+    };
+    match result(){
+        Ok((data, Some((opened, signature)))) => {
+            let mut info = json!({
+                "len": data.len(),
+                "content_type": opened.frame.content_type,
+                "encrypted": opened.key_id.is_some(),
+                "signature": signature,
+            });
+            if let Some(key_id) = opened.key_id {
+                info["key_id"] = json!(key_id);
+            }
+            Ok(ServerResponse::success_with_data(&base64::encode(data), info))
         }
-    }())
+        Ok((data, None)) => Ok(ServerResponse::success(&base64::encode(data))),
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
 }
 
 /// HTTP 写入数据
-async fn write_data(req: Request<()>) -> tide::Result {
+async fn write_data(req: Request<State>) -> tide::Result {
     resp!(|| -> Result<String>{
-        let WriteParam { data } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
-        let w = base64::decode(data)?;
+        let WriteParam { data, order, frame, content_type, encrypt, sign, keyset, diff, verify, expected_uid, token, timeout } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        if content_type == Some(ContentType::Encrypted) {
+            return Err(anyhow!("加密数据请使用encrypt参数"));
+        }
+        let encrypt = encrypt.unwrap_or(req.state().config.encrypt);
+        let sign = sign.unwrap_or(req.state().config.sign);
+        let keys = if encrypt || sign {
+            Some(req.state().payload_keys(keyset)?.ok_or_else(|| anyhow!("服务器没有配置密钥 --payload-keys"))?)
+        } else {
+            None
+        };
+        let frame = keys.is_some() || content_type.is_some() || frame.unwrap_or(req.state().config.frame);
+        let order = match (frame, order){
+            (true, Some(StorageOrder::Legacy)) => return Err(anyhow!("带长度头的数据只支持Natural顺序")),
+            (true, _) => StorageOrder::Natural,
+            (false, order) => order.unwrap_or(req.state().config.order),
+        };
+        let verify = req.state().verify(verify);
+        let policy = req.state().write_policy(token)?;
+        let expected_uid = expected_uid_param(expected_uid)?;
+        let mut w = base64::decode(data)?;
         let len = w.len();
+        if frame && !encrypt{
+            w = encode_frame(content_type.unwrap_or_default(), &w)?;
+        }
         // warn!("写入:{:?}", w);
-        let (cmd, success, _data) = ntag::write_data(w)?;
-        if success{
-            Ok(format!("写入成功 数据长度:{}", len))
-        }else{
-            Err(anyhow!("写入失败 cmd={}", cmd))
+        let reader = req.state().reader()?;
+        let written = reader.execute(timeout_param(timeout), move |card| card.guarded(policy, |card| card.bound(expected_uid.as_deref(), |card| {
+            //加密和签名的密钥由卡片UID派生，需要在读到UID之后处理
+            let mut w = w.clone();
+            if let Some(keys) = &keys{
+                if encrypt{
+                    w = seal_frame(card, keys, content_type.unwrap_or_default(), &w)?;
+                }
+                if sign{
+                    w = sign_frame(card, keys, &w)?;
+                }
+            }
+            let written = match diff{
+                Some(base) => order.write_diff(card, &w, base)?,
+                None => {
+                    order.write(card, &w)?;
+                    w.len().div_ceil(PAGE_SIZE)
+                }
+            };
+            if verify{
+                order.verify(card, &w)?;
+            }
+            Ok(written)
+        }))).map_err(|err| anyhow!("写入失败 {:?}", err))?;
+        Ok(format!("写入成功 数据长度:{} 写入页数:{}", len, written))
+    }())
+}
+
+/// HTTP 按页读取
+async fn read_pages(req: Request<State>) -> tide::Result {
+    resp!(|| -> Result<String>{
+        let ReadPagesParam { start, count, cache, timeout } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let cache = req.state().use_cache(cache);
+        let reader = req.state().reader()?;
+        let data = reader.execute(timeout_param(timeout), move |card| {
+            if cache{ card.cached(|card| card.read_pages(start, count)) }else{ card.read_pages(start, count) }
+        })
+            .map_err(|err| anyhow!("读取失败 {:?}", err))?;
+        Ok(base64::encode(data))
+    }())
+}
+
+/// HTTP 按页写入
+async fn write_pages(req: Request<State>) -> tide::Result {
+    resp!(|| -> Result<String>{
+        let WritePagesParam { start, data, diff, verify, token, timeout } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let verify = req.state().verify(verify);
+        let policy = req.state().write_policy(token)?;
+        let w = base64::decode(data)?;
+        let len = w.len();
+        let reader = req.state().reader()?;
+        let written = reader.execute(timeout_param(timeout), move |card| card.guarded(policy, |card| card.bound(None, |card| {
+            let written = match diff{
+                Some(base) => card.write_pages_diff(start, &w, base)?,
+                None => {
+                    card.write_pages(start, &w)?;
+                    w.len() / PAGE_SIZE
+                }
+            };
+            if verify{
+                card.verify_bytes(start as usize * PAGE_SIZE, &w)?;
+            }
+            Ok(written)
+        }))).map_err(|err| anyhow!("写入失败 {:?}", err))?;
+        Ok(format!("写入成功 数据长度:{} 写入页数:{}", len, written))
+    }())
+}
+
+/// HTTP 按字节地址读取
+async fn read_bytes(req: Request<State>) -> tide::Result {
+    resp!(|| -> Result<String>{
+        let ReadBytesParam { offset, len, cache, timeout } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let cache = req.state().use_cache(cache);
+        let reader = req.state().reader()?;
+        let data = reader.execute(timeout_param(timeout), move |card| {
+            if cache{ card.cached(|card| card.read_bytes(offset, len)) }else{ card.read_bytes(offset, len) }
+        })
+            .map_err(|err| anyhow!("读取失败 {:?}", err))?;
+        Ok(base64::encode(data))
+    }())
+}
+
+/// HTTP 按字节地址写入
+async fn write_bytes(req: Request<State>) -> tide::Result {
+    resp!(|| -> Result<String>{
+        let WriteBytesParam { offset, data, diff, verify, token, timeout } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let verify = req.state().verify(verify);
+        let policy = req.state().write_policy(token)?;
+        let w = base64::decode(data)?;
+        let len = w.len();
+        let reader = req.state().reader()?;
+        let written = reader.execute(timeout_param(timeout), move |card| card.guarded(policy, |card| card.bound(None, |card| {
+            let written = match diff{
+                Some(base) => card.write_bytes_diff(offset, &w, base)?,
+                None => {
+                    card.write_bytes(offset, &w)?;
+                    (offset % PAGE_SIZE + w.len()).div_ceil(PAGE_SIZE)
+                }
+            };
+            if verify{
+                card.verify_bytes(offset, &w)?;
+            }
+            Ok(written)
+        }))).map_err(|err| anyhow!("写入失败 {:?}", err))?;
+        Ok(format!("写入成功 数据长度:{} 写入页数:{}", len, written))
+    }())
+}
+
+/// HTTP 迁移旧版倒序存储的卡片
+async fn migrate(req: Request<State>) -> tide::Result {
+    resp!(|| -> Result<String>{
+        let MigrateParam { header, len, token, timeout } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let header = hex::decode(header).map_err(|err| anyhow!("header不是hex字符串 {:?}", err))?;
+        let policy = req.state().write_policy(token)?;
+        let reader = req.state().reader()?;
+        let outcome = reader.execute(timeout_param(timeout), move |card| card.guarded(policy, |card| migrate_legacy(card, &header, len)))
+            .map_err(|err| anyhow!("迁移失败 {:?}", err))?;
+        Ok(format!("{:?}", outcome))
+    }())
+}
+
+/// 导出的文件，无法表示的字段名放在 X-Unrepresentable 头中，详细原因写入日志
+fn file_response(format: ImageFormat, converted: Converted<Vec<u8>>) -> Response{
+    let mut resp = Response::new(StatusCode::Ok);
+    resp.set_content_type(format.content_type());
+    resp.insert_header("Content-Disposition", format!("attachment; filename=\"dump.{}\"", format.extension()));
+    if !converted.unrepresentable.is_empty(){
+        log_unrepresentable(&converted.unrepresentable);
+        let fields: Vec<&str> = converted.unrepresentable.iter().map(|item| item.field.as_str()).collect();
+        resp.insert_header("X-Unrepresentable", fields.join(", "));
+    }
+    resp.set_body(converted.value);
+    resp
+}
+
+fn log_unrepresentable(list: &[Unrepresentable]){
+    for item in list{
+        warn!("无法表示的字段 {}: {}", item.field, item.reason);
+    }
+}
+
+/// HTTP 读取双槽存储中最后一条完整的记录
+async fn read_record_handler(req: Request<State>) -> tide::Result {
+    let result = || -> Result<serde_json::Value>{
+        let RecordParam { start, capacity, timeout, .. } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let layout = req.state().record_layout(start, capacity);
+        let reader = req.state().reader()?;
+        let record = reader.execute(timeout_param(timeout), move |card| read_record(card, &layout))
+            .map_err(|err| anyhow!("读取失败 {:?}", err))?
+            .ok_or_else(|| anyhow!("两个槽都没有完整的记录"))?;
+        Ok(json!({ "slot": record.slot, "seq": record.seq, "data": base64::encode(record.data) }))
+    };
+    match result(){
+        Ok(data) => Ok(ServerResponse::success_with_data("OK", data)),
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
+}
+
+/// HTTP 写入一条记录到较旧的槽
+async fn write_record_handler(req: Request<State>) -> tide::Result {
+    resp!(|| -> Result<String>{
+        let RecordParam { data, start, capacity, verify, token, timeout } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let w = base64::decode(data.ok_or_else(|| anyhow!("缺少data参数"))?)?;
+        let layout = req.state().record_layout(start, capacity);
+        let verify = req.state().verify(verify);
+        let policy = req.state().write_policy(token)?;
+        let reader = req.state().reader()?;
+        let record = reader.execute(timeout_param(timeout), move |card| card.guarded(policy, |card| write_record(card, &layout, &w, verify)))
+            .map_err(|err| anyhow!("写入失败 {:?}", err))?;
+        Ok(format!("写入成功 槽:{} 序号:{} 数据长度:{}", record.slot, record.seq, record.data.len()))
+    }())
+}
+
+/// HTTP 按字段布局读取
+async fn get_fields(req: Request<State>) -> tide::Result {
+    let result = || -> Result<(String, serde_json::Value)>{
+        let FieldsParam { names, cache, timeout, .. } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let names: Option<Vec<String>> = names.map(|names| names.split(',').map(|name| name.trim().to_string()).collect());
+        let cache = req.state().use_cache(cache);
+        let layouts = req.state().config.layouts.clone();
+        let reader = req.state().reader()?;
+        let fields = reader.execute(timeout_param(timeout), move |card| {
+            let layout = layouts.get(card.card_type())?;
+            let read = |card: &mut Card| read_fields(card, layout, names.as_deref());
+            if cache{ card.cached(read) }else{ read(card) }
+        }).map_err(|err| anyhow!("读取失败 {:?}", err))?;
+        let message = match fields.version{
+            Some(version) => format!("版本:{}", version),
+            None => String::from("OK"),
+        };
+        Ok((message, serde_json::Value::Object(fields.values)))
+    };
+    match result(){
+        Ok((message, data)) => Ok(ServerResponse::success_with_data(&message, data)),
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
+}
+
+/// HTTP 按字段布局写入，请求体为字段名到值的JSON对象
+async fn put_fields(mut req: Request<State>) -> tide::Result {
+    let body = req.body_bytes().await;
+    resp!(|| -> Result<String>{
+        let FieldsParam { verify, token, timeout, .. } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let values = match json_body(body)?{
+            serde_json::Value::Object(values) => values,
+            _ => return Err(anyhow!("请求体需要是JSON对象")),
+        };
+        let verify = req.state().verify(verify);
+        let policy = req.state().write_policy(token)?;
+        let layouts = req.state().config.layouts.clone();
+        let count = values.len();
+        let reader = req.state().reader()?;
+        let written = reader.execute(timeout_param(timeout), move |card| {
+            let layout = layouts.get(card.card_type())?;
+            card.guarded(policy, |card| write_fields(card, layout, &values, verify))
+        }).map_err(|err| anyhow!("写入失败 {:?}", err))?;
+        Ok(format!("写入成功 字段数:{} 写入页数:{}", count, written))
+    }())
+}
+
+/// HTTP 把旧版本布局的卡片升级到当前版本
+async fn migrate_fields(req: Request<State>) -> tide::Result {
+    resp!(|| -> Result<String>{
+        let FieldsParam { token, timeout, .. } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let policy = req.state().write_policy(token)?;
+        let layouts = req.state().config.layouts.clone();
+        let reader = req.state().reader()?;
+        let outcome = reader.execute(timeout_param(timeout), move |card| {
+            let layout = layouts.get(card.card_type())?;
+            card.guarded(policy, |card| migrate_layout(card, layout))
+        }).map_err(|err| anyhow!("升级失败 {:?}", err))?;
+        Ok(match outcome{
+            LayoutOutcome::Migrated{ from, to } => format!("Migrated {} -> {}", from, to),
+            outcome => format!("{:?}", outcome),
+        })
+    }())
+}
+
+/// HTTP 列出文件
+async fn files_list(req: Request<State>) -> tide::Result {
+    let result = || -> Result<serde_json::Value>{
+        let FileParam { timeout, .. } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let reader = req.state().reader()?;
+        let files = reader.execute(timeout_param(timeout), list_files).map_err(|err| anyhow!("读取失败 {:?}", err))?;
+        Ok(json!(files))
+    };
+    match result(){
+        Ok(files) => Ok(ServerResponse::success_with_data("OK", files)),
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
+}
+
+/// HTTP 读取文件
+async fn files_read(req: Request<State>) -> tide::Result {
+    resp!(|| -> Result<String>{
+        let FileParam { name, timeout, .. } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let name = name.ok_or_else(|| anyhow!("缺少name参数"))?;
+        let reader = req.state().reader()?;
+        let data = reader.execute(timeout_param(timeout), move |card| read_file(card, &name))
+            .map_err(|err| anyhow!("读取失败 {:?}", err))?;
+        Ok(base64::encode(data))
+    }())
+}
+
+/// HTTP 写入文件
+async fn files_write(req: Request<State>) -> tide::Result {
+    resp!(|| -> Result<String>{
+        let FileParam { name, data, verify, token, timeout, .. } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let name = name.ok_or_else(|| anyhow!("缺少name参数"))?;
+        let w = base64::decode(data.ok_or_else(|| anyhow!("缺少data参数"))?)?;
+        let verify = req.state().verify(verify);
+        let policy = req.state().write_policy(token)?;
+        let reader = req.state().reader()?;
+        let entry = reader.execute(timeout_param(timeout), move |card| card.guarded(policy, |card| write_file(card, &name, &w, verify)))
+            .map_err(|err| anyhow!("写入失败 {:?}", err))?;
+        Ok(format!("写入成功 文件:{} 数据长度:{} 起始页:{}", entry.name, entry.len, entry.start_page))
+    }())
+}
+
+/// HTTP 删除文件
+async fn files_delete(req: Request<State>) -> tide::Result {
+    resp!(|| -> Result<String>{
+        let FileParam { name, verify, token, timeout, .. } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let name = name.ok_or_else(|| anyhow!("缺少name参数"))?;
+        let verify = req.state().verify(verify);
+        let policy = req.state().write_policy(token)?;
+        let reader = req.state().reader()?;
+        let deleted = reader.execute(timeout_param(timeout), move |card| card.guarded(policy, |card| delete_file(card, &name, verify)))
+            .map_err(|err| anyhow!("删除失败 {:?}", err))?;
+        if !deleted{
+            return Err(anyhow!("文件不存在"));
         }
+        Ok(String::from("OK"))
+    }())
+}
+
+/// HTTP 格式化文件目录
+async fn files_format(req: Request<State>) -> tide::Result {
+    resp!(|| -> Result<String>{
+        let FileParam { max_files, token, timeout, .. } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let max_files = max_files.unwrap_or(DEFAULT_MAX_FILES);
+        let policy = req.state().write_policy(token)?;
+        let reader = req.state().reader()?;
+        reader.execute(timeout_param(timeout), move |card| card.guarded(policy, |card| format_files(card, max_files)))
+            .map_err(|err| anyhow!("格式化失败 {:?}", err))?;
+        Ok(String::from("OK"))
     }())
-}
\ No newline at end of file
+}
+
+/// 请求体中的JSON
+fn json_body(body: tide::Result<Vec<u8>>) -> Result<serde_json::Value>{
+    let body = body.map_err(|err| anyhow!("{:?}", err))?;
+    serde_json::from_slice(&body).map_err(|err| anyhow!("请求体格式错误 {:?}", err))
+}
+
+fn document_message(write: &DocumentWrite) -> String{
+    format!("写入成功 格式:{:?} 编码后字节数:{} 写入页数:{}", write.content_type, write.size, write.pages)
+}
+
+/// HTTP 读取卡片上的JSON文档，空白卡片返回null
+async fn get_document(req: Request<State>) -> tide::Result {
+    let result = || -> Result<serde_json::Value>{
+        let DocumentParam { cache, timeout, .. } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let cache = req.state().use_cache(cache);
+        let reader = req.state().reader()?;
+        reader.execute(timeout_param(timeout), move |card| {
+            if cache{ card.cached(read_document) }else{ read_document(card) }
+        }).map_err(|err| anyhow!("读取失败 {:?}", err))
+    };
+    match result(){
+        Ok(doc) => Ok(ServerResponse::success_with_data("OK", doc)),
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
+}
+
+/// HTTP 写入整个JSON文档
+async fn put_document(mut req: Request<State>) -> tide::Result {
+    let body = req.body_bytes().await;
+    resp!(|| -> Result<String>{
+        let DocumentParam { deflate, verify, token, timeout, .. } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let doc = json_body(body)?;
+        let policy = req.state().write_policy(token)?;
+        let reader = req.state().reader()?;
+        let write = reader.execute(timeout_param(timeout), move |card| {
+            card.guarded(policy, |card| write_document(card, &doc, deflate, verify.unwrap_or(true)))
+        }).map_err(|err| anyhow!("写入失败 {:?}", err))?;
+        Ok(document_message(&write))
+    }())
+}
+
+/// HTTP 按JSON Merge Patch修改文档，data中返回修改后的文档
+async fn patch_document_handler(mut req: Request<State>) -> tide::Result {
+    let body = req.body_bytes().await;
+    let result = || -> Result<(serde_json::Value, DocumentWrite)>{
+        let DocumentParam { deflate, verify, token, timeout, .. } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let patch = json_body(body)?;
+        let policy = req.state().write_policy(token)?;
+        let reader = req.state().reader()?;
+        reader.execute(timeout_param(timeout), move |card| {
+            card.guarded(policy, |card| patch_document(card, &patch, deflate, verify.unwrap_or(true)))
+        }).map_err(|err| anyhow!("写入失败 {:?}", err))
+    };
+    match result(){
+        Ok((doc, write)) => Ok(ServerResponse::success_with_data(&document_message(&write), doc)),
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
+}
+
+/// HTTP 导出整张卡片
+async fn dump(req: Request<State>) -> tide::Result {
+    let result = || -> Result<(ImageFormat, Dump)>{
+        let DumpParam { format, timeout } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let reader = req.state().reader()?;
+        let dump = reader.execute(timeout.map(Duration::from_millis).unwrap_or(DUMP_TIMEOUT), Dump::read)
+            .map_err(|err| anyhow!("导出失败 {:?}", err))?;
+        Ok((format.unwrap_or(ImageFormat::Json), dump))
+    };
+    match result(){
+        Ok((ImageFormat::Json, dump)) => Ok(ServerResponse::success_with_data(&format!("{:?} {}页", dump.model, dump.pages.len()), json!(dump))),
+        Ok((format, dump)) => match formats::export(&dump, format){
+            Ok(converted) => Ok(file_response(format, converted)),
+            Err(err) => Ok(ServerResponse::error(&format!("导出失败 {:?}", err))),
+        },
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
+}
+
+/// HTTP 把导出数据写回卡片
+async fn restore(mut req: Request<State>) -> tide::Result {
+    let body = req.body_bytes().await;
+    let result = || -> Result<serde_json::Value>{
+        let RestoreParam { format, card_type, block_size, config, token, timeout } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let body = body.map_err(|err| anyhow!("{:?}", err))?;
+        let import = ImportOptions{ card_type: card_type.unwrap_or(CardType::UltraLight), block_size };
+        let Converted { value: dump, unrepresentable } = formats::import(&body, format.unwrap_or(ImageFormat::Json), import)?;
+        let options = RestoreOptions{ config: config.unwrap_or(false) };
+        let policy = req.state().write_policy(token)?;
+        let reader = req.state().reader()?;
+        let report = reader.execute(timeout.map(Duration::from_millis).unwrap_or(DUMP_TIMEOUT), move |card| card.guarded(policy, |card| restore_dump(card, &dump, options)))
+            .map_err(|err| anyhow!("恢复失败 {:?}", err))?;
+        let mut data = json!(report);
+        data["unrepresentable"] = json!(unrepresentable);
+        Ok(data)
+    };
+    match result(){
+        Ok(report) => Ok(ServerResponse::success_with_data("恢复成功", report)),
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
+}
+
+/// HTTP 当前的写保护策略
+async fn policy(req: Request<State>) -> tide::Result {
+    Ok(ServerResponse::success_with_data("OK", json!(req.state().config.policy.as_ref())))
+}
+
+/// HTTP 读取NTAG镜像和NFC计数器配置
+async fn mirror_get(req: Request<State>) -> tide::Result {
+    let result = || -> Result<MirrorConfig>{
+        let MirrorParam { timeout, .. } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let reader = req.state().reader()?;
+        reader.execute(timeout_param(timeout), read_mirror).map_err(|err| anyhow!("读取失败 {:?}", err))
+    };
+    match result(){
+        Ok(config) => Ok(ServerResponse::success_with_data("OK", json!(config))),
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
+}
+
+/// HTTP 修改NTAG镜像和NFC计数器配置，没有指定的项保持不变
+async fn mirror_set(req: Request<State>) -> tide::Result {
+    let result = || -> Result<MirrorConfig>{
+        let MirrorParam { mode, page, byte, counter, counter_pwd, token, timeout, .. } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let policy = req.state().write_policy(token)?;
+        let reader = req.state().reader()?;
+        reader.execute(timeout_param(timeout), move |card| card.guarded(policy, |card| card.bound(None, |card| {
+            let current = read_mirror(card)?;
+            let config = MirrorConfig {
+                mode: mode.unwrap_or(current.mode),
+                page: page.unwrap_or(current.page),
+                byte: byte.unwrap_or(current.byte),
+                counter_enabled: counter.unwrap_or(current.counter_enabled),
+                counter_password: counter_pwd.unwrap_or(current.counter_password),
+            };
+            write_mirror(card, &config)?;
+            Ok(config)
+        }))).map_err(|err| anyhow!("写入失败 {:?}", err))
+    };
+    match result(){
+        Ok(config) => Ok(ServerResponse::success_with_data("写入成功", json!(config))),
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
+}
+
+/// HTTP 写入带镜像的NDEF URL
+async fn mirror_url(req: Request<State>) -> tide::Result {
+    let result = || -> Result<MirrorUrl>{
+        let MirrorParam { url, counter_pwd, token, timeout, .. } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let url = url.ok_or_else(|| anyhow!("缺少url参数"))?;
+        let policy = req.state().write_policy(token)?;
+        let reader = req.state().reader()?;
+        reader.execute(timeout_param(timeout), move |card| card.guarded(policy, |card| write_mirror_url(card, &url, counter_pwd.unwrap_or(false))))
+            .map_err(|err| anyhow!("写入失败 {:?}", err))
+    };
+    match result(){
+        Ok(written) => Ok(ServerResponse::success_with_data("写入成功", json!(written))),
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
+}
+
+/// 票券库
+fn ticket_db(state: &AppState) -> Result<Arc<Mutex<TicketDb>>> {
+    state.config.tickets.clone().ok_or_else(|| anyhow!("服务器没有配置票券库 --tickets"))
+}
+
+/// HTTP 票券库中的所有票
+async fn tickets_list(req: Request<State>) -> tide::Result {
+    let result = || -> Result<serde_json::Value>{
+        let tickets = ticket_db(req.state())?;
+        let tickets = tickets.lock().map_err(|err| anyhow!("票券库锁定失败:{:?}", err))?;
+        Ok(json!(tickets.tickets()))
+    };
+    match result(){
+        Ok(tickets) => Ok(ServerResponse::success_with_data("OK", tickets)),
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
+}
+
+/// HTTP 把当前卡片登记为一张未使用的票，需要特权令牌
+async fn tickets_issue(req: Request<State>) -> tide::Result {
+    let result = || -> Result<serde_json::Value>{
+        let TicketParam { token, timeout } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        req.state().require_admin(token)?;
+        let tickets = ticket_db(req.state())?;
+        let reader = req.state().reader()?;
+        let (uid, ticket) = reader.execute(timeout_param(timeout), move |card| TicketDb::issue(&tickets, card))
+            .map_err(|err| anyhow!("发票失败 {:?}", err))?;
+        let mut data = json!(ticket);
+        data["uid"] = json!(uid);
+        Ok(data)
+    };
+    match result(){
+        Ok(ticket) => Ok(ServerResponse::success_with_data("发票成功", ticket)),
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
+}
+
+/// HTTP 最近一次验票结果
+async fn tickets_last(req: Request<State>) -> tide::Result {
+    let result = || -> Result<Option<TicketCheck>>{
+        ticket_db(req.state())?;
+        let last = req.state().config.last_ticket.lock().map_err(|err| anyhow!("验票结果锁定失败:{:?}", err))?;
+        Ok(last.clone())
+    };
+    match result(){
+        Ok(Some(check)) => Ok(ServerResponse::success_with_data(&format!("{:?}", check.result), json!(check))),
+        Ok(None) => Ok(ServerResponse::error("还没有验票")),
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
+}
+
+/// HTTP 防克隆令牌的服务器记录
+async fn rolling_list(req: Request<State>) -> tide::Result {
+    let result = || -> Result<serde_json::Value>{
+        let ledger = req.state().config.rolling.lock().map_err(|err| anyhow!("令牌记录锁定失败:{:?}", err))?;
+        Ok(json!(ledger.entries()))
+    };
+    match result(){
+        Ok(entries) => Ok(ServerResponse::success_with_data("OK", entries)),
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
+}
+
+/// HTTP 删除一张卡片的防克隆令牌记录，需要特权令牌
+async fn rolling_reset(req: Request<State>) -> tide::Result {
+    resp!(|| -> Result<String>{
+        let RollingParam { uid, token } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        req.state().require_admin(token)?;
+        let uid = hex::decode(uid).map_err(|err| anyhow!("uid格式错误 {:?}", err))?;
+        let mut ledger = req.state().config.rolling.lock().map_err(|err| anyhow!("令牌记录锁定失败:{:?}", err))?;
+        if ledger.reset(&uid)? {
+            Ok("已删除，下次刷卡时重新登记".to_string())
+        } else {
+            Err(anyhow!("没有这张卡片的记录"))
+        }
+    }())
+}
+
+/// HTTP 密钥库中的密钥集和密钥名称，不包含密钥的值
+async fn keys_list(req: Request<State>) -> tide::Result {
+    let result = || -> Result<serde_json::Value>{
+        Ok(json!(req.state().keystore()?.info()))
+    };
+    match result(){
+        Ok(info) => Ok(ServerResponse::success_with_data("OK", info)),
+        Err(err) => Ok(ServerResponse::error(&format!("{:?}", err))),
+    }
+}
+
+/// HTTP 当前卡片的密钥，需要特权令牌
+async fn keys_derive(req: Request<State>) -> tide::Result {
+    resp!(|| -> Result<String>{
+        let KeyParam { set, name, token, timeout } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        req.state().require_admin(token)?;
+        let keystore = req.state().keystore()?.clone();
+        keystore.set(&set)?;
+        let reader = req.state().reader()?;
+        let key = reader.execute(timeout_param(timeout), move |card| card.bound(None, |card| keystore.set(&set)?.card_key(card, &name)))
+            .map_err(|err| anyhow!("读取失败 {:?}", err))?;
+        Ok(hex::encode(key))
+    }())
+}
+
+/// HTTP 转换镜像格式，不需要读卡器
+async fn convert(mut req: Request<State>) -> tide::Result {
+    let body = req.body_bytes().await;
+    let result = || -> Result<(ImageFormat, Converted<Vec<u8>>)>{
+        let ConvertParam { from, to, card_type, block_size } = req.query().map_err(|err| anyhow!("{:?}", err) )?;
+        let body = body.map_err(|err| anyhow!("{:?}", err))?;
+        let import = ImportOptions{ card_type: card_type.unwrap_or(CardType::UltraLight), block_size };
+        let Converted { value: dump, mut unrepresentable } = formats::import(&body, from, import)?;
+        let mut converted = formats::export(&dump, to)?;
+        unrepresentable.append(&mut converted.unrepresentable);
+        converted.unrepresentable = unrepresentable;
+        Ok((to, converted))
+    };
+    match result(){
+        Ok((format, converted)) => Ok(file_response(format, converted)),
+        Err(err) => Ok(ServerResponse::error(&format!("转换失败 {:?}", err))),
+    }
+}
@@ -0,0 +1,337 @@
+//! NTAG21x UID/NFC计数器镜像
+//!
+//! NTAG213/215/216可以把UID和NFC计数器以ASCII hex的形式镜像到用户数据区，手机读取NDEF URL时
+//! 每张卡片、每次读取得到的URL都不同。镜像由配置页控制:
+//! CFG0: MIRROR(1) | RFUI(1) | MIRROR_PAGE(1) | AUTH0(1)，
+//! MIRROR: MIRROR_CONF(bit7-6) | MIRROR_BYTE(bit5-4) | STRG_MOD_EN(bit2)；
+//! CFG1: ACCESS(1) | RFUI(3)，ACCESS: PROT(bit7) | CFGLCK(bit6) | NFC_CNT_EN(bit4) | NFC_CNT_PWD_PROT(bit3) | AUTHLIM(bit2-0)。
+//! 只修改镜像和计数器相关的位，其他位保持不变。
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use crate::formats::ndef_tlv;
+use crate::ntag::{Card, CardType, TagModel, PAGE_SIZE, USER_START_PAGE};
+
+const MIRROR_CONF_SHIFT: u8 = 6;
+const MIRROR_BYTE_SHIFT: u8 = 4;
+const MIRROR_BYTE_MASK: u8 = 0x30;
+const ACCESS_CFGLCK: u8 = 0x40;
+const ACCESS_NFC_CNT_EN: u8 = 0x10;
+const ACCESS_NFC_CNT_PWD_PROT: u8 = 0x08;
+
+/// URL模板中的占位符
+const UID_PLACEHOLDER: &str = "{uid}";
+const COUNTER_PLACEHOLDER: &str = "{counter}";
+const UID_COUNTER_PLACEHOLDER: &str = "{uid}x{counter}";
+
+/// NDEF URI记录的前缀缩写，按长度从长到短匹配
+const URI_PREFIXES: [(u8, &str); 4] = [(0x02, "https://www."), (0x01, "http://www."), (0x04, "https://"), (0x03, "http://")];
+
+/// 镜像内容 MIRROR_CONF
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MirrorMode{
+    /// 不镜像
+    #[default]
+    Off,
+    /// UID，14个字符
+    Uid,
+    /// NFC计数器，6个字符
+    Counter,
+    /// UID、字符'x'和NFC计数器，21个字符
+    UidCounter,
+}
+
+impl MirrorMode{
+    fn from_bits(bits: u8) -> MirrorMode{
+        match bits & 0x03{
+            1 => MirrorMode::Uid,
+            2 => MirrorMode::Counter,
+            3 => MirrorMode::UidCounter,
+            _ => MirrorMode::Off,
+        }
+    }
+
+    fn to_bits(self) -> u8{
+        match self{
+            MirrorMode::Off => 0,
+            MirrorMode::Uid => 1,
+            MirrorMode::Counter => 2,
+            MirrorMode::UidCounter => 3,
+        }
+    }
+
+    /// 镜像的ASCII字符数
+    pub fn ascii_len(self) -> usize{
+        match self{
+            MirrorMode::Off => 0,
+            MirrorMode::Uid => 14,
+            MirrorMode::Counter => 6,
+            MirrorMode::UidCounter => 21,
+        }
+    }
+
+    /// 是否包含NFC计数器
+    pub fn has_counter(self) -> bool{
+        matches!(self, MirrorMode::Counter | MirrorMode::UidCounter)
+    }
+}
+
+/// 镜像和NFC计数器配置
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub struct MirrorConfig{
+    pub mode: MirrorMode,
+    /// 镜像起始页 MIRROR_PAGE，0表示不镜像
+    pub page: u8,
+    /// 镜像在起始页中的字节位置 MIRROR_BYTE 0~3
+    pub byte: u8,
+    /// NFC计数器 NFC_CNT_EN
+    pub counter_enabled: bool,
+    /// READ_CNT需要先密码认证 NFC_CNT_PWD_PROT
+    pub counter_password: bool,
+}
+
+impl MirrorConfig{
+    fn parse(cfg0: &[u8], access: u8) -> MirrorConfig{
+        MirrorConfig{
+            mode: MirrorMode::from_bits(cfg0[0] >> MIRROR_CONF_SHIFT),
+            page: cfg0[2],
+            byte: (cfg0[0] & MIRROR_BYTE_MASK) >> MIRROR_BYTE_SHIFT,
+            counter_enabled: access & ACCESS_NFC_CNT_EN != 0,
+            counter_password: access & ACCESS_NFC_CNT_PWD_PROT != 0,
+        }
+    }
+
+    /// 检查镜像位置在用户数据区内
+    fn check(&self, model: TagModel) -> Result<()>{
+        if self.byte > 3{
+            return Err(anyhow!("MIRROR_BYTE需要在0~3之间"));
+        }
+        if self.mode == MirrorMode::Off{
+            return Ok(());
+        }
+        let (start, end) = model.user_pages().ok_or_else(|| anyhow!("无法识别卡片型号"))?;
+        let offset = self.page as usize * PAGE_SIZE + self.byte as usize;
+        if (self.page as u16) < start || offset + self.mode.ascii_len() > end as usize * PAGE_SIZE{
+            return Err(anyhow!("镜像位置超出用户数据区 第{}页第{}字节起{}个字符", self.page, self.byte, self.mode.ascii_len()));
+        }
+        Ok(())
+    }
+}
+
+/// 配置页CFG0的页号，只支持NTAG213/215/216
+fn config_page(card: &mut Card) -> Result<(TagModel, u8)>{
+    if card.card_type() != CardType::UltraLight{
+        return Err(anyhow!("镜像只支持NTAG213/215/216卡片"));
+    }
+    let model = card.detect_model()?;
+    match model.config_page(){
+        Some(page) => Ok((model, page as u8)),
+        None => Err(anyhow!("镜像只支持NTAG213/215/216卡片，当前型号:{:?}", model)),
+    }
+}
+
+/// 读取镜像和NFC计数器配置
+pub fn read_mirror(card: &mut Card) -> Result<MirrorConfig>{
+    card.bound(None, |card| {
+        let (_, page) = config_page(card)?;
+        let cfg = card.read_pages(page, 2)?;
+        Ok(MirrorConfig::parse(&cfg[..PAGE_SIZE], cfg[PAGE_SIZE]))
+    })
+}
+
+/// 写入镜像和NFC计数器配置，配置页的其他位保持不变；CFGLCK已设置时返回失败
+pub fn write_mirror(card: &mut Card, config: &MirrorConfig) -> Result<()>{
+    card.bound(None, |card| {
+        let (model, page) = config_page(card)?;
+        config.check(model)?;
+        let cfg = card.read_pages(page, 2)?;
+        let mut cfg0 = [0u8; PAGE_SIZE];
+        let mut cfg1 = [0u8; PAGE_SIZE];
+        cfg0.copy_from_slice(&cfg[..PAGE_SIZE]);
+        cfg1.copy_from_slice(&cfg[PAGE_SIZE..]);
+        if cfg1[0] & ACCESS_CFGLCK != 0{
+            return Err(anyhow!("配置页已被CFGLCK永久锁定"));
+        }
+
+        cfg0[0] = (cfg0[0] & !(0x03 << MIRROR_CONF_SHIFT) & !MIRROR_BYTE_MASK)
+            | config.mode.to_bits() << MIRROR_CONF_SHIFT
+            | config.byte << MIRROR_BYTE_SHIFT;
+        cfg0[2] = if config.mode == MirrorMode::Off{ 0 }else{ config.page };
+        let mut access = cfg1[0] & !(ACCESS_NFC_CNT_EN | ACCESS_NFC_CNT_PWD_PROT);
+        if config.counter_enabled{
+            access |= ACCESS_NFC_CNT_EN;
+        }
+        if config.counter_password{
+            access |= ACCESS_NFC_CNT_PWD_PROT;
+        }
+        cfg1[0] = access;
+
+        if cfg0 != cfg[..PAGE_SIZE]{
+            card.write_page(page, &cfg0)?;
+        }
+        if cfg1 != cfg[PAGE_SIZE..]{
+            card.write_page(page + 1, &cfg1)?;
+        }
+        card.verify_bytes(page as usize * PAGE_SIZE, &[cfg0, cfg1].concat())
+    })
+}
+
+/// URL模板写入的结果
+#[derive(Debug, Clone, Serialize)]
+pub struct MirrorUrl{
+    pub config: MirrorConfig,
+    /// 卡片上的URL，镜像位置以0填充
+    pub url: String,
+    /// 写入的页数
+    pub pages: usize,
+}
+
+/// 把URL模板编码为NDEF TLV，返回TLV和镜像的字节地址
+fn encode_url(template: &str) -> Result<(MirrorMode, String, Vec<u8>, usize)>{
+    let uids = template.matches(UID_PLACEHOLDER).count();
+    let counters = template.matches(COUNTER_PLACEHOLDER).count();
+    let (mode, placeholder) = match (uids, counters){
+        (1, 0) => (MirrorMode::Uid, UID_PLACEHOLDER),
+        (0, 1) => (MirrorMode::Counter, COUNTER_PLACEHOLDER),
+        (1, 1) if template.contains(UID_COUNTER_PLACEHOLDER) => (MirrorMode::UidCounter, UID_COUNTER_PLACEHOLDER),
+        (0, 0) => return Err(anyhow!("URL模板中需要 {}、{} 或 {}", UID_PLACEHOLDER, COUNTER_PLACEHOLDER, UID_COUNTER_PLACEHOLDER)),
+        _ => return Err(anyhow!("镜像只能放在一个位置，UID和计数器同时镜像时使用 {}", UID_COUNTER_PLACEHOLDER)),
+    };
+    let url = template.replacen(placeholder, &"0".repeat(mode.ascii_len()), 1);
+    let position = template.find(placeholder).expect("已检查占位符");
+
+    let (code, prefix) = URI_PREFIXES.iter().find(|(_, prefix)| url.starts_with(prefix)).copied().unwrap_or((0x00, ""));
+    let mut payload = vec![code];
+    payload.extend_from_slice(&url.as_bytes()[prefix.len()..]);
+    //NDEF记录: 短记录 MB|ME|SR|TNF=1，长记录 MB|ME|TNF=1
+    let mut record = vec![];
+    if payload.len() < 0x100{
+        record.extend_from_slice(&[0xD1, 0x01, payload.len() as u8]);
+    }else{
+        record.extend_from_slice(&[0xC1, 0x01]);
+        record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    }
+    record.push(b'U');
+    if position < prefix.len(){
+        return Err(anyhow!("镜像不能放在URL前缀中"));
+    }
+    //记录头 + 前缀缩写 + 占位符在URL剩余部分中的位置
+    let mirror_in_record = record.len() + 1 + position - prefix.len();
+    record.extend_from_slice(&payload);
+
+    let tlv = ndef_tlv(&record)?;
+    let tlv_header = if record.len() < 0xFF{ 2 }else{ 4 };
+    let offset = USER_START_PAGE as usize * PAGE_SIZE + tlv_header + mirror_in_record;
+    Ok((mode, url, tlv, offset))
+}
+
+/// 从第4页开始写入NDEF URL，URL模板中的占位符位置自动配置为镜像:
+/// {uid} 镜像UID，{counter} 镜像NFC计数器(同时打开计数器)，{uid}x{counter} 同时镜像两者
+pub fn write_mirror_url(card: &mut Card, template: &str, counter_password: bool) -> Result<MirrorUrl>{
+    let (mode, url, tlv, offset) = encode_url(template)?;
+    card.bound(None, |card| {
+        let (model, _) = config_page(card)?;
+        let (_, end) = model.user_pages().ok_or_else(|| anyhow!("无法识别卡片型号"))?;
+        let capacity = (end - USER_START_PAGE as u16) as usize * PAGE_SIZE;
+        if tlv.len() > capacity{
+            return Err(anyhow!("URL过长 编码后{}字节 卡片最多{}字节", tlv.len(), capacity));
+        }
+        let config = MirrorConfig{
+            mode,
+            page: (offset / PAGE_SIZE) as u8,
+            byte: (offset % PAGE_SIZE) as u8,
+            counter_enabled: mode.has_counter(),
+            counter_password,
+        };
+        //先关闭原有的镜像，否则回读校验时读到的是镜像内容
+        let current = read_mirror(card)?;
+        if current.mode != MirrorMode::Off{
+            write_mirror(card, &MirrorConfig{ mode: MirrorMode::Off, ..current })?;
+        }
+        card.write_pages(USER_START_PAGE, &tlv)?;
+        card.verify_bytes(USER_START_PAGE as usize * PAGE_SIZE, &tlv)?;
+        write_mirror(card, &config)?;
+        Ok(MirrorUrl{ config, url: url.clone(), pages: tlv.len() / PAGE_SIZE })
+    })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const BASE: usize = USER_START_PAGE as usize * PAGE_SIZE;
+
+    /// 镜像位置的字符和其后的URL
+    fn mirrored(template: &str) -> (MirrorMode, String, usize, String){
+        let (mode, url, tlv, offset) = encode_url(template).unwrap();
+        let at = offset - BASE;
+        let ascii = String::from_utf8(tlv[at..at + mode.ascii_len()].to_vec()).unwrap();
+        let rest: String = tlv[at + mode.ascii_len()..].iter().take_while(|b| **b != 0xFE).map(|b| *b as char).collect();
+        (mode, url, offset, format!("{}|{}", ascii, rest))
+    }
+
+    #[test]
+    fn uid_offset(){
+        let (mode, url, offset, text) = mirrored("https://example.com/t?id={uid}");
+        assert_eq!(mode, MirrorMode::Uid);
+        assert_eq!(url, "https://example.com/t?id=00000000000000");
+        //TLV头2 + 记录头4 + 前缀缩写1 + "example.com/t?id=" 17
+        assert_eq!(offset, BASE + 2 + 4 + 1 + 17);
+        assert_eq!(text, "00000000000000|");
+    }
+
+    #[test]
+    fn counter_offset(){
+        let (mode, url, offset, text) = mirrored("http://www.a.cn/{counter}/x");
+        assert_eq!(mode, MirrorMode::Counter);
+        assert!(mode.has_counter());
+        assert_eq!(url, "http://www.a.cn/000000/x");
+        assert_eq!(offset, BASE + 2 + 4 + 1 + 5);
+        assert_eq!(text, "000000|/x");
+    }
+
+    #[test]
+    fn uid_counter_offset(){
+        let (mode, url, offset, text) = mirrored("tel:{uid}x{counter}");
+        assert_eq!(mode, MirrorMode::UidCounter);
+        assert_eq!(url, format!("tel:{}", "0".repeat(21)));
+        //没有前缀缩写时URL原样保存
+        assert_eq!(offset, BASE + 2 + 4 + 1 + 4);
+        assert_eq!(text, format!("{}|", "0".repeat(21)));
+    }
+
+    #[test]
+    fn long_url_offset(){
+        let template = format!("https://example.com/{}?c={{counter}}", "p".repeat(300));
+        let (mode, _, offset, text) = mirrored(&template);
+        assert_eq!(mode, MirrorMode::Counter);
+        //TLV头4 + 长记录头7 + 前缀缩写1
+        assert_eq!(offset, BASE + 4 + 7 + 1 + "example.com/".len() + 300 + "?c=".len());
+        assert_eq!(text, "000000|");
+    }
+
+    #[test]
+    fn bad_templates(){
+        for template in [
+            "https://example.com/",
+            "https://example.com/{uid}/{uid}",
+            "https://example.com/{uid}/{counter}",
+            "https://example.com/{counter}x{uid}",
+        ]{
+            assert!(encode_url(template).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn config_bits(){
+        let config = MirrorConfig::parse(&[0xE4, 0, 0x0A, 0xFF], 0x18);
+        assert_eq!(config, MirrorConfig{ mode: MirrorMode::UidCounter, page: 0x0A, byte: 2, counter_enabled: true, counter_password: true });
+        let config = MirrorConfig{ mode: MirrorMode::Uid, page: 4, byte: 0, counter_enabled: false, counter_password: false };
+        assert!(config.check(TagModel::Ntag213).is_ok());
+        assert!(MirrorConfig{ page: 3, ..config }.check(TagModel::Ntag213).is_err());
+        assert!(MirrorConfig{ page: 39, ..config }.check(TagModel::Ntag213).is_err());
+        assert!(MirrorConfig{ byte: 4, ..config }.check(TagModel::Ntag213).is_err());
+        assert!(config.check(TagModel::Unknown).is_err());
+    }
+}
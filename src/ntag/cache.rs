@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+use super::PAGE_SIZE;
+
+/// 当前卡片已知的页内容
+///
+/// 每次读写成功后记录页内容，读取UID时发现卡片变化(更换、离开或通信失败)就全部清空，
+/// 所以缓存中的内容只在卡片一直停留在读卡区域时有效。
+#[derive(Debug, Default)]
+pub(crate) struct PageCache{
+    uid: Option<Vec<u8>>,
+    pages: HashMap<u8, [u8; PAGE_SIZE]>,
+}
+
+impl PageCache{
+    /// 记录最新读取到的UID，和之前不同时清空
+    pub fn set_uid(&mut self, uid: Option<&[u8]>){
+        if self.uid.as_deref() != uid{
+            self.pages.clear();
+            self.uid = uid.map(|uid| uid.to_vec());
+        }
+    }
+
+    /// 缓存对应的UID
+    pub fn uid(&self) -> Option<&[u8]>{
+        self.uid.as_deref()
+    }
+
+    pub fn get(&self, page: u8) -> Option<[u8; PAGE_SIZE]>{
+        self.pages.get(&page).copied()
+    }
+
+    pub fn put(&mut self, page: u8, data: [u8; PAGE_SIZE]){
+        if self.uid.is_some(){
+            self.pages.insert(page, data);
+        }
+    }
+
+    pub fn remove(&mut self, page: u8){
+        self.pages.remove(&page);
+    }
+
+    pub fn clear(&mut self){
+        self.uid = None;
+        self.pages.clear();
+    }
+}
@@ -1,132 +1,308 @@
-
+#[allow(clippy::module_inception)]
 mod ntag;
+mod cache;
+mod model;
+mod status;
 
 use log::error;
-pub use ntag::{CMD_READ_DATA, CMD_SET_BUZZER, CMD_CLOSE_UID_REPORT, CMD_OPEN_UID_REPORT, CMD_WRITE_DATA, CardType};
-use std::thread;
-use std::time::Duration;
-use std::{sync::{Mutex, mpsc::{Receiver, Sender}}};
-use once_cell::sync::Lazy;
+pub use ntag::{Card, CardType, DiffBase, PackageInfo, PAGE_SIZE, USER_START_PAGE, ST_CODE_SUCCESS};
+pub use model::{PageKind, TagModel};
+pub use status::{ReaderState, ReaderStatus};
+pub(crate) use status::now_ms;
+use serialport::SerialPort;
+use status::StatusCell;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, sync_channel, RecvTimeoutError, SyncSender, TrySendError};
+use ntag::Job;
 use anyhow::{anyhow, Result};
 
-/// 给串口线程发送命令
-static SENDER: Lazy<Mutex<Option<Sender<(u8, Vec<u8>)>>>> = Lazy::new(|| { Mutex::new( None ) });
-/// 从串口线程接收命令
-static RECEIVER: Lazy<Mutex<Option<Receiver<(u8, bool, Vec<u8>)>>>> = Lazy::new(|| { Mutex::new( None ) });
+/// 命令默认超时时间
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(5000);
+/// 命令超时后等待串口线程取消的额外时间
+const CANCEL_GRACE: Duration = Duration::from_millis(2000);
+/// 命令队列长度，队列满时新的请求直接返回忙
+pub const QUEUE_CAPACITY: usize = 4;
 
-/// 检查串口是否已打开
-pub fn is_opened() -> bool{
-    match ntag::OPENED.lock(){
-        Ok(opend) => {
-            *opend
-        }
-        Err(err) => {
-            error!("{:?}", err);
-            false
+/// 读卡器参数
+#[derive(Debug, Clone)]
+pub struct ReaderOptions{
+    /// 卡片类型
+    pub card_type: CardType,
+    /// UID读取频率
+    pub poll_interval: Duration,
+    /// 命令默认超时时间
+    pub timeout: Duration,
+    /// 读卡器FAST_READ命令的功能码，读卡器固件支持时多页读取一次完成
+    pub fast_read: Option<u8>,
+    /// 读卡器READ_CNT(读取NTAG NFC计数器)命令的功能码
+    pub read_cnt: Option<u8>,
+    /// 读卡器READ_SIG(读取NTAG原厂签名)命令的功能码
+    pub read_sig: Option<u8>,
+    /// 调试输出
+    pub debug: bool,
+}
+
+impl Default for ReaderOptions{
+    fn default() -> Self{
+        ReaderOptions{
+            card_type: CardType::UltraLight,
+            poll_interval: Duration::from_millis(300),
+            timeout: DEFAULT_TIMEOUT,
+            fast_read: None,
+            read_cnt: None,
+            read_sig: None,
+            debug: false,
         }
     }
 }
 
-/// 获取当前读取到的UID，读取失败时为空
-pub use ntag::get_current_uid;
+/// 刷卡回调，新卡片进入读卡区域时在串口线程中调用
+pub trait TapHook: Send{
+    fn on_tap(&mut self, card: &mut Card, uid: &[u8]) -> Result<()>;
+}
 
-/// 设置是否循环读取UID
-pub use ntag::set_loop;
+impl<F> TapHook for F where F: FnMut(&mut Card, &[u8]) -> Result<()> + Send{
+    fn on_tap(&mut self, card: &mut Card, uid: &[u8]) -> Result<()>{
+        self(card, uid)
+    }
+}
 
-/// 设置是否循环读取UID
-pub fn set_loop_sleep(lp: bool, time_ms: u64) -> Result<()>{
-    ntag::set_loop(lp)?;
-    thread::sleep(Duration::from_millis(time_ms as u64));
-    Ok(())
+/// 读卡器和串口线程共享的数据
+pub(crate) struct Shared{
+    status: StatusCell,
+    /// 当前读取到的UID
+    uid: Mutex<Option<Vec<u8>>>,
+    /// 刷卡回调
+    hooks: Mutex<Vec<Box<dyn TapHook>>>,
 }
 
-/// 打开串口
-pub fn open(dev: &str, card_type:CardType, delay: u32, debug: bool) -> Result<()>{
-    let (tx, rx) = ntag::open_port(dev.to_string(), card_type, delay as u16, debug)?;
-    if let Ok(mut sender) = SENDER.lock(){
-        *sender = Some(tx);
-    }else{
-        return Err(anyhow!("sender锁定失败!"));
+impl Shared{
+    fn set_uid(&self, uid: Option<Vec<u8>>){
+        match self.uid.lock(){
+            Ok(mut current) => *current = uid,
+            Err(err) => error!("UID lock失败:{:?}", err)
+        }
     }
-    if let Ok(mut recv) = RECEIVER.lock(){
-        *recv = Some(rx);
-    }else{
-        return Err(anyhow!("receiver锁定失败!"));
+
+    /// 依次调用刷卡回调，回调出错时记录错误，不影响其他回调
+    fn on_tap(&self, card: &mut Card, uid: &[u8]){
+        let mut hooks = match self.hooks.lock(){
+            Ok(hooks) => hooks,
+            Err(err) => {
+                error!("hooks lock失败:{:?}", err);
+                return;
+            }
+        };
+        for hook in hooks.iter_mut(){
+            if let Err(err) = hook.on_tap(card, uid){
+                error!("刷卡回调失败 uid={} {:?}", hex::encode(uid), err);
+                self.status.record_error(&format!("刷卡回调失败 uid={} {:?}", hex::encode(uid), err));
+            }
+        }
     }
-    Ok(())
 }
 
-/// 关闭串口
-pub fn close() -> bool{
-    ntag::close_port()
+/// XELC-MINI335TE 读卡器
+///
+/// 每个读卡器有一个串口线程，定时读取UID，并按顺序执行其他命令。
+/// 读卡器被释放时关闭串口。
+pub struct Reader{
+    shared: Arc<Shared>,
+    /// 给串口线程发送任务，关闭后为空
+    sender: Mutex<Option<SyncSender<Job>>>,
+    /// 串口线程句柄，关闭操作期间一直锁定
+    worker: Mutex<Option<JoinHandle<()>>>,
+    /// 任务编号
+    next_id: AtomicU64,
+    timeout: Duration,
 }
 
-/// 读取数据
-pub fn read_data(len: u8) -> Result<NTAGResult>{
-    Ok(send_cmd(CMD_READ_DATA, vec![len as u8])?)
-}
+impl Reader{
+    /// 打开串口设备
+    pub fn open(dev: &str, options: ReaderOptions) -> Result<Reader>{
+        Reader::from_port(ntag::open_device(dev)?, options)
+    }
 
-/// 设置蜂鸣器
-pub fn set_buzzer(data: u8) -> Result<NTAGResult>{
-    Ok(send_cmd(CMD_SET_BUZZER, vec![data])?)
-}
+    /// 使用已经打开的串口
+    pub fn from_port(port: Box<dyn SerialPort>, options: ReaderOptions) -> Result<Reader>{
+        let shared = Arc::new(Shared{
+            status: StatusCell::new(port.name()),
+            uid: Mutex::new(None),
+            hooks: Mutex::new(vec![]),
+        });
+        shared.status.set_state(ReaderState::Opening);
+        let (tx, rx) = sync_channel::<Job>(QUEUE_CAPACITY);
+        let worker_shared = shared.clone();
+        let timeout = options.timeout;
+        let handle = thread::Builder::new()
+            .name("xelc-reader".to_string())
+            .spawn(move || ntag::run(port, options, rx, worker_shared))
+            .map_err(|err| {
+                shared.status.record_error(&format!("串口线程启动失败 {:?}", err));
+                shared.status.set_state(ReaderState::Error);
+                anyhow!("串口线程启动失败 {:?}", err)
+            })?;
+        shared.status.set_state(ReaderState::Open);
+        Ok(Reader{
+            shared,
+            sender: Mutex::new(Some(tx)),
+            worker: Mutex::new(Some(handle)),
+            next_id: AtomicU64::new(1),
+            timeout,
+        })
+    }
 
-/// 关闭UID主动上报
-fn close_uid_report() -> Result<NTAGResult>{
-    Ok(send_cmd_no_resp(CMD_CLOSE_UID_REPORT, vec![])?)
-}
+    /// 关闭串口，等待串口线程退出、串口释放后返回
+    pub fn close(&self) -> Result<()>{
+        let mut worker = self.worker.lock().map_err(|err| anyhow!("worker锁定失败:{:?}", err))?;
+        let handle = match worker.take(){
+            Some(handle) => handle,
+            None => return Ok(())
+        };
+        self.shared.status.set_state(ReaderState::Closing);
+        //释放发送端，串口线程等待命令时会立即醒来退出
+        match self.sender.lock(){
+            Ok(mut sender) => *sender = None,
+            Err(err) => error!("{:?}", err)
+        }
+        if handle.join().is_err(){
+            self.shared.status.record_error("串口线程异常退出");
+        }
+        self.shared.status.set_state(ReaderState::Closed);
+        Ok(())
+    }
 
-/// 打开UID主动上报
-pub fn open_uid_report() -> Result<NTAGResult>{
-    Ok(send_cmd_no_resp( CMD_OPEN_UID_REPORT, vec![])?)
-}
+    /// 检查串口是否已打开
+    pub fn is_opened(&self) -> bool{
+        self.shared.status.state().is_opened()
+    }
 
-/// 写入数据
-pub fn write_data(data: Vec<u8>) -> Result<NTAGResult>{
-    Ok(send_cmd( CMD_WRITE_DATA, data)?)
-}
+    /// 获取读卡器状态报告
+    pub fn status(&self) -> ReaderStatus{
+        self.shared.status.snapshot()
+    }
 
-/// 发送操作到线程
-fn send_cmd_no_resp(cmd: u8, data: Vec<u8>) -> Result<NTAGResult>{
-    let sender_lock = SENDER.lock();
-    if let Err(err) = sender_lock{
-        error!("{:?}", err);
-        return Err(anyhow!(format!("{:?}", err)));
-    }
-    let sender = sender_lock.unwrap();    
-    let tx = sender.as_ref().unwrap();
-    tx.send((cmd, data))?;
-    Ok(( cmd, true, vec![]))
-}
+    /// 获取当前读取到的UID，无卡片时为空
+    pub fn uid(&self) -> Option<Vec<u8>>{
+        match self.shared.uid.lock(){
+            Ok(uid) => uid.clone(),
+            Err(err) => err.into_inner().clone()
+        }
+    }
 
-/// 发送操作到线程
-fn send_cmd(cmd: u8, data: Vec<u8>) -> Result<NTAGResult>{
-    let sender_lock = SENDER.lock();
-    if let Err(err) = sender_lock{
-        error!("{:?}", err);
-        return Err(anyhow!(format!("{:?}", err)));
+    /// 命令默认超时时间
+    pub fn timeout(&self) -> Duration{
+        self.timeout
     }
-    let sender = sender_lock.unwrap();
 
-    let receiver_lock = RECEIVER.lock();
-    if let Err(err) = receiver_lock{
-        error!("{:?}", err);
-        return Err(anyhow!(format!("{:?}", err)));
+    /// 添加刷卡回调，新卡片进入读卡区域时在串口线程中调用，回调的超时时间和普通命令相同
+    pub fn add_tap_hook<H: TapHook + 'static>(&self, hook: H) -> Result<()>{
+        let mut hooks = self.shared.hooks.lock().map_err(|err| anyhow!("hooks锁定失败:{:?}", err))?;
+        hooks.push(Box::new(hook));
+        Ok(())
     }
-    let receiver = receiver_lock.unwrap();
 
-    if receiver.is_none(){
-        return Err(anyhow!("receiver为空!"));    
+    /// 在串口线程中执行操作，超过timeout后串口线程会取消操作，队列已满时返回忙
+    pub fn execute<T, F>(&self, timeout: Duration, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Card) -> Result<T> + Send + 'static,
+    {
+        let tx = self.sender()?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (reply_tx, reply_rx) = channel();
+        let job = Job{
+            deadline: Instant::now() + timeout,
+            run: Box::new(move |card: &mut Card| {
+                let result = match card.check_deadline(){
+                    Ok(()) => f(card),
+                    Err(_) => Err(anyhow!("命令{}在队列中等待超时，已取消", id)),
+                };
+                if let Err(err) = &result{
+                    error!("命令{} 执行失败: {:?}", id, err);
+                }
+                //请求方超时后会丢弃接收端，这里发送失败可以忽略
+                let _ = reply_tx.send(result);
+            }),
+        };
+        match tx.try_send(job){
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => return Err(anyhow!("读卡器忙，请稍后重试")),
+            Err(TrySendError::Disconnected(_)) => return Err(anyhow!("串口线程已退出")),
+        }
+        drop(tx);
+
+        //串口线程在截止时间后还要完成当前这一页的操作才能响应取消
+        match reply_rx.recv_timeout(timeout + CANCEL_GRACE){
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(anyhow!("命令{}超时", id)),
+            Err(RecvTimeoutError::Disconnected) => Err(anyhow!("串口线程已退出")),
+        }
+    }
+
+    /// 从start页开始读取count页
+    pub fn read_pages(&self, start: u8, count: u8) -> Result<Vec<u8>>{
+        self.execute(self.timeout, move |card| card.read_pages(start, count))
+    }
+
+    /// 从start页开始写入，数据长度必须是4的整数倍
+    pub fn write_pages(&self, start: u8, data: &[u8]) -> Result<()>{
+        let data = data.to_vec();
+        self.execute(self.timeout, move |card| card.write_pages(start, &data))
+    }
+
+    /// 从字节地址offset(页号*4+页内偏移)开始读取len个字节
+    pub fn read_bytes(&self, offset: usize, len: usize) -> Result<Vec<u8>>{
+        self.execute(self.timeout, move |card| card.read_bytes(offset, len))
+    }
+
+    /// 从字节地址offset开始写入，不会改动范围外的字节
+    pub fn write_bytes(&self, offset: usize, data: &[u8]) -> Result<()>{
+        let data = data.to_vec();
+        self.execute(self.timeout, move |card| card.write_bytes(offset, &data))
+    }
+
+    /// 旧版读取: 从第4页开始读取len个字节
+    pub fn read_data(&self, len: usize) -> Result<Vec<u8>>{
+        self.execute(self.timeout, move |card| card.read_data(len))
     }
-    
-    let rx = receiver.as_ref().unwrap();
-    let tx = sender.as_ref().unwrap();
 
-    tx.send((cmd, data))?;
-    let (cmd, success, data) = rx.recv()?;
-    //数据都出来是倒置的
-    let data = data.into_iter().rev().collect();
-    Ok((cmd, success, data))
+    /// 旧版写入: 从第4页开始倒序写入
+    pub fn write_data(&self, data: &[u8]) -> Result<()>{
+        let data = data.to_vec();
+        self.execute(self.timeout, move |card| card.write_data(&data))
+    }
+
+    /// 设置蜂鸣器
+    pub fn buzzer(&self, data: u8) -> Result<()>{
+        self.execute(self.timeout, move |card| card.buzzer(data))
+    }
+
+    /// 发送任意功能码，返回读卡器应答
+    pub fn transceive(&self, fn_code: u8, data: &[u8]) -> Result<PackageInfo>{
+        let data = data.to_vec();
+        self.execute(self.timeout, move |card| card.transceive(fn_code, &data))
+    }
+
+    /// 打开或关闭UID主动上报
+    pub fn set_uid_report(&self, enable: bool) -> Result<()>{
+        self.execute(self.timeout, move |card| card.set_uid_report(enable))
+    }
+
+    /// 取出任务发送端，不长时间持有锁
+    fn sender(&self) -> Result<SyncSender<Job>>{
+        let sender = self.sender.lock().map_err(|err| anyhow!("sender锁定失败:{:?}", err))?;
+        sender.clone().ok_or_else(|| anyhow!("串口未打开"))
+    }
 }
 
-pub type NTAGResult = (u8, bool, Vec<u8>);
\ No newline at end of file
+impl Drop for Reader{
+    fn drop(&mut self){
+        if let Err(err) = self.close(){
+            error!("串口关闭失败 {:?}", err);
+        }
+    }
+}
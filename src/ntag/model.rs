@@ -0,0 +1,300 @@
+use serde::{Deserialize, Serialize};
+
+/// 所有NTAG/Ultralight都有的用户数据区结束页(不包含)
+const ULTRALIGHT_USER_END: u16 = 16;
+
+/// 页的用途
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PageKind{
+    /// UID(第0、1页)
+    Uid,
+    /// UID最后一个字节和静态锁定字节(第2页)
+    Lock,
+    /// 能力容器CC(第3页，一次性写入)
+    Cc,
+    /// 用户数据
+    User,
+    /// 动态锁定字节
+    DynamicLock,
+    /// 配置页 CFG0、CFG1
+    Config,
+    /// 密码 PWD，读取时总是返回0
+    Password,
+    /// 密码确认 PACK，读取时总是返回0
+    Pack,
+    /// Mifare Classic扇区尾块，包含密钥和访问控制位
+    SectorTrailer,
+    /// 无法识别型号的NTAG/Ultralight第16页之后的页，可能是用户数据，也可能是配置页、密码或锁定字节
+    Unknown,
+}
+
+impl PageKind{
+    /// 能否通过普通写入恢复，Unknown由写保护策略决定
+    pub fn restorable(&self) -> bool{
+        matches!(self, PageKind::User | PageKind::Config | PageKind::Unknown)
+    }
+}
+
+/// 标签型号，按CC中的数据区大小识别
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TagModel{
+    /// MIFARE Ultralight，16页
+    Ultralight,
+    /// NTAG213，45页
+    Ntag213,
+    /// NTAG215，135页
+    Ntag215,
+    /// NTAG216，231页
+    Ntag216,
+    /// Mifare Classic 1K，64块，每块16字节
+    MifareClassic1k,
+    /// Mifare Classic 4K，256块，每块16字节
+    MifareClassic4k,
+    /// ISO15693，块数和块大小由卡片决定
+    Iso15693,
+    /// 无法识别，只按读取结果确定页数
+    Unknown,
+}
+
+impl TagModel{
+    /// 根据CC(第3页)识别型号
+    pub fn from_cc(cc: &[u8; 4]) -> TagModel{
+        if cc[0] != 0xE1{
+            return TagModel::Unknown;
+        }
+        match cc[2]{
+            0x06 => TagModel::Ultralight,
+            0x12 => TagModel::Ntag213,
+            0x3E => TagModel::Ntag215,
+            0x6D => TagModel::Ntag216,
+            _ => TagModel::Unknown,
+        }
+    }
+
+    /// 是否为NTAG/Ultralight系列
+    pub fn is_ntag(&self) -> bool{
+        matches!(self, TagModel::Ultralight | TagModel::Ntag213 | TagModel::Ntag215 | TagModel::Ntag216)
+    }
+
+    /// 是否为Mifare Classic
+    pub fn is_classic(&self) -> bool{
+        matches!(self, TagModel::MifareClassic1k | TagModel::MifareClassic4k)
+    }
+
+    /// 每页(块)的字节数，ISO15693为最常见的4字节
+    pub fn page_size(&self) -> usize{
+        if self.is_classic(){ 16 }else{ 4 }
+    }
+
+    /// 总页数，无法识别时为空
+    pub fn total_pages(&self) -> Option<u16>{
+        match self{
+            TagModel::Ultralight => Some(16),
+            TagModel::Ntag213 => Some(45),
+            TagModel::Ntag215 => Some(135),
+            TagModel::Ntag216 => Some(231),
+            TagModel::MifareClassic1k => Some(64),
+            TagModel::MifareClassic4k => Some(256),
+            TagModel::Iso15693 | TagModel::Unknown => None,
+        }
+    }
+
+    /// 用户数据页范围 [start, end)
+    pub fn user_pages(&self) -> Option<(u16, u16)>{
+        match self{
+            TagModel::Ultralight => Some((4, 16)),
+            TagModel::Ntag213 => Some((4, 40)),
+            TagModel::Ntag215 => Some((4, 130)),
+            TagModel::Ntag216 => Some((4, 226)),
+            _ => None,
+        }
+    }
+
+    /// 动态锁定字节所在页
+    pub fn dynamic_lock_page(&self) -> Option<u16>{
+        match self{
+            TagModel::Ntag213 => Some(40),
+            TagModel::Ntag215 => Some(130),
+            TagModel::Ntag216 => Some(226),
+            _ => None,
+        }
+    }
+
+    /// 配置页CFG0所在页，之后依次为CFG1、PWD、PACK
+    pub fn config_page(&self) -> Option<u16>{
+        self.dynamic_lock_page().map(|page| page + 1)
+    }
+
+    /// 每个动态锁定位锁定的页数
+    fn dynamic_lock_granularity(&self) -> u16{
+        match self{
+            TagModel::Ntag213 => 2,
+            _ => 16,
+        }
+    }
+
+    /// 是否为Mifare Classic扇区尾块: 前32个扇区每4块一个，后8个扇区每16块一个
+    fn is_sector_trailer(block: u16) -> bool{
+        if block < 128{
+            block % 4 == 3
+        }else{
+            (block - 128) % 16 == 15
+        }
+    }
+
+    /// 页的用途
+    pub fn page_kind(&self, page: u16) -> PageKind{
+        if self.is_classic(){
+            return match page{
+                0 => PageKind::Uid,
+                _ if TagModel::is_sector_trailer(page) => PageKind::SectorTrailer,
+                _ => PageKind::User,
+            };
+        }
+        if *self == TagModel::Iso15693{
+            return PageKind::User;
+        }
+        match page{
+            0 | 1 => return PageKind::Uid,
+            2 => return PageKind::Lock,
+            3 => return PageKind::Cc,
+            _ => ()
+        }
+        match self.dynamic_lock_page(){
+            Some(dynamic) => match page.checked_sub(dynamic){
+                Some(0) => PageKind::DynamicLock,
+                Some(1) | Some(2) => PageKind::Config,
+                Some(3) => PageKind::Password,
+                Some(4) => PageKind::Pack,
+                _ => PageKind::User,
+            },
+            //Ultralight EV1、NTAG210/212和CC无法识别的卡片从第16页开始可能就是配置页，只有第4~15页一定是用户数据
+            None if page >= ULTRALIGHT_USER_END => PageKind::Unknown,
+            None => PageKind::User,
+        }
+    }
+
+    /// 根据已读取的页数据计算每一页是否被锁定，pages为从第0页开始的连续数据，只适用于NTAG/Ultralight
+    pub fn locked_pages(&self, pages: &[[u8; 4]]) -> Vec<bool>{
+        //UID页出厂后只读
+        let mut locked: Vec<bool> = (0..pages.len()).map(|page| page < 2).collect();
+        //静态锁定字节: 第2页第2、3字节，控制第3~15页
+        if let Some(page2) = pages.get(2){
+            let bits = u16::from_le_bytes([page2[2], page2[3]]);
+            for (page, lock) in locked.iter_mut().enumerate().take(16).skip(3){
+                if bits & (1 << page) != 0{
+                    *lock = true;
+                }
+            }
+        }
+        //动态锁定字节: 从第16页开始，每一位锁定若干页
+        if let Some(dynamic) = self.dynamic_lock_page(){
+            if let Some(bytes) = pages.get(dynamic as usize){
+                let bits = u16::from_le_bytes([bytes[0], bytes[1]]);
+                let granularity = self.dynamic_lock_granularity();
+                for (page, lock) in locked.iter_mut().enumerate().take(dynamic as usize).skip(16){
+                    let bit = (page as u16 - 16) / granularity;
+                    if bit < 16 && bits & (1 << bit) != 0{
+                        *lock = true;
+                    }
+                }
+            }
+        }
+        //CFG1 ACCESS字节的CFGLCK位永久锁定配置页
+        if let Some(config) = self.config_page(){
+            if let Some(access) = pages.get(config as usize + 1){
+                if access[0] & 0x40 != 0{
+                    for page in config..config + 2{
+                        if let Some(lock) = locked.get_mut(page as usize){
+                            *lock = true;
+                        }
+                    }
+                }
+            }
+        }
+        locked
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn blank(model: TagModel) -> Vec<[u8; 4]>{
+        vec![[0u8; 4]; model.total_pages().unwrap() as usize]
+    }
+
+    fn locked_list(locked: &[bool]) -> Vec<usize>{
+        locked.iter().enumerate().filter(|(_, locked)| **locked).map(|(page, _)| page).collect()
+    }
+
+    #[test]
+    fn from_cc(){
+        assert_eq!(TagModel::from_cc(&[0xE1, 0x10, 0x12, 0x00]), TagModel::Ntag213);
+        assert_eq!(TagModel::from_cc(&[0xE1, 0x10, 0x3E, 0x00]), TagModel::Ntag215);
+        assert_eq!(TagModel::from_cc(&[0xE1, 0x10, 0x6D, 0x00]), TagModel::Ntag216);
+        assert_eq!(TagModel::from_cc(&[0xE1, 0x10, 0x06, 0x00]), TagModel::Ultralight);
+        assert_eq!(TagModel::from_cc(&[0x00, 0x10, 0x12, 0x00]), TagModel::Unknown);
+    }
+
+    #[test]
+    fn page_kinds(){
+        assert_eq!(TagModel::Ntag213.page_kind(2), PageKind::Lock);
+        assert_eq!(TagModel::Ntag213.page_kind(39), PageKind::User);
+        assert_eq!(TagModel::Ntag213.page_kind(40), PageKind::DynamicLock);
+        assert_eq!(TagModel::Ntag213.page_kind(42), PageKind::Config);
+        assert_eq!(TagModel::Ntag213.page_kind(43), PageKind::Password);
+        assert_eq!(TagModel::Ntag213.page_kind(44), PageKind::Pack);
+        assert_eq!(TagModel::Unknown.page_kind(15), PageKind::User);
+        assert_eq!(TagModel::Unknown.page_kind(16), PageKind::Unknown);
+        assert_eq!(TagModel::MifareClassic4k.page_kind(127), PageKind::SectorTrailer);
+        assert_eq!(TagModel::MifareClassic4k.page_kind(131), PageKind::User);
+        assert_eq!(TagModel::MifareClassic4k.page_kind(143), PageKind::SectorTrailer);
+    }
+
+    #[test]
+    fn unlocked_card_only_locks_uid(){
+        let model = TagModel::Ntag213;
+        assert_eq!(locked_list(&model.locked_pages(&blank(model))), [0, 1]);
+    }
+
+    #[test]
+    fn static_lock_bits(){
+        let model = TagModel::Ntag213;
+        let mut pages = blank(model);
+        //第2字节的第3位锁定第3页(CC)，第3字节的第0位锁定第8页
+        pages[2] = [0, 0, 0x08, 0x01];
+        assert_eq!(locked_list(&model.locked_pages(&pages)), [0, 1, 3, 8]);
+        pages[2] = [0, 0, 0xF8, 0xFF];
+        assert_eq!(locked_list(&model.locked_pages(&pages)), (0..16).filter(|page| *page != 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn dynamic_lock_bits(){
+        //NTAG213每一位锁定2页
+        let model = TagModel::Ntag213;
+        let mut pages = blank(model);
+        pages[40] = [0x81, 0, 0, 0];
+        assert_eq!(locked_list(&model.locked_pages(&pages)), [0, 1, 16, 17, 30, 31]);
+
+        //NTAG216每一位锁定16页
+        let model = TagModel::Ntag216;
+        let mut pages = blank(model);
+        pages[226] = [0x02, 0, 0, 0];
+        assert_eq!(locked_list(&model.locked_pages(&pages)), [0, 1].into_iter().chain(32..48).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn cfglck_locks_config_pages(){
+        let model = TagModel::Ntag215;
+        let mut pages = blank(model);
+        pages[132] = [0x40, 0, 0, 0];
+        assert_eq!(locked_list(&model.locked_pages(&pages)), [0, 1, 131, 132]);
+    }
+
+    #[test]
+    fn short_dump_does_not_panic(){
+        let pages = vec![[0u8; 4]; 3];
+        assert_eq!(TagModel::Ntag216.locked_pages(&pages), [true, true, false]);
+    }
+}
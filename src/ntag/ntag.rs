@@ -1,23 +1,20 @@
 use anyhow::{anyhow, Result};
 use log::{error, info, warn};
-use serde::Deserialize;
-use serialport::SerialPort;
+use serde::{Deserialize, Serialize};
+use serialport::{ClearBuffer, SerialPort};
 use std::{io::Read, time::{Duration, Instant}};
 use crc16::*;
-use std::thread;
-use std::sync::Mutex;
+use std::sync::Arc;
 use std::mem;
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
-use once_cell::sync::Lazy;
-use std::sync::mpsc::{channel, Sender, Receiver};
-
-// 是否关闭串口
-pub static OPENED: Lazy<Mutex<bool>> = Lazy::new(|| { Mutex::new(false) });
-// 存储当前读取到的UID
-static UID: Lazy<Mutex<Option<Vec<u8>>>> = Lazy::new(|| { Mutex::new( None ) });
-static LOOPING: Lazy<Mutex<bool>> = Lazy::new(|| { Mutex::new( true ) });
-
-#[derive(Debug, Deserialize)]
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use super::status::{ReaderState, StatusCell};
+use super::cache::PageCache;
+use super::{ReaderOptions, Shared, TagModel};
+use crate::policy::WritePolicy;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum CardType{
     Mifare,
     UltraLight,
@@ -28,6 +25,7 @@ pub enum CardType{
 }
 
 impl CardType{
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(tp: &str) -> CardType{
         match tp{
             "Mifare" => CardType::Mifare,
@@ -105,33 +103,46 @@ pub const FN_CODE_UID_REPORT_SET:u8 = 0x07;
 
 /// 操作成功
 pub const ST_CODE_SUCCESS:u8 = 0x00;
-/// 数据长度错误
+// 数据长度错误
 // pub const ST_CODE_DATA_ERROR:u8 = 0x01;
-/// CRC校验失败
+// CRC校验失败
 // pub const ST_CODE_CRC_ERROR:u8 = 0x02;
-/// 命令参数错误
+// 命令参数错误
 // pub const ST_CODE_PARAM_ERROR:u8 = 0x03;
-/// 寻卡失败
+// 寻卡失败
 // pub const ST_CODE_CARD_ERROR:u8 = 0x0B;
-/// UID获取失败
+// UID获取失败
 // pub const ST_CODE_UID_ERROR:u8 = 0x0C;
-/// 读写数据错误
+// 读写数据错误
 // pub const ST_CODE_READ_WRITE_ERROR:u8 = 0x0F;
 
-//读取UID
-// pub const CMD_READ_UID:u8 = 0x00;
-//写入数据
-pub const CMD_WRITE_DATA: u8 = 0x01;
-//读取数据
-pub const CMD_READ_DATA: u8 = 0x02;
-//设置蜂鸣器
-pub const CMD_SET_BUZZER: u8 = 0x03;
-//关闭UID主动上报
-pub const CMD_CLOSE_UID_REPORT: u8 = 0x04;
-//打开UID主动上报
-pub const CMD_OPEN_UID_REPORT: u8 = 0x05;
-
-pub const READ_TIMEOUT:u16 = 500;
+/// 每页字节数
+pub const PAGE_SIZE: usize = 4;
+/// READ_CNT中NFC计数器的地址
+const NFC_COUNTER_ADDR: u8 = 0x02;
+/// NTAG21x原厂签名长度
+const SIGNATURE_LEN: usize = 32;
+/// 用户数据起始页
+pub const USER_START_PAGE: u8 = 4;
+/// 旧版数据格式(read_data/write_data)的最后一页
+pub const LEGACY_LAST_PAGE: u8 = 39;
+
+/// 帧头
+pub const FRAME_HEADER: u8 = 0x24;
+/// 等待一个应答数据包的超时时间
+pub const READ_TIMEOUT: Duration = Duration::from_millis(500);
+/// 每次从串口读取的最大字节数
+const READ_CHUNK: usize = 256;
+/// 应答丢失后，发送下一条命令前等待串口安静的时间，迟到的应答在此期间被丢弃
+const DRAIN_QUIET: Duration = Duration::from_millis(50);
+/// 数据包中数据长度的上限，超过的认为不是帧头
+const MAX_DATA_LENGTH: usize = 1024;
+/// 一次READ命令最多返回的页数
+const READ_MAX_PAGES: usize = 4;
+/// 一次FAST_READ命令最多读取的页数，受读卡器数据包长度限制
+const FAST_READ_MAX_PAGES: usize = 60;
+/// 连续通信失败多少次后进入降级状态
+pub const DEGRADED_THRESHOLD: u32 = 3;
 
 /// 给数据添加校验码
 fn wrap_data(mut data: Vec<u8>) -> Result<Vec<u8>>{
@@ -143,472 +154,847 @@ fn wrap_data(mut data: Vec<u8>) -> Result<Vec<u8>>{
     Ok(data)
 }
 
-struct PackageInfo{
+/// 读卡器应答数据包
+#[derive(Debug, Clone)]
+pub struct PackageInfo{
     /// 帧头
     pub header: u8,
     /// 包长度 为（功能码 + 状态码 + 数据长度 + 数据 + CRC）的所有字节长度之和，低字节在前，高字节在后；
     pub length: u16,
     /// 功能码
-    fn_code: u8,
+    pub fn_code: u8,
     /// 状态码
-    st_code: u8,
+    pub st_code: u8,
     /// 数据长度(数据字节长度)
     pub data_length: u16,
     /// 数据
-    data: Vec<u8>,
+    pub data: Vec<u8>,
     /// 校验码
     pub crc: u16,
 }
 
-/// 发送数据包
-fn send_package(port:&mut Box<dyn SerialPort>, fn_code: u8, data:&[u8], debug: bool) -> Result<()>{
-    let mut pkg = vec![];
-    //帧头
-    pkg.push(0x24);
-    //包长度为（  功能码  +  状态码  +  数据长度  +  数据  + CRC  ）的所有字节长度之和
-    let pkg_len:u16 = 1 + 2 + data.len() as u16 + 2;
-    let mut pkg_len_bytes = vec![];
-    pkg_len_bytes.write_u16::<LittleEndian>(pkg_len)?;
-    pkg.extend_from_slice(&pkg_len_bytes);
-    //功能码
-    pkg.push(fn_code);
-    //数据长度
-    let data_len = data.len() as u16;
-    let mut data_len_bytes = vec![];
-    data_len_bytes.write_u16::<LittleEndian>(data_len)?;
-    pkg.extend_from_slice(&data_len_bytes);
-    //数据
-    pkg.extend_from_slice(data);
-    //crc
-    let send_data = wrap_data(pkg.to_vec())?;
-    if debug{
-        warn!("发送:{:X?}", send_data);
-    }
-    port.write_all(&send_data)?;
-    Ok(())
+impl PackageInfo{
+    /// 状态码不是成功时返回错误
+    pub fn check(self, what: &str) -> Result<PackageInfo>{
+        if self.st_code == ST_CODE_SUCCESS{
+            Ok(self)
+        }else{
+            Err(anyhow!("{} 失败 st_code={:#04X}", what, self.st_code))
+        }
+    }
 }
 
-///u16转字节
-fn u16_to_slice<'a>(v: u16) -> Result<Vec<u8>>{
-    let mut u16_bytes = Vec::with_capacity(2);
-    u16_bytes.write_u16::<LittleEndian>(v)?;
-    Ok(u16_bytes)
+/// 串口连接，带接收缓冲区
+struct Connection{
+    port: Box<dyn SerialPort>,
+    /// 已收到但还未解析的字节
+    buf: Vec<u8>,
+    /// 上一条命令没有收到应答，应答可能迟到
+    stale: bool,
+    debug: bool,
 }
 
-///从串口中读取一个字节
-fn read_u8(port:&mut Box<dyn SerialPort>, mut try_times: u8) -> Result<u8>{
-    let buf = &mut[0u8];
-    let mut success = false;
-    loop{
-        match port.read(buf){
-            Err(_err) => {
-                //可能会读取超时，但是不报错，继续尝试读取
-                // error!("{:?}", err);
+impl Connection{
+    fn new(port: Box<dyn SerialPort>, debug: bool) -> Connection{
+        Connection{ port, buf: Vec::with_capacity(READ_CHUNK * 2), stale: false, debug }
+    }
+
+    /// 丢弃发送前收到的所有数据。上一条命令的应答丢失时，先等待串口安静，
+    /// 迟到的应答不会被当作下一条命令的应答
+    fn discard_input(&mut self) -> Result<()>{
+        if self.stale{
+            let deadline = Instant::now() + READ_TIMEOUT;
+            let mut chunk = [0u8; READ_CHUNK];
+            while Instant::now() < deadline{
+                self.port.set_timeout(DRAIN_QUIET)?;
+                match self.port.read(&mut chunk){
+                    Ok(0) => break,
+                    Ok(len) => {
+                        if self.debug{
+                            warn!("丢弃迟到的数据:{}", hex::encode(&chunk[..len]));
+                        }
+                    }
+                    Err(err) if err.kind() == std::io::ErrorKind::TimedOut => break,
+                    Err(err) => return Err(err.into()),
+                }
             }
-            Ok(len) => {
-                if len == 1{
-                    success = true;
-                    break;
+            self.stale = false;
+        }
+        self.buf.clear();
+        self.port.clear(ClearBuffer::Input)?;
+        Ok(())
+    }
+
+    /// 发送数据包
+    fn send_package(&mut self, fn_code: u8, data:&[u8]) -> Result<()>{
+        let mut pkg = vec![];
+        //帧头
+        pkg.push(FRAME_HEADER);
+        //包长度为（  功能码  +  状态码  +  数据长度  +  数据  + CRC  ）的所有字节长度之和
+        let pkg_len:u16 = 1 + 2 + data.len() as u16 + 2;
+        pkg.write_u16::<LittleEndian>(pkg_len)?;
+        //功能码
+        pkg.push(fn_code);
+        //数据长度
+        pkg.write_u16::<LittleEndian>(data.len() as u16)?;
+        //数据
+        pkg.extend_from_slice(data);
+        //crc
+        let send_data = wrap_data(pkg)?;
+        if self.debug{
+            warn!("发送:{:X?}", send_data);
+        }
+        self.port.write_all(&send_data)?;
+        Ok(())
+    }
+
+    /// 从缓冲区中解析一个完整的数据包，数据不足时返回None，校验失败的数据会被丢弃
+    fn parse_package(&mut self) -> Option<PackageInfo>{
+        loop{
+            //丢弃帧头之前的数据
+            match self.buf.iter().position(|b| *b == FRAME_HEADER){
+                Some(pos) => { self.buf.drain(..pos); }
+                None => {
+                    self.buf.clear();
+                    return None;
                 }
             }
+            //帧头 + 包长度 + 功能码 + 状态码 + 数据长度
+            if self.buf.len() < 7{
+                return None;
+            }
+            let length = LittleEndian::read_u16(&self.buf[1..3]);
+            let fn_code = self.buf[3];
+            let st_code = self.buf[4];
+            let data_length = LittleEndian::read_u16(&self.buf[5..7]);
+            if data_length as usize > MAX_DATA_LENGTH{
+                //不是真正的帧头，跳过继续查找
+                self.buf.drain(..1);
+                continue;
+            }
+            let total = 7 + data_length as usize + 2;
+            if self.buf.len() < total{
+                return None;
+            }
+            let crc = LittleEndian::read_u16(&self.buf[total-2..total]);
+            let my_crc = State::<XMODEM>::calculate(&self.buf[..total-2]);
+            if crc != my_crc{
+                warn!("数据校验失败 丢弃:{}", hex::encode(&self.buf[..total]));
+                self.buf.drain(..1);
+                continue;
+            }
+            let data = self.buf[7..total-2].to_vec();
+            self.buf.drain(..total);
+            if self.debug{
+                warn!("接收 => 包长度:{} 功能码:{:X} 状态码:{:X} 数据长度:{} 数据:{} 校验码:{:#02X} 本地校验码:{:#02X}",
+                length, fn_code, st_code, data_length, hex::encode(&data), crc, my_crc);
+            }
+            return Some(PackageInfo{
+                header: FRAME_HEADER,
+                length,
+                fn_code,
+                st_code,
+                data_length,
+                data,
+                crc,
+            });
         }
-        //延迟一会儿
-        thread::sleep(Duration::from_millis(1));
-        if try_times == 0{
-            break;
+    }
+
+    ///从串口读取数据包，阻塞读取直到收到完整数据包或超时
+    fn read_package(&mut self) -> Result<PackageInfo>{
+        let deadline = Instant::now() + READ_TIMEOUT;
+        let mut chunk = [0u8; READ_CHUNK];
+        loop{
+            if let Some(pkg) = self.parse_package(){
+                return Ok(pkg);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero(){
+                return Err(anyhow!("数据包读取超时"));
+            }
+            self.port.set_timeout(remaining)?;
+            match self.port.read(&mut chunk){
+                Ok(len) => self.buf.extend_from_slice(&chunk[..len]),
+                Err(err) if err.kind() == std::io::ErrorKind::TimedOut => (),
+                Err(err) => return Err(err.into()),
+            }
         }
-        try_times -= 1;
     }
-    if success{
-        Ok(buf[0])
-    }else{
-        Err(anyhow!("read_u8 超时"))
+
+    /// 同步发送消息，并等待应答。
+    /// 应答中只有功能码，没有页号等命令参数，所以发送前丢弃所有已收到的数据，只接受发送之后收到的应答
+    fn send_package_and_wait(&mut self, fn_code: u8, data:&[u8]) -> Result<PackageInfo>{
+        self.discard_input()?;
+        //发送
+        self.send_package(fn_code, data)?;
+        //尝试3次读取对应的回应
+        let mut count = 0;
+        loop{
+            let pkg = match self.read_package(){
+                Ok(pkg) => pkg,
+                Err(err) => {
+                    self.stale = true;
+                    return Err(err);
+                }
+            };
+            if pkg.fn_code == fn_code{
+                return Ok(pkg);
+            }
+            count += 1;
+            if count > 3{
+                break;
+            }
+        }
+        self.stale = true;
+        Err(anyhow!("功能码:{} 应答超时", fn_code))
     }
 }
-///从串口中读取两个字节
-fn read_u16(port:&mut Box<dyn SerialPort>, mut try_times: u8) -> Result<u16>{
-    let buf = &mut[0u8; 2];
-    let mut success = false;
-    loop{
-        if port.read(buf)? == 2{
-            success = true;
-            break;
+
+/// 是否为串口丢失(拔出、断开)等无法恢复的错误，超时类错误可以重试
+fn is_port_lost(err: &anyhow::Error) -> bool{
+    match err.downcast_ref::<std::io::Error>(){
+        Some(err) => !matches!(err.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted),
+        None => false
+    }
+}
+
+/// 串口线程中的卡片操作，所有操作在串口线程中顺序执行，不会和UID检测或其他请求交叉
+pub struct Card{
+    conn: Connection,
+    card_type: CardType,
+    /// 当前命令的截止时间
+    deadline: Option<Instant>,
+    /// 多页操作开始时读取到的UID，操作结束前再次检查
+    bound_uid: Option<Vec<u8>>,
+    /// 当前卡片已知的页内容，用于差分写入
+    cache: PageCache,
+    /// 为true时read_pages优先使用缓存
+    use_cache: bool,
+    /// 已发送的命令数，用于判断操作是否和卡片通信过
+    sent: u64,
+    /// 最近一次READ命令返回的页数
+    read_width: usize,
+    fast_read: FastRead,
+    /// 读卡器READ_CNT、READ_SIG的功能码
+    read_cnt: Option<u8>,
+    read_sig: Option<u8>,
+    /// 写保护策略，guarded中每次写页前检查
+    policy: Option<Arc<WritePolicy>>,
+}
+
+/// FAST_READ支持情况
+#[derive(Debug, Clone, Copy)]
+struct FastRead{
+    /// 读卡器的FAST_READ功能码，未配置时不使用
+    code: Option<u8>,
+    /// 读卡器是否支持，没有应答或应答格式错误后不再使用
+    reader_ok: bool,
+    /// 当前卡片是否支持，换卡后重新尝试
+    card_ok: bool,
+}
+
+/// 差分写入时卡片当前内容的来源
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffBase{
+    /// 写入前先读取
+    Read,
+    /// 使用卡片停留期间读写过的内容，缓存中没有的页再读取
+    Cache,
+}
+
+impl Card{
+    /// 卡片类型
+    pub fn card_type(&self) -> CardType{
+        self.card_type
+    }
+
+    /// 检查当前命令是否超时
+    pub fn check_deadline(&self) -> Result<()>{
+        match self.deadline{
+            Some(deadline) if Instant::now() >= deadline => Err(anyhow!("命令超时，已取消")),
+            _ => Ok(())
         }
-        if try_times == 0{
-            break;
+    }
+
+    /// 把操作绑定到当前卡片: 开始前读取UID(和expected比较)，f成功后再次检查UID没有变化，
+    /// 防止操作中途更换卡片。已经绑定时(嵌套调用)直接执行f。
+    /// 在cached中并且UID检测已经读取到卡片时使用缓存的UID，f完全从缓存读取时不再和卡片通信
+    pub fn bound<T>(&mut self, expected: Option<&[u8]>, f: impl FnOnce(&mut Card) -> Result<T>) -> Result<T>{
+        if self.bound_uid.is_some(){
+            if let (Some(expected), Some(uid)) = (expected, &self.bound_uid){
+                if expected != uid.as_slice(){
+                    return Err(anyhow!("卡片UID不符 期望:{} 实际:{}", hex::encode(expected), hex::encode(uid)));
+                }
+            }
+            return f(self);
+        }
+        let uid = match self.cache.uid(){
+            Some(uid) if self.use_cache => uid.to_vec(),
+            _ => self.read_uid()?.ok_or_else(|| anyhow!("无卡片"))?,
+        };
+        if let Some(expected) = expected{
+            if expected != uid.as_slice(){
+                return Err(anyhow!("卡片UID不符 期望:{} 实际:{}", hex::encode(expected), hex::encode(&uid)));
+            }
+        }
+        self.bound_uid = Some(uid.clone());
+        let sent = self.sent;
+        let result = f(self);
+        self.bound_uid = None;
+        let value = result?;
+        if self.sent == sent{
+            return Ok(value);
+        }
+        match self.read_uid()?{
+            Some(current) if current == uid => Ok(value),
+            Some(current) => Err(anyhow!("操作过程中卡片已更换 开始:{} 结束:{}", hex::encode(&uid), hex::encode(current))),
+            None => Err(anyhow!("操作过程中卡片已离开 uid={}", hex::encode(&uid))),
         }
-        try_times -= 1;
     }
-    if success{
-        let v = LittleEndian::read_u16(buf);
-        Ok(v)
-    }else{
-        Err(anyhow!("read_u16 超时"))
+
+    /// 在f中读取时优先使用缓存: 卡片停留期间读写过的页直接返回，不再和卡片通信，
+    /// 缓存中不完整时仍然从卡片读取
+    pub fn cached<T>(&mut self, f: impl FnOnce(&mut Card) -> Result<T>) -> Result<T>{
+        let previous = self.use_cache;
+        self.use_cache = true;
+        let result = f(self);
+        self.use_cache = previous;
+        result
     }
-}
-///从串口中读取一组字节
-fn read_bytes(port:&mut Box<dyn SerialPort>, len: usize, mut try_times: u8) -> Result<Vec<u8>>{
-    if len == 0{
-        return Ok(vec![]);
-    }
-    let mut buf = vec![0u8; len];
-    let mut idx = 0;
-    let mut success = false;
-    loop{
-        let read_len = port.read(&mut buf[idx..])?;
-        idx += read_len;
-        if idx == read_len{
-            success = true;
-            break;
+
+    /// 在f中按policy检查每一页写入，受保护的页返回失败且不发送写命令；policy为空时不限制
+    pub fn guarded<T>(&mut self, policy: Option<Arc<WritePolicy>>, f: impl FnOnce(&mut Card) -> Result<T>) -> Result<T>{
+        let previous = mem::replace(&mut self.policy, policy);
+        let result = f(self);
+        self.policy = previous;
+        result
+    }
+
+    /// 按guarded中的写保护策略检查一页能否写入，不发送写命令
+    pub fn check_writable(&mut self, page: u8) -> Result<()>{
+        let policy = match &self.policy{
+            Some(policy) if policy.get(self.card_type).is_some() => policy.clone(),
+            _ => return Ok(()),
+        };
+        let model = match self.card_type{
+            //1K的块号范围内两种型号的扇区尾块位置相同
+            CardType::Mifare => TagModel::MifareClassic4k,
+            CardType::ISO15693 => TagModel::Iso15693,
+            CardType::UltraLight => self.cached_model()?,
+            _ => TagModel::Unknown,
+        };
+        policy.check(self.card_type, model, page as u16)
+    }
+
+    /// 最近一次读取到的UID，卡片离开后为空，不和卡片通信
+    pub fn cached_uid(&self) -> Option<&[u8]>{
+        self.cache.uid()
+    }
+
+    /// 缓存中的连续页，有任意一页不在缓存中时为空
+    fn cached_pages(&self, start: u8, count: u8) -> Option<Vec<u8>>{
+        self.cache.uid()?;
+        let mut data = Vec::with_capacity(count as usize * PAGE_SIZE);
+        for i in 0..count{
+            data.extend_from_slice(&self.cache.get(start + i)?);
         }
-        if read_len == 0{
-            if try_times == 0{
-                break;
+        Some(data)
+    }
+
+    /// 当前绑定的UID，不在bound中时为空
+    pub fn bound_uid(&self) -> Option<&[u8]>{
+        self.bound_uid.as_deref()
+    }
+
+    /// 发送一个功能码和数据，返回读卡器的应答
+    pub fn transceive(&mut self, fn_code: u8, data: &[u8]) -> Result<PackageInfo>{
+        self.check_deadline()?;
+        self.sent += 1;
+        self.conn.send_package_and_wait(fn_code, data)
+    }
+
+    /// 立即读取一次UID，无卡片时为空
+    pub fn read_uid(&mut self) -> Result<Option<Vec<u8>>>{
+        let pkg = match self.transceive(self.card_type.fn_code_read_uid(), &[]){
+            Ok(pkg) => pkg,
+            Err(err) => {
+                self.cache.clear();
+                return Err(err);
+            }
+        };
+        if pkg.st_code != ST_CODE_SUCCESS{
+            if self.conn.debug{
+                error!("FN_CODE_READ_UID st_code={}", pkg.st_code);
             }
-            try_times -= 1;
+            self.cache.set_uid(None);
+            Ok(None)
+        }else{
+            if self.cache.uid() != Some(pkg.data.as_slice()){
+                self.fast_read.card_ok = true;
+            }
+            self.cache.set_uid(Some(&pkg.data));
+            Ok(Some(pkg.data))
         }
     }
-    if success{
-        Ok(buf)
-    }else{
-        Err(anyhow!("read_bytes 超时"))
+
+    /// 读取CC(第3页)识别标签型号
+    pub fn detect_model(&mut self) -> Result<TagModel>{
+        Ok(TagModel::from_cc(&self.read_page(3)?))
     }
-}
 
-///从串口读取数据包
-fn read_package(port:&mut Box<dyn SerialPort>, debug: bool) -> Result<PackageInfo>{
-    //每次读取字节尝试3次
-    let try_times = 3;
-    let mut pkg_buf = vec![];
-    //等待帧头
-    let start = Instant::now();
-    while read_u8(port, try_times)? != 36{
-        thread::sleep(Duration::from_millis(1));
-        if start.elapsed().as_millis() as u16 > READ_TIMEOUT{
-            return Err( anyhow!("帧头读取超时") );
-        }
-    }
-    pkg_buf.push(0x24);
-    //读取包长度
-    let length = read_u16(port, try_times)?;
-    pkg_buf.extend_from_slice(&u16_to_slice(length)?);
-    //读取功能码
-    let fn_code = read_u8(port, try_times)?;
-    pkg_buf.push(fn_code);
-    //读取状态码
-    let st_code = read_u8(port, try_times)?;
-    pkg_buf.push(st_code);
-    //读取数据长度
-    let data_length = read_u16(port, try_times)?;
-    pkg_buf.extend_from_slice(&u16_to_slice(data_length)?);
-    //读取数据
-    let data:Vec<u8> = read_bytes(port, data_length as usize, try_times)?;
-    pkg_buf.extend_from_slice(&data);
-    //读取校验码
-    let crc = read_u16(port, try_times)?;
-    let my_crc = State::<XMODEM>::calculate(&pkg_buf);
-    if crc != my_crc{
-        return Err(anyhow!("数据校验失败"));
-    }
-    if debug{
-        warn!("接收 => 包长度:{} 功能码:{:X} 状态码:{:X} 数据长度:{} 数据:{} 校验码:{:#02X} 本地校验码:{:#02X}",
-        length, fn_code, st_code, data_length, hex::encode(&data), crc, my_crc);
-    }
-
-    let pkg_info = PackageInfo{
-        header: 0x24,
-        length,
-        fn_code,
-        st_code,
-        data_length,
-        data,
-        crc,
-    };
-    Ok(pkg_info)
-}
+    /// 识别标签型号，CC已在缓存中时不和卡片通信
+    pub fn cached_model(&mut self) -> Result<TagModel>{
+        match self.cache.get(3){
+            Some(cc) => Ok(TagModel::from_cc(&cc)),
+            None => self.detect_model(),
+        }
+    }
 
-/// 写入4个字节并等待
-fn write_page(port:&mut Box<dyn SerialPort>, card_type:&CardType, page: u8, data:&[u8; 4], debug: bool) -> Result<PackageInfo>{
-    let mut snd:Vec<u8> = Vec::with_capacity(5);
-    snd.push(page);
-    snd.extend(data);
-    Ok(send_package_and_wait(port, card_type.fn_code_write_data(), &snd, debug)?)
-}
+    /// 读取一页
+    pub fn read_page(&mut self, page: u8) -> Result<[u8; PAGE_SIZE]>{
+        let block = self.read_block(page)?;
+        let mut buf = [0u8; PAGE_SIZE];
+        buf.copy_from_slice(&block[..PAGE_SIZE]);
+        self.cache.put(page, buf);
+        Ok(buf)
+    }
 
-/// 关闭串口
-pub fn close_port() -> bool{
-    match OPENED.lock(){
-        Ok(mut opened) => {
-            *opened = false;
-            true
+    /// 读取从page开始的一个数据块，返回应答中的所有整页数据。
+    /// NTAG的READ命令一次返回4页，读到页末尾时会回绕到第0页，调用方只能使用自己请求范围内的页。
+    /// Mifare等卡片的应答是page这一个块，16字节的块不是4个连续的页，只返回和单页读取相同的前4字节
+    fn read_block(&mut self, page: u8) -> Result<Vec<u8>>{
+        let fn_code_read_data = self.card_type.fn_code_read_data();
+        let pkg = self.transceive(fn_code_read_data, &[page])?.check(&format!("读取第{}页", page))?;
+        if pkg.data.len() < PAGE_SIZE{
+            return Err(anyhow!("读取第{}页 数据长度错误:{}", page, pkg.data.len()));
         }
-        Err(err) => {
-            error!("{:?}", err);
-            false
+        let pages = if self.card_type == CardType::UltraLight{
+            (pkg.data.len() / PAGE_SIZE).min(READ_MAX_PAGES)
+        }else{
+            1
+        };
+        self.read_width = pages;
+        Ok(pkg.data[..pages * PAGE_SIZE].to_vec())
+    }
+
+    /// 用FAST_READ读取从start开始最多count页，读卡器或卡片不支持时为空
+    fn fast_read(&mut self, start: u8, count: usize) -> Result<Option<Vec<u8>>>{
+        let fn_code = match self.fast_read.code{
+            Some(code) if self.card_type == CardType::UltraLight && self.fast_read.reader_ok && self.fast_read.card_ok => code,
+            _ => return Ok(None),
+        };
+        let count = count.min(FAST_READ_MAX_PAGES);
+        let end = start as usize + count - 1;
+        match self.transceive(fn_code, &[start, end as u8]){
+            Ok(pkg) if pkg.st_code == ST_CODE_SUCCESS && pkg.data.len() >= count * PAGE_SIZE => {
+                Ok(Some(pkg.data[..count * PAGE_SIZE].to_vec()))
+            }
+            Ok(pkg) if pkg.st_code == ST_CODE_SUCCESS => {
+                warn!("FAST_READ应答长度错误:{} 读卡器不支持，改用READ", pkg.data.len());
+                self.fast_read.reader_ok = false;
+                Ok(None)
+            }
+            Ok(pkg) => {
+                //卡片不支持(如Ultralight)，换卡后再尝试
+                warn!("FAST_READ失败 st_code={:#04X} 当前卡片改用READ", pkg.st_code);
+                self.fast_read.card_ok = false;
+                Ok(None)
+            }
+            Err(err) => {
+                self.check_deadline()?;
+                if is_port_lost(&err){
+                    return Err(err);
+                }
+                warn!("FAST_READ无应答 读卡器不支持，改用READ {:?}", err);
+                self.fast_read.reader_ok = false;
+                Ok(None)
+            }
         }
     }
-}
 
-/// 获取当前读取到的UID，读取失败时为空
-pub fn get_current_uid() -> Result<Option<Vec<u8>>>{
-    match UID.lock(){
-        Ok(uid) => Ok(uid.clone()),
-        Err(err) => {
-            let err = format!("UID lock失败:{:?}", err);
-            Err(anyhow!(err))
+    /// 从start页开始读取count页，数据按卡片上的顺序返回。
+    /// 使用读卡器和卡片支持的最宽的读取命令: FAST_READ > 一次4页的READ > 逐页读取
+    pub fn read_pages(&mut self, start: u8, count: u8) -> Result<Vec<u8>>{
+        check_page_range(start, count as usize)?;
+        if self.use_cache{
+            if let Some(data) = self.cached_pages(start, count){
+                return Ok(data);
+            }
         }
+        if count == 1{
+            return Ok(self.read_page(start)?.to_vec());
+        }
+        self.bound(None, |card| {
+            let end = start as usize + count as usize;
+            let mut data = Vec::with_capacity(count as usize * PAGE_SIZE);
+            let mut page = start as usize;
+            while page < end{
+                let want = end - page;
+                let fast = if want > card.read_width{ card.fast_read(page as u8, want)? }else{ None };
+                let chunk = match fast{
+                    Some(chunk) => chunk,
+                    None => card.read_block(page as u8)?,
+                };
+                let take = (chunk.len() / PAGE_SIZE).min(want);
+                for (i, bytes) in chunk.chunks(PAGE_SIZE).take(take).enumerate(){
+                    let mut buf = [0u8; PAGE_SIZE];
+                    buf.copy_from_slice(bytes);
+                    card.cache.put((page + i) as u8, buf);
+                }
+                data.extend_from_slice(&chunk[..take * PAGE_SIZE]);
+                page += take;
+            }
+            Ok(data)
+        })
+    }
+
+    /// 写入一页
+    pub fn write_page(&mut self, page: u8, data: &[u8; PAGE_SIZE]) -> Result<()>{
+        self.check_writable(page)?;
+        let mut snd:Vec<u8> = Vec::with_capacity(PAGE_SIZE + 1);
+        snd.push(page);
+        snd.extend(data);
+        //写入失败时页内容不确定
+        self.cache.remove(page);
+        self.transceive(self.card_type.fn_code_write_data(), &snd)?.check(&format!("写入第{}页", page))?;
+        self.cache.put(page, *data);
+        Ok(())
     }
-}
 
-// 开始、停止循环读取UID(在做其他命令的时候要先停止，停止后，至少要过一定时间才生效)
-pub fn set_loop(lp: bool) -> Result<()>{
-    match LOOPING.lock(){
-        Ok(mut l) => {
-            *l = lp;
+    /// 从start页开始写入，数据长度必须是4的整数倍
+    pub fn write_pages(&mut self, start: u8, data: &[u8]) -> Result<()>{
+        if !data.len().is_multiple_of(PAGE_SIZE){
+            return Err(anyhow!("数据长度必须是{}的整数倍", PAGE_SIZE));
+        }
+        check_page_range(start, data.len() / PAGE_SIZE)?;
+        //有受保护的页时一页都不写
+        for i in 0..data.len() / PAGE_SIZE{
+            self.check_writable(start + i as u8)?;
+        }
+        let write = |card: &mut Card| {
+            for (i, chunk) in data.chunks(PAGE_SIZE).enumerate(){
+                let mut page = [0u8; PAGE_SIZE];
+                page.copy_from_slice(chunk);
+                card.write_page(start + i as u8, &page).map_err(|err| anyhow!("{:?} 已写入{}页", err, i))?;
+            }
             Ok(())
+        };
+        if data.len() <= PAGE_SIZE{
+            write(self)
+        }else{
+            self.bound(None, write)
         }
-        Err(err) => {
-            let err = format!("LOOPING lock失败:{:?}", err);
-            Err(anyhow!(err))
+    }
+
+    /// 从字节地址offset(页号*4+页内偏移)开始读取len个字节
+    pub fn read_bytes(&mut self, offset: usize, len: usize) -> Result<Vec<u8>>{
+        if len == 0{
+            return Ok(vec![]);
         }
+        let (start, count) = page_span(offset, len)?;
+        let data = self.read_pages(start, count)?;
+        let skip = offset % PAGE_SIZE;
+        Ok(data[skip..skip + len].to_vec())
     }
-}
 
-/*
+    /// 从字节地址offset开始写入，首尾不完整的页先读取再合并写回
+    pub fn write_bytes(&mut self, offset: usize, data: &[u8]) -> Result<()>{
+        if data.is_empty(){
+            return Ok(());
+        }
+        let (start, count) = page_span(offset, data.len())?;
+        let skip = offset % PAGE_SIZE;
+        let end = skip + data.len();
+        let partial = skip != 0 || !end.is_multiple_of(PAGE_SIZE);
+        let merge = |card: &mut Card| {
+            let mut pages = vec![0u8; count as usize * PAGE_SIZE];
+            if skip != 0{
+                pages[..PAGE_SIZE].copy_from_slice(&card.read_page(start)?);
+            }
+            if !end.is_multiple_of(PAGE_SIZE) && (count > 1 || skip == 0){
+                let last = start + count - 1;
+                pages[(count as usize - 1) * PAGE_SIZE..].copy_from_slice(&card.read_page(last)?);
+            }
+            pages[skip..end].copy_from_slice(data);
+            card.write_pages(start, &pages)
+        };
+        //先读后写时，读和写必须是同一张卡片
+        if partial{
+            self.bound(None, merge)
+        }else{
+            merge(self)
+        }
+    }
 
-loop{
-    -> 同步获取UID 发送指令，等待返回
-    -> 同步写入
-    -> 同步读取
-}
+    /// 读取count页的当前内容，base为Cache时优先使用缓存
+    fn current_pages(&mut self, start: u8, count: u8, base: DiffBase) -> Result<Vec<u8>>{
+        if base == DiffBase::Read{
+            let previous = mem::replace(&mut self.use_cache, false);
+            let result = self.read_pages(start, count);
+            self.use_cache = previous;
+            return result;
+        }
+        let mut data = Vec::with_capacity(count as usize * PAGE_SIZE);
+        for i in 0..count{
+            let page = match self.cache.get(start + i){
+                Some(page) if self.bound_uid.is_some() && self.cache.uid() == self.bound_uid.as_deref() => page,
+                _ => self.read_page(start + i)?,
+            };
+            data.extend_from_slice(&page);
+        }
+        Ok(data)
+    }
 
- */
+    /// 差分写入: 和卡片当前内容比较，只写入不同的页，返回实际写入的页数
+    pub fn write_pages_diff(&mut self, start: u8, data: &[u8], base: DiffBase) -> Result<usize>{
+        if !data.len().is_multiple_of(PAGE_SIZE){
+            return Err(anyhow!("数据长度必须是{}的整数倍", PAGE_SIZE));
+        }
+        check_page_range(start, data.len() / PAGE_SIZE)?;
+        self.bound(None, |card| {
+            let current = card.current_pages(start, (data.len() / PAGE_SIZE) as u8, base)?;
+            let changed: Vec<usize> = data.chunks(PAGE_SIZE).zip(current.chunks(PAGE_SIZE))
+                .enumerate().filter(|(_, (new, old))| new != old).map(|(i, _)| i).collect();
+            //内容没有变化的页不写入，不受写保护限制
+            for i in &changed{
+                card.check_writable(start + *i as u8)?;
+            }
+            let mut written = 0;
+            for i in changed{
+                let new = &data[i * PAGE_SIZE..(i + 1) * PAGE_SIZE];
+                let mut page = [0u8; PAGE_SIZE];
+                page.copy_from_slice(new);
+                card.write_page(start + i as u8, &page).map_err(|err| anyhow!("{:?} 已写入{}页", err, written))?;
+                written += 1;
+            }
+            Ok(written)
+        })
+    }
 
- /// 同步发送消息，并等待应答
- fn send_package_and_wait(port:&mut Box<dyn SerialPort>, fn_code: u8, data:&[u8], debug: bool) -> Result<PackageInfo>{
-    //发送
-    send_package(port, fn_code, &data, debug)?;
-    //尝试3次读取对应的回应
-    let mut count = 0;
-    loop{
-        let pkg = read_package(port, debug)?;
-        if pkg.fn_code == fn_code{
-            return Ok(pkg);
+    /// 差分写入: 从字节地址offset开始写入，只写入内容变化的页，返回实际写入的页数
+    pub fn write_bytes_diff(&mut self, offset: usize, data: &[u8], base: DiffBase) -> Result<usize>{
+        if data.is_empty(){
+            return Ok(0);
         }
-        count += 1;
-        if count > 3{
-            break;
+        let (start, count) = page_span(offset, data.len())?;
+        let skip = offset % PAGE_SIZE;
+        self.bound(None, |card| {
+            let mut pages = card.current_pages(start, count, base)?;
+            pages[skip..skip + data.len()].copy_from_slice(data);
+            card.write_pages_diff(start, &pages, DiffBase::Cache)
+        })
+    }
+
+    /// 回读校验: 从字节地址offset读取并和data比较，不一致时返回第一处不一致的页
+    pub fn verify_bytes(&mut self, offset: usize, data: &[u8]) -> Result<()>{
+        let actual = self.read_bytes(offset, data.len())?;
+        match actual.iter().zip(data).position(|(a, b)| a != b){
+            Some(pos) => Err(anyhow!("回读校验失败 第{}页不一致", (offset + pos) / PAGE_SIZE)),
+            None => Ok(()),
         }
     }
-    Err(anyhow!("功能码:{} 应答超时", fn_code))
- }
 
-/// 启动检测线程
-// 返回: Sender
-// 返回: Receiver
-pub fn open_port(dev:String, card_type:CardType, query_delay: u16, debug: bool) -> Result<(Sender<(u8, Vec<u8>)>, Receiver<(u8, bool, Vec<u8>)>)>{
+    /// 旧版写入: 从第4页开始写入，整段数据倒序存储
+    pub fn write_data(&mut self, data: &[u8]) -> Result<()>{
+        let stream = legacy_stream(data)?;
+        self.write_pages(USER_START_PAGE, &stream)
+    }
 
-    info!("打开串口 {} UID检测频率:{}ms card_type={:?}", dev, query_delay, card_type);
+    /// 旧版差分写入，返回实际写入的页数
+    pub fn write_data_diff(&mut self, data: &[u8], base: DiffBase) -> Result<usize>{
+        let stream = legacy_stream(data)?;
+        self.write_pages_diff(USER_START_PAGE, &stream, base)
+    }
 
-    let mut port = serialport::new(dev.clone(), 115_200)
-    .timeout(Duration::from_millis(100))
-    .open()?;
+    /// 旧版读取: 从第4页开始读取len个字节，并倒序还原
+    pub fn read_data(&mut self, len: usize) -> Result<Vec<u8>>{
+        let capacity = (LEGACY_LAST_PAGE - USER_START_PAGE + 1) as usize * PAGE_SIZE;
+        if len > capacity{
+            return Err(anyhow!("读取长度过长 最多{}字节", capacity));
+        }
+        let mut stream = self.read_pages(USER_START_PAGE, len.div_ceil(PAGE_SIZE) as u8)?;
+        stream.truncate(len);
+        //数据都出来是倒置的
+        stream.reverse();
+        Ok(stream)
+    }
 
-    match OPENED.lock(){
-        Ok(mut opened) => *opened = true,
-        Err(err) => error!("{:?}", err)
-    };
+    /// 读取NTAG21x的24位NFC计数器(READ_CNT)。计数器在上电后第一次READ/FAST_READ时加1，
+    /// 需要先读取任意一页；卡片没有打开NFC_CNT_EN时返回失败
+    pub fn read_counter(&mut self) -> Result<u32>{
+        let fn_code = self.read_cnt.ok_or_else(|| anyhow!("读卡器没有配置READ_CNT功能码"))?;
+        let pkg = self.transceive(fn_code, &[NFC_COUNTER_ADDR])?.check("读取NFC计数器")?;
+        if pkg.data.len() < 3{
+            return Err(anyhow!("读取NFC计数器 数据长度错误:{}", pkg.data.len()));
+        }
+        Ok(u32::from_le_bytes([pkg.data[0], pkg.data[1], pkg.data[2], 0]))
+    }
+
+    /// 读取NTAG21x的32字节原厂签名(READ_SIG)
+    pub fn read_signature(&mut self) -> Result<Vec<u8>>{
+        let fn_code = self.read_sig.ok_or_else(|| anyhow!("读卡器没有配置READ_SIG功能码"))?;
+        let pkg = self.transceive(fn_code, &[0x00])?.check("读取原厂签名")?;
+        if pkg.data.len() < SIGNATURE_LEN{
+            return Err(anyhow!("读取原厂签名 数据长度错误:{}", pkg.data.len()));
+        }
+        Ok(pkg.data[..SIGNATURE_LEN].to_vec())
+    }
 
+    /// 设置蜂鸣器
+    pub fn buzzer(&mut self, data: u8) -> Result<()>{
+        self.transceive(FN_CODE_SET_BUZZER, &[data])?.check("蜂鸣器设置")?;
+        Ok(())
+    }
+
+    /// 打开或关闭UID主动上报，读卡器对该命令没有应答
+    pub fn set_uid_report(&mut self, enable: bool) -> Result<()>{
+        self.check_deadline()?;
+        self.conn.send_package(FN_CODE_UID_REPORT_SET, &[if enable { 0x55 } else { 0xAA }])
+    }
+}
+
+/// 字节范围对应的起始页和页数
+fn page_span(offset: usize, len: usize) -> Result<(u8, u8)>{
+    let start = offset / PAGE_SIZE;
+    let end = (offset + len).div_ceil(PAGE_SIZE);
+    if end > 256{
+        return Err(anyhow!("字节范围超出 offset={} len={}", offset, len));
+    }
+    Ok((start as u8, (end - start) as u8))
+}
+
+/// 旧版格式: 整段数据倒序，补0到整页
+fn legacy_stream(data: &[u8]) -> Result<Vec<u8>>{
+    let capacity = (LEGACY_LAST_PAGE - USER_START_PAGE + 1) as usize * PAGE_SIZE;
+    if data.len() > capacity{
+        return Err(anyhow!("数据过长 最多{}字节", capacity));
+    }
+    let mut stream: Vec<u8> = data.iter().rev().copied().collect();
+    stream.resize(data.len().div_ceil(PAGE_SIZE) * PAGE_SIZE, 0);
+    Ok(stream)
+}
+
+/// 检查页范围没有超出单字节页地址
+fn check_page_range(start: u8, count: usize) -> Result<()>{
+    if start as usize + count > 256{
+        return Err(anyhow!("页范围超出 start={} count={}", start, count));
+    }
+    Ok(())
+}
+
+/// 发给串口线程的任务
+pub(crate) struct Job{
+    /// 截止时间，超过后串口线程取消任务
+    pub deadline: Instant,
+    pub run: Box<dyn FnOnce(&mut Card) + Send>,
+}
+
+/// 打开串口设备
+pub(crate) fn open_device(dev: &str) -> Result<Box<dyn SerialPort>>{
+    info!("打开串口 {}", dev);
+    let port = serialport::new(dev, 115_200)
+    .timeout(READ_TIMEOUT)
+    .open()?;
     info!("串口打开成功 {:?}", port.name());
+    Ok(port)
+}
+
+/// 串口线程主循环，线程在状态变为Closing、发送端全部释放或串口丢失时退出，退出时释放串口
+pub(crate) fn run(port: Box<dyn SerialPort>, options: ReaderOptions, jobs: Receiver<Job>, shared: Arc<Shared>){
+    let ReaderOptions { card_type, poll_interval: query_delay, timeout, fast_read, read_cnt, read_sig, debug } = options;
+    let name = port.name().unwrap_or_default();
+    info!("串口线程启动 {} UID检测频率:{:?} card_type={:?}", name, query_delay, card_type);
+    let mut card = Card{
+        conn: Connection::new(port, debug),
+        card_type,
+        deadline: None,
+        bound_uid: None,
+        cache: PageCache::default(),
+        use_cache: false,
+        sent: 0,
+        read_width: 1,
+        fast_read: FastRead{ code: fast_read, reader_ok: true, card_ok: true },
+        read_cnt,
+        read_sig,
+        policy: None,
+    };
+    let status: &StatusCell = &shared.status;
 
     //注意，两条指令不能一起发
 
     //关闭蜂鸣器: 24 06 00 05 01 00 04
     // send_hex(&mut port,"24060005010004")?;
     //关闭主动上报
-    // send_package(&mut port, FN_CODE_UID_REPORT_SET, &[0xAA])?;
+    // card.set_uid_report(false)?;
     //主动上报UID
-    // send_package(&mut port, FN_CODE_UID_REPORT_SET, &[0x55]).unwrap();
-    
-    let mut delay_time = Instant::now();
-    
-    let (port_tx, port_rx) = channel();
-    let (user_tx, user_rx) = channel();
-    thread::spawn(move || {
-        loop{
-            if let Ok(opened) = OPENED.try_lock(){
-                if !*opened{
-                    break;
-                }
-            }
+    // card.set_uid_report(true).unwrap();
 
-            //每隔一定时间发送一次获取UID指令
-            if delay_time.elapsed().as_millis() as u16 >= query_delay{
-                delay_time = Instant::now();
-                match send_package_and_wait(&mut port, card_type.fn_code_read_uid(), &[], debug){
-                    Ok(pkg) => {
-                        if pkg.st_code != ST_CODE_SUCCESS{
-                            if debug{
-                                error!("FN_CODE_READ_UID st_code={}", pkg.st_code);
-                            }
-                            match UID.lock(){
-                                Ok(mut uid) => *uid = None,
-                                Err(err) => error!("UID lock失败:{:?}", err)
-                            };
-                            // if let Err(err) = crate::notify_uid(CMD_READ_UID, false, vec![]){
-                            //     error!("UID通知失败:{}", err);
-                            // }
-                        }else{
-                            if debug{
-                                warn!("UID读取成功:{}", hex::encode(&pkg.data));
-                            }
-                            match UID.lock(){
-                                Ok(mut uid) => *uid = Some(pkg.data.clone()),
-                                Err(err) => error!("UID lock失败:{:?}", err)
-                            };
-                            // if let Err(err) = crate::notify_uid(CMD_READ_UID, true, pkg.data.clone()){
-                            //     error!("UID通知失败:{}", err);
-                            // }
-                        }
-                    }
-                    Err(err) => {
-                        match UID.lock(){
-                            Ok(mut uid) => *uid = None,
-                            Err(err) => error!("UID lock失败:{:?}", err)
-                        };
-                        error!("UID读取失败 {:?}", err);
-                        // if let Err(err) = crate::notify_uid(CMD_READ_UID, false, vec![]){
-                        //     error!("UID通知失败:{}", err);
-                        // }
-                    }
-                }
-            }
+    let mut next_query = Instant::now();
+    //上一次读取到的UID，用于判断新卡片
+    let mut last_uid: Option<Vec<u8>> = None;
+    //连续通信失败次数
+    let mut failures = 0;
+    loop{
+        if status.state() == ReaderState::Closing{
+            break;
+        }
 
-            //接收要发送的命令
-            if let Ok((cmd, data)) = port_rx.try_recv(){
-                let mut data:Vec<u8> = data;
-                //写入块数据
-                if cmd == CMD_WRITE_DATA{
-                    //同步写入每一个数据块
-                    let mut page = 4;//4~39
-                    let mut write_success = true;
-                    while data.len()>0{
-                        let byte4 = &mut [0u8; 4];
-                        if let Some(b1) = data.pop(){
-                            byte4[0] = b1;
-                        }
-                        if let Some(b2) = data.pop(){
-                            byte4[1] = b2;
-                        }
-                        if let Some(b3) = data.pop(){
-                            byte4[2] = b3;
-                        }
-                        if let Some(b4) = data.pop(){
-                            byte4[3] = b4;
-                        }
-                        match write_page(&mut port, &card_type, page, byte4, debug){
-                            Ok(pkg) => {
-                                if pkg.st_code != ST_CODE_SUCCESS{
-                                    error!("CMD_WRITE_DATA st_code={}", pkg.st_code);
-                                    write_success = false;
-                                    break;
-                                }
-                            }
-                            Err(err) => {
-                                info!("CMD_WRITE_DATA {:?}", err);
-                                write_success = false;
-                                break;
-                            }
-                        }
-                        page += 1;
-                        if page>=39{
-                            break;
-                        }
+        //每隔一定时间发送一次获取UID指令
+        if Instant::now() >= next_query{
+            match card.read_uid(){
+                Ok(uid) => {
+                    if failures > 0{
+                        failures = 0;
+                        status.set_degraded(false);
                     }
-                    //返回写入结果
-                    if let Err(err) = user_tx.send((CMD_WRITE_DATA, write_success, vec![])){
-                        error!("消息 发送失败: CMD_WRITE_DATA {:?}", err);
-                    }
-                }else if cmd == CMD_READ_DATA{
-                    let total_read_len = data[0] as usize;
-                    let mut data_read = vec![];
-                    //从第4页开始读取
-                    let mut page_index = 4;
-                    let mut read_success = true;
-                    while data_read.len() < total_read_len{
-                        let fn_code_read_data = card_type.fn_code_read_data();
-                        match send_package_and_wait(&mut port, fn_code_read_data, &[page_index], debug){
-                            Ok(pkg) => {
-                                if pkg.st_code != ST_CODE_SUCCESS{
-                                    error!("CMD_READ_DATA st_code={} fn_code_read_data={:X}", pkg.st_code, fn_code_read_data);
-                                    read_success = false;
-                                    break;
-                                }else{
-                                    let last_count = total_read_len - data_read.len();
-                                    if last_count>=4{
-                                        data_read.extend(pkg.data);
-                                    }else{
-                                        data_read.extend(&pkg.data[0..last_count]);
-                                    }
-                                }
-                            }
-                            Err(err) => {
-                                error!("FN_CODE_READ_DATA {:?}", err);
-                                read_success = false;
-                                break;
-                            }
-                        }
-                        page_index += 1;
-                        //最多读39页
-                        if page_index>39{
-                            if data_read.len() != total_read_len{
-                                read_success = false;
-                            }
-                            break;
+                    if let Some(uid) = &uid{
+                        if debug{
+                            warn!("UID读取成功:{}", hex::encode(uid));
                         }
+                        status.record_uid();
                     }
-                    //通知读取结果
-                    if let Err(err) = user_tx.send((CMD_READ_DATA, read_success, data_read)){
-                        error!("end_read_data 发送失败: CMD_READ_DATA {:?}", err);
-                    }
-                }else if cmd == CMD_SET_BUZZER {
-                    let mut success = false;
-                    if data.len() == 0{
-                        error!("蜂鸣器设置失败 数据为空 data={:?}", data);
-                    }else{
-                        match send_package_and_wait(&mut port, FN_CODE_SET_BUZZER, &[data[0]], debug){
-                            Ok(pkg) => {
-                                if pkg.st_code == ST_CODE_SUCCESS{
-                                    success = true;
-                                }else{
-                                    error!("蜂鸣器设置失败 st_code={}", pkg.st_code);
-                                }
-                            }
-                            Err(err) => {
-                                error!("蜂鸣器设置失败{:?}", err);
-                            }
+                    shared.set_uid(uid.clone());
+                    match &uid{
+                        Some(uid) if last_uid.as_ref() != Some(uid) => {
+                            card.deadline = Some(Instant::now() + timeout);
+                            shared.on_tap(&mut card, uid);
+                            card.deadline = None;
                         }
+                        _ => ()
                     }
-                    if let Err(err) = user_tx.send((FN_CODE_SET_BUZZER, success, vec![])){
-                        error!("发送失败: FN_CODE_SET_BUZZER {:?}", err);
+                    last_uid = uid;
+                }
+                Err(err) => {
+                    shared.set_uid(None);
+                    last_uid = None;
+                    error!("UID读取失败 {:?}", err);
+                    status.record_error(&format!("UID读取失败 {:?}", err));
+                    if is_port_lost(&err){
+                        status.set_state(ReaderState::Error);
+                        break;
+                    }
+                    failures += 1;
+                    if failures >= DEGRADED_THRESHOLD{
+                        status.set_degraded(true);
                     }
-                }else if cmd == CMD_CLOSE_UID_REPORT {
-                    match send_package(&mut port, FN_CODE_UID_REPORT_SET, &[0xAA], debug){
-                        Ok(_) => error!("FN_CODE_UID_REPORT_SET 设置成功"),
-                        Err(err) => error!("FN_CODE_UID_REPORT_SET 设置失败 {:?}", err),
-                    };
-                }else if cmd == CMD_OPEN_UID_REPORT {
-                    match send_package(&mut port, FN_CODE_UID_REPORT_SET, &[0x55], debug){
-                        Ok(_) => error!("FN_CODE_UID_REPORT_SET 设置成功"),
-                        Err(err) => error!("FN_CODE_UID_REPORT_SET 设置失败 {:?}", err),
-                    };
                 }
             }
-            thread::sleep(Duration::from_millis(1));
+            next_query = Instant::now() + query_delay;
         }
-        
-        info!("串口关闭 {}", dev);
-    });
 
-    Ok((port_tx, user_rx))
-}
\ No newline at end of file
+        //等待要执行的任务，有任务时立即唤醒，否则等到下一次读取UID的时间
+        let job = match jobs.recv_timeout(next_query.saturating_duration_since(Instant::now())){
+            Ok(job) => job,
+            Err(RecvTimeoutError::Timeout) => continue,
+            //发送端全部释放，说明串口已关闭
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        card.deadline = Some(job.deadline);
+        (job.run)(&mut card);
+        card.deadline = None;
+    }
+
+    info!("串口关闭 {}", name);
+}
@@ -0,0 +1,144 @@
+use log::{error, warn};
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 读卡器状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ReaderState{
+    /// 串口已关闭
+    Closed,
+    /// 正在打开串口
+    Opening,
+    /// 串口已打开，通信正常
+    Open,
+    /// 串口已打开，但连续通信失败
+    Degraded,
+    /// 正在关闭串口，等待线程退出
+    Closing,
+    /// 串口出错，线程已退出
+    Error,
+}
+
+impl ReaderState{
+    /// 串口是否处于可用状态
+    pub fn is_opened(&self) -> bool{
+        matches!(self, ReaderState::Open | ReaderState::Degraded)
+    }
+}
+
+/// 读卡器状态报告(时间均为UNIX毫秒时间戳)
+#[derive(Debug, Clone, Serialize)]
+pub struct ReaderStatus{
+    /// 当前状态
+    pub state: ReaderState,
+    /// 当前状态开始时间
+    pub state_since: u64,
+    /// 串口名称
+    pub port: Option<String>,
+    /// 最近一次打开成功的时间
+    pub opened_at: Option<u64>,
+    /// 最近一次关闭的时间
+    pub closed_at: Option<u64>,
+    /// 最近一次错误信息
+    pub last_error: Option<String>,
+    /// 最近一次错误时间
+    pub last_error_at: Option<u64>,
+    /// 最近一次读取到UID的时间
+    pub last_uid_at: Option<u64>,
+}
+
+/// 当前UNIX毫秒时间戳
+pub fn now_ms() -> u64{
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+impl ReaderStatus{
+    /// 初始状态
+    pub fn new(port: Option<String>) -> ReaderStatus{
+        ReaderStatus{
+            state: ReaderState::Closed,
+            state_since: now_ms(),
+            port,
+            opened_at: None,
+            closed_at: None,
+            last_error: None,
+            last_error_at: None,
+            last_uid_at: None,
+        }
+    }
+
+    fn transition(&mut self, state: ReaderState){
+        if self.state == state{
+            return;
+        }
+        warn!("读卡器状态 {:?} -> {:?}", self.state, state);
+        let now = now_ms();
+        self.state = state;
+        self.state_since = now;
+        match state{
+            ReaderState::Open if self.opened_at.is_none() => self.opened_at = Some(now),
+            ReaderState::Closed => self.closed_at = Some(now),
+            _ => ()
+        }
+    }
+
+    /// 记录错误
+    pub fn record_error(&mut self, err: &str){
+        self.last_error = Some(err.to_string());
+        self.last_error_at = Some(now_ms());
+    }
+}
+
+/// 读卡器和串口线程共享的状态
+pub struct StatusCell(Mutex<ReaderStatus>);
+
+impl StatusCell{
+    pub fn new(port: Option<String>) -> StatusCell{
+        StatusCell(Mutex::new(ReaderStatus::new(port)))
+    }
+
+    fn update<F: FnOnce(&mut ReaderStatus)>(&self, f: F){
+        match self.0.lock(){
+            Ok(mut status) => f(&mut status),
+            Err(err) => error!("STATUS lock失败:{:?}", err)
+        }
+    }
+
+    /// 获取状态报告
+    pub fn snapshot(&self) -> ReaderStatus{
+        match self.0.lock(){
+            Ok(status) => status.clone(),
+            Err(err) => err.into_inner().clone()
+        }
+    }
+
+    /// 获取当前状态
+    pub fn state(&self) -> ReaderState{
+        self.snapshot().state
+    }
+
+    /// 切换状态
+    pub fn set_state(&self, state: ReaderState){
+        self.update(|status| status.transition(state));
+    }
+
+    /// 通信降级或恢复，只在串口打开期间生效，不会覆盖正在关闭等状态
+    pub fn set_degraded(&self, degraded: bool){
+        self.update(|status| {
+            if status.state.is_opened(){
+                status.transition(if degraded { ReaderState::Degraded } else { ReaderState::Open });
+            }
+        });
+    }
+
+    /// 记录错误
+    pub fn record_error(&self, err: &str){
+        self.update(|status| status.record_error(err));
+    }
+
+    /// 记录UID读取时间
+    pub fn record_uid(&self){
+        self.update(|status| status.last_uid_at = Some(now_ms()));
+    }
+}
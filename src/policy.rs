@@ -0,0 +1,128 @@
+//! 页写保护策略
+//!
+//! 按卡片类型配置哪些页(块)可以通过接口写入。默认禁止写入NTAG/Ultralight的UID、锁定字节、CC、
+//! 动态锁定字节、配置页、密码页和Mifare Classic的扇区尾块，写错这些页可能导致卡片永久不可用。
+//! 无法识别型号的NTAG/Ultralight只允许写入第4~15页，之后的页可能是配置页，需要特权令牌。
+//! 策略由 [`Card::guarded`](crate::Card::guarded) 在每次写页前检查。
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use crate::ntag::{CardType, PageKind, TagModel};
+
+/// 一种卡片的写保护规则
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PagePolicy{
+    /// 禁止写入的页用途
+    #[serde(default)]
+    pub protected_kinds: Vec<PageKind>,
+    /// 额外禁止写入的页范围 [起始页, 结束页]，包含结束页
+    #[serde(default)]
+    pub protected_pages: Vec<[u16; 2]>,
+    /// 允许写入的页范围，为空时不限制
+    #[serde(default)]
+    pub writable_pages: Vec<[u16; 2]>,
+}
+
+fn in_ranges(ranges: &[[u16; 2]], page: u16) -> bool{
+    ranges.iter().any(|[start, end]| (*start..=*end).contains(&page))
+}
+
+impl PagePolicy{
+    /// 检查一页是否允许写入
+    pub fn check(&self, model: TagModel, page: u16) -> Result<()>{
+        let kind = model.page_kind(page);
+        if self.protected_kinds.contains(&kind){
+            return Err(anyhow!("第{}页({:?})受写保护", page, kind));
+        }
+        if in_ranges(&self.protected_pages, page){
+            return Err(anyhow!("第{}页受写保护", page));
+        }
+        if !self.writable_pages.is_empty() && !in_ranges(&self.writable_pages, page){
+            return Err(anyhow!("第{}页不在允许写入的范围内", page));
+        }
+        Ok(())
+    }
+}
+
+/// 按卡片类型配置的写保护策略，没有配置的卡片类型不限制
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct WritePolicy{
+    cards: HashMap<CardType, PagePolicy>,
+}
+
+impl Default for WritePolicy{
+    fn default() -> Self{
+        let mut cards = HashMap::new();
+        cards.insert(CardType::UltraLight, PagePolicy{
+            protected_kinds: vec![
+                PageKind::Uid, PageKind::Lock, PageKind::Cc, PageKind::DynamicLock,
+                PageKind::Config, PageKind::Password, PageKind::Pack, PageKind::Unknown,
+            ],
+            ..Default::default()
+        });
+        cards.insert(CardType::Mifare, PagePolicy{
+            protected_kinds: vec![PageKind::Uid, PageKind::SectorTrailer],
+            ..Default::default()
+        });
+        WritePolicy{ cards }
+    }
+}
+
+impl WritePolicy{
+    /// 不做任何限制的策略
+    pub fn unrestricted() -> WritePolicy{
+        WritePolicy{ cards: HashMap::new() }
+    }
+
+    /// 从JSON文件加载，键为卡片类型，值为 [`PagePolicy`]
+    pub fn load(path: impl AsRef<Path>) -> Result<WritePolicy>{
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("读取写保护策略失败 {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("写保护策略格式错误 {}", path.display()))
+    }
+
+    /// 卡片类型对应的规则
+    pub fn get(&self, card_type: CardType) -> Option<&PagePolicy>{
+        self.cards.get(&card_type)
+    }
+
+    /// 检查一页是否允许写入，model用于确定页的用途
+    pub fn check(&self, card_type: CardType, model: TagModel, page: u16) -> Result<()>{
+        match self.get(card_type){
+            Some(policy) => policy.check(model, page),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn unknown_model_only_allows_first_user_pages(){
+        let policy = WritePolicy::default();
+        for page in 4..16{
+            assert!(policy.check(CardType::UltraLight, TagModel::Unknown, page).is_ok(), "{}", page);
+        }
+        for page in [0, 2, 3, 16, 36, 41, 43, 44]{
+            assert!(policy.check(CardType::UltraLight, TagModel::Unknown, page).is_err(), "{}", page);
+            assert!(policy.check(CardType::UltraLight, TagModel::Ultralight, page).is_err(), "{}", page);
+        }
+    }
+
+    #[test]
+    fn known_model_protects_config_pages(){
+        let policy = WritePolicy::default();
+        assert!(policy.check(CardType::UltraLight, TagModel::Ntag213, 39).is_ok());
+        for page in 40..45{
+            assert!(policy.check(CardType::UltraLight, TagModel::Ntag213, page).is_err(), "{}", page);
+        }
+        assert!(policy.check(CardType::Mifare, TagModel::MifareClassic1k, 7).is_err());
+        assert!(policy.check(CardType::Mifare, TagModel::MifareClassic1k, 8).is_ok());
+        assert!(WritePolicy::unrestricted().check(CardType::UltraLight, TagModel::Unknown, 41).is_ok());
+    }
+}
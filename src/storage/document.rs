@@ -0,0 +1,169 @@
+//! 卡片上的JSON文档
+//!
+//! 文档按CBOR编码，压缩后更短时再用deflate压缩，保存为带长度头的数据(内容类型cbor或cbordeflate)。
+//! 修改时按JSON Merge Patch(RFC 7386)合并，在同一张卡片上读取、合并、只写入变化的页并回读校验。
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use crate::ntag::{Card, DiffBase};
+use super::frame::{try_read_frame, write_frame, ContentType};
+
+/// 解压后的最大长度
+const MAX_INFLATED: usize = 64 * 1024;
+
+/// 写入结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DocumentWrite{
+    /// 保存时使用的内容类型
+    pub content_type: ContentType,
+    /// 编码后的字节数(不含头部)
+    pub size: usize,
+    /// 实际写入的页数
+    pub pages: usize,
+}
+
+/// 把文档编码为CBOR，deflate为空时选择更短的一种
+pub fn encode_document(doc: &Value, deflate: Option<bool>) -> Result<(ContentType, Vec<u8>)>{
+    let mut cbor = Vec::new();
+    ciborium::ser::into_writer(doc, &mut cbor).map_err(|err| anyhow!("CBOR编码失败 {:?}", err))?;
+    if deflate == Some(false){
+        return Ok((ContentType::Cbor, cbor));
+    }
+    let compressed = miniz_oxide::deflate::compress_to_vec(&cbor, 9);
+    if deflate == Some(true) || compressed.len() < cbor.len(){
+        Ok((ContentType::CborDeflate, compressed))
+    }else{
+        Ok((ContentType::Cbor, cbor))
+    }
+}
+
+/// 按内容类型解码文档
+pub fn decode_document(content_type: ContentType, data: &[u8]) -> Result<Value>{
+    let inflated;
+    let cbor = match content_type{
+        ContentType::Cbor => data,
+        ContentType::CborDeflate => {
+            inflated = miniz_oxide::inflate::decompress_to_vec_with_limit(data, MAX_INFLATED)
+                .map_err(|err| anyhow!("数据已损坏 解压失败 {:?}", err))?;
+            &inflated
+        }
+        ContentType::Json => return serde_json::from_slice(data).map_err(|err| anyhow!("数据已损坏 {:?}", err)),
+        other => return Err(anyhow!("卡片上不是JSON文档 内容类型:{:?}", other)),
+    };
+    ciborium::de::from_reader(cbor).map_err(|err| anyhow!("数据已损坏 CBOR解码失败 {:?}", err))
+}
+
+/// 按JSON Merge Patch合并: patch中的null删除字段，对象递归合并，其他值直接替换
+pub fn merge_patch(target: &mut Value, patch: &Value){
+    let patch = match patch{
+        Value::Object(patch) => patch,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object(){
+        *target = Value::Object(Default::default());
+    }
+    if let Value::Object(map) = target{
+        for (key, value) in patch{
+            if value.is_null(){
+                map.remove(key);
+            }else{
+                merge_patch(map.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+/// 读取文档，空白卡片返回null
+pub fn read_document(card: &mut Card) -> Result<Value>{
+    match try_read_frame(card)?{
+        Some(frame) => decode_document(frame.content_type, &frame.data),
+        None => Ok(Value::Null),
+    }
+}
+
+/// 写入整个文档
+pub fn write_document(card: &mut Card, doc: &Value, deflate: Option<bool>, verify: bool) -> Result<DocumentWrite>{
+    let (content_type, data) = encode_document(doc, deflate)?;
+    let pages = write_frame(card, content_type, &data, DiffBase::Read, verify)?;
+    Ok(DocumentWrite{ content_type, size: data.len(), pages })
+}
+
+/// 读取文档并合并patch后写回，返回合并后的文档
+pub fn patch_document(card: &mut Card, patch: &Value, deflate: Option<bool>, verify: bool) -> Result<(Value, DocumentWrite)>{
+    card.bound(None, |card| {
+        let mut doc = read_document(card)?;
+        merge_patch(&mut doc, patch);
+        let (content_type, data) = encode_document(&doc, deflate)?;
+        //刚读取过的页在缓存中，差分时不再读取
+        let pages = write_frame(card, content_type, &data, DiffBase::Cache, verify)?;
+        Ok((doc, DocumentWrite{ content_type, size: data.len(), pages }))
+    })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merge_patch_rfc7386_examples(){
+        //RFC 7386 附录A
+        for (target, patch, result) in [
+            (json!({"a":"b"}), json!({"a":"c"}), json!({"a":"c"})),
+            (json!({"a":"b"}), json!({"b":"c"}), json!({"a":"b","b":"c"})),
+            (json!({"a":"b"}), json!({"a":null}), json!({})),
+            (json!({"a":"b","b":"c"}), json!({"a":null}), json!({"b":"c"})),
+            (json!({"a":["b"]}), json!({"a":"c"}), json!({"a":"c"})),
+            (json!({"a":"c"}), json!({"a":["b"]}), json!({"a":["b"]})),
+            (json!({"a":{"b":"c"}}), json!({"a":{"b":"d","c":null}}), json!({"a":{"b":"d"}})),
+            (json!({"a":[{"b":"c"}]}), json!({"a":[1]}), json!({"a":[1]})),
+            (json!(["a","b"]), json!(["c","d"]), json!(["c","d"])),
+            (json!({"a":"b"}), json!(["c"]), json!(["c"])),
+            (json!({"a":"foo"}), json!(null), json!(null)),
+            (json!({"a":"foo"}), json!("bar"), json!("bar")),
+            (json!({"e":null}), json!({"a":1}), json!({"e":null,"a":1})),
+            (json!([1,2]), json!({"a":"b","c":null}), json!({"a":"b"})),
+            (json!({}), json!({"a":{"bb":{"ccc":null}}}), json!({"a":{"bb":{}}})),
+        ]{
+            let mut doc = target.clone();
+            merge_patch(&mut doc, &patch);
+            assert_eq!(doc, result, "{} + {}", target, patch);
+        }
+    }
+
+    #[test]
+    fn merge_patch_into_blank_card(){
+        let mut doc = Value::Null;
+        merge_patch(&mut doc, &json!({"name":"张三","tags":{"vip":true,"old":null}}));
+        assert_eq!(doc, json!({"name":"张三","tags":{"vip":true}}));
+    }
+
+    #[test]
+    fn document_round_trip(){
+        let doc = json!({"name":"张三","points":1200,"history":vec!["2024-01-01"; 20],"vip":true,"note":null});
+        for (deflate, expected) in [(Some(false), ContentType::Cbor), (Some(true), ContentType::CborDeflate), (None, ContentType::CborDeflate)]{
+            let (content_type, data) = encode_document(&doc, deflate).unwrap();
+            assert_eq!(content_type, expected);
+            assert_eq!(decode_document(content_type, &data).unwrap(), doc);
+        }
+        //很短的文档压缩后更长，自动选择时不压缩
+        let (content_type, data) = encode_document(&json!({"a":1}), None).unwrap();
+        assert_eq!(content_type, ContentType::Cbor);
+        assert_eq!(decode_document(content_type, &data).unwrap(), json!({"a":1}));
+        assert_eq!(decode_document(ContentType::Json, br#"{"a":1}"#).unwrap(), json!({"a":1}));
+    }
+
+    #[test]
+    fn corrupted_documents_are_rejected(){
+        let (_, data) = encode_document(&json!({"a":[1,2,3]}), Some(true)).unwrap();
+        assert!(decode_document(ContentType::CborDeflate, &data[..data.len() / 2]).is_err());
+        assert!(decode_document(ContentType::Cbor, &[0xFF, 0x00]).is_err());
+        assert!(decode_document(ContentType::Binary, &[]).is_err());
+        //解压后超过上限
+        let bomb = miniz_oxide::deflate::compress_to_vec(&vec![0u8; MAX_INFLATED + 1], 9);
+        assert!(decode_document(ContentType::CborDeflate, &bomb).is_err());
+    }
+}
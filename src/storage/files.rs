@@ -0,0 +1,383 @@
+//! 用户数据区中的简单文件系统
+//!
+//! 多个应用共用一张卡片时，每个应用按文件名读写自己的文件，不会互相覆盖。
+//! 从第4页开始依次放两份目录副本，每份是8字节头部
+//! 魔数"FS"(2) | 版本(1) | 最大文件数(1) | 序号 u16 LE | CRC16-XMODEM LE(版本、最大文件数、序号和所有目录项)，
+//! 之后每个文件一个16字节的目录项: 文件名(10，UTF-8，不足时以0填充) | 起始页(1) | 页数(1) |
+//! 文件长度 u16 LE | 文件CRC16-XMODEM LE，文件名全0为空项。两份目录之后到用户数据区末尾是文件数据区。
+//!
+//! 和双槽存储一样，更新目录时总是覆盖较旧的副本，卡片中途离开时读取回退到另一份副本。
+//! 写入文件时先把数据写到空闲的页，再更新目录，写入中途卡片离开时旧文件保持完整；
+//! 空闲的页不够、只能覆盖原文件的位置时，中途离开只会损坏这一个文件。
+
+use anyhow::{anyhow, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use crc16::{State, XMODEM};
+use serde::Serialize;
+use crate::ntag::{Card, CardType, DiffBase, PAGE_SIZE, USER_START_PAGE};
+
+const DIR_MAGIC: [u8; 2] = *b"FS";
+const DIR_VERSION: u8 = 2;
+const DIR_HEADER_LEN: usize = 8;
+const ENTRY_LEN: usize = 16;
+/// 文件名最大字节数
+pub const MAX_NAME_LEN: usize = 10;
+/// 默认最大文件数
+pub const DEFAULT_MAX_FILES: u8 = 8;
+/// 目录的字节地址
+const DIR_OFFSET: usize = USER_START_PAGE as usize * PAGE_SIZE;
+
+/// 目录项
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileEntry{
+    pub name: String,
+    /// 数据起始页
+    pub start_page: u8,
+    /// 占用的页数
+    pub pages: u8,
+    /// 文件长度
+    pub len: u16,
+    /// 文件数据的CRC16-XMODEM
+    pub crc: u16,
+}
+
+impl FileEntry{
+    fn parse(bytes: &[u8]) -> Result<Option<FileEntry>>{
+        let name = &bytes[..MAX_NAME_LEN];
+        if name.iter().all(|b| *b == 0){
+            return Ok(None);
+        }
+        let end = name.iter().rposition(|b| *b != 0).map(|i| i + 1).unwrap_or(0);
+        let name = String::from_utf8(name[..end].to_vec()).map_err(|_| anyhow!("目录已损坏 文件名不是有效的UTF-8"))?;
+        Ok(Some(FileEntry{
+            name,
+            start_page: bytes[10],
+            pages: bytes[11],
+            len: LittleEndian::read_u16(&bytes[12..14]),
+            crc: LittleEndian::read_u16(&bytes[14..16]),
+        }))
+    }
+
+    fn write(&self, bytes: &mut [u8]){
+        bytes.fill(0);
+        bytes[..self.name.len()].copy_from_slice(self.name.as_bytes());
+        bytes[10] = self.start_page;
+        bytes[11] = self.pages;
+        LittleEndian::write_u16(&mut bytes[12..14], self.len);
+        LittleEndian::write_u16(&mut bytes[14..16], self.crc);
+    }
+}
+
+/// 目录
+#[derive(Debug, Clone)]
+struct Directory{
+    entries: Vec<Option<FileEntry>>,
+    /// 用户数据区结束页(不包含)
+    end_page: u16,
+    /// 序号，每次更新目录加1
+    seq: u16,
+    /// 读取到的副本 0 或 1，下次更新写入另一份
+    copy: usize,
+}
+
+impl Directory{
+    /// 一份目录副本占用的字节数，补齐到整页
+    fn copy_size(max_files: usize) -> usize{
+        (DIR_HEADER_LEN + max_files * ENTRY_LEN).div_ceil(PAGE_SIZE) * PAGE_SIZE
+    }
+
+    /// 目录副本的字节地址
+    fn copy_offset(max_files: usize, copy: usize) -> usize{
+        DIR_OFFSET + copy * Directory::copy_size(max_files)
+    }
+
+    /// 文件数据区的起始页
+    fn data_start(&self) -> u16{
+        USER_START_PAGE as u16 + (Directory::copy_size(self.entries.len()) * 2 / PAGE_SIZE) as u16
+    }
+
+    fn to_bytes(&self, seq: u16) -> Vec<u8>{
+        let mut bytes = vec![0u8; Directory::copy_size(self.entries.len())];
+        for (entry, slot) in self.entries.iter().zip(bytes[DIR_HEADER_LEN..].chunks_mut(ENTRY_LEN)){
+            if let Some(entry) = entry{
+                entry.write(slot);
+            }
+        }
+        bytes[..2].copy_from_slice(&DIR_MAGIC);
+        bytes[2] = DIR_VERSION;
+        bytes[3] = self.entries.len() as u8;
+        LittleEndian::write_u16(&mut bytes[4..6], seq);
+        let checksum = dir_crc(&bytes);
+        LittleEndian::write_u16(&mut bytes[6..8], checksum);
+        bytes
+    }
+
+    /// 解析一份目录副本，魔数、版本、最大文件数或CRC不正确时为空
+    fn parse(bytes: &[u8], copy: usize, max_files: usize, end_page: u16) -> Option<Directory>{
+        if bytes.len() < Directory::copy_size(max_files) || bytes[..2] != DIR_MAGIC || bytes[2] != DIR_VERSION || bytes[3] as usize != max_files{
+            return None;
+        }
+        if LittleEndian::read_u16(&bytes[6..8]) != dir_crc(bytes){
+            return None;
+        }
+        let entries = bytes[DIR_HEADER_LEN..DIR_HEADER_LEN + max_files * ENTRY_LEN].chunks(ENTRY_LEN)
+            .map(FileEntry::parse)
+            .collect::<Result<Vec<_>>>().ok()?;
+        Some(Directory{ entries, end_page, seq: LittleEndian::read_u16(&bytes[4..6]), copy })
+    }
+
+    fn find(&self, name: &str) -> Option<usize>{
+        self.entries.iter().position(|entry| entry.as_ref().map(|entry| entry.name == name).unwrap_or(false))
+    }
+
+    /// 查找能放下pages页的空闲位置，exclude的目录项占用的页视为空闲
+    fn allocate(&self, pages: u16, exclude: Option<usize>) -> Option<u16>{
+        let mut used: Vec<(u16, u16)> = self.entries.iter().enumerate()
+            .filter(|(i, _)| Some(*i) != exclude)
+            .filter_map(|(_, entry)| entry.as_ref())
+            .filter(|entry| entry.pages > 0)
+            .map(|entry| (entry.start_page as u16, entry.start_page as u16 + entry.pages as u16))
+            .collect();
+        used.sort();
+        let mut start = self.data_start();
+        for (used_start, used_end) in used{
+            if used_start >= start + pages{
+                break;
+            }
+            start = start.max(used_end);
+        }
+        if start + pages <= self.end_page{ Some(start) }else{ None }
+    }
+}
+
+fn crc(data: &[u8]) -> u16{
+    State::<XMODEM>::calculate(data)
+}
+
+/// 目录副本的CRC，覆盖头部中的版本、最大文件数、序号和之后的所有字节
+fn dir_crc(bytes: &[u8]) -> u16{
+    let mut state = State::<XMODEM>::new();
+    state.update(&bytes[2..6]);
+    state.update(&bytes[DIR_HEADER_LEN..]);
+    state.get()
+}
+
+/// 序号a是否比b新，允许序号回绕
+fn newer(a: u16, b: u16) -> bool{
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+/// 用户数据区结束页，只支持NTAG/Ultralight
+fn user_end_page(card: &mut Card) -> Result<u16>{
+    if card.card_type() != CardType::UltraLight{
+        return Err(anyhow!("文件系统只支持NTAG/Ultralight卡片"));
+    }
+    let model = card.detect_model()?;
+    model.user_pages().map(|(_, end)| end).ok_or_else(|| anyhow!("无法识别卡片型号，CC:{:?}", model))
+}
+
+/// 从两份目录副本中选出有效且较新的一份
+fn select_directory(bytes: &[u8], max_files: usize, end_page: u16) -> Option<Directory>{
+    let size = Directory::copy_size(max_files);
+    let a = Directory::parse(&bytes[..size], 0, max_files, end_page);
+    let b = Directory::parse(&bytes[size..], 1, max_files, end_page);
+    match (a, b){
+        (Some(a), Some(b)) => Some(if newer(b.seq, a.seq){ b }else{ a }),
+        (a, b) => a.or(b),
+    }
+}
+
+/// 读取目录
+fn read_directory(card: &mut Card) -> Result<Directory>{
+    let end_page = user_end_page(card)?;
+    //魔数、版本和最大文件数所在的页格式化之后不再改变，两份副本相同
+    let header = card.read_bytes(DIR_OFFSET, PAGE_SIZE)?;
+    if header[..2] != DIR_MAGIC{
+        return Err(anyhow!("卡片没有文件目录，需要先格式化"));
+    }
+    if header[2] != DIR_VERSION{
+        return Err(anyhow!("不支持的目录版本:{}，需要重新格式化", header[2]));
+    }
+    let max_files = header[3] as usize;
+    let bytes = card.read_bytes(DIR_OFFSET, Directory::copy_size(max_files) * 2)?;
+    select_directory(&bytes, max_files, end_page).ok_or_else(|| anyhow!("目录已损坏 两份目录的CRC都不一致"))
+}
+
+/// 写入一份目录副本，verify为true时回读校验
+fn write_copy(card: &mut Card, dir: &Directory, copy: usize, seq: u16, verify: bool) -> Result<()>{
+    let bytes = dir.to_bytes(seq);
+    let offset = Directory::copy_offset(dir.entries.len(), copy);
+    card.write_bytes_diff(offset, &bytes, DiffBase::Cache)?;
+    if verify{
+        card.verify_bytes(offset, &bytes)?;
+    }
+    Ok(())
+}
+
+/// 更新目录: 序号加1写入另一份副本，读取到的副本保持不变
+fn write_directory(card: &mut Card, dir: &Directory, verify: bool) -> Result<()>{
+    write_copy(card, dir, 1 - dir.copy, dir.seq.wrapping_add(1), verify)
+}
+
+/// 格式化: 写入空目录，原有的文件全部丢失
+pub fn format_files(card: &mut Card, max_files: u8) -> Result<()>{
+    if max_files == 0{
+        return Err(anyhow!("最大文件数不能为0"));
+    }
+    card.bound(None, |card| {
+        let dir = Directory{ entries: vec![None; max_files as usize], end_page: user_end_page(card)?, seq: 0, copy: 0 };
+        if dir.data_start() >= dir.end_page{
+            return Err(anyhow!("目录过大 {}个文件的两份目录超出用户数据区", max_files));
+        }
+        write_copy(card, &dir, 0, 0, true)?;
+        write_copy(card, &dir, 1, 0, true)
+    })
+}
+
+/// 列出所有文件
+pub fn list_files(card: &mut Card) -> Result<Vec<FileEntry>>{
+    card.bound(None, |card| Ok(read_directory(card)?.entries.into_iter().flatten().collect()))
+}
+
+/// 读取文件，CRC不一致时返回失败
+pub fn read_file(card: &mut Card, name: &str) -> Result<Vec<u8>>{
+    card.bound(None, |card| {
+        let dir = read_directory(card)?;
+        let entry = dir.find(name).and_then(|i| dir.entries[i].clone()).ok_or_else(|| anyhow!("文件不存在:{}", name))?;
+        let data = card.read_bytes(entry.start_page as usize * PAGE_SIZE, entry.len as usize)?;
+        if crc(&data) != entry.crc{
+            return Err(anyhow!("文件已损坏 CRC不一致:{}", name));
+        }
+        Ok(data)
+    })
+}
+
+/// 写入文件，同名文件存在时替换；优先写到空闲的页，空间不足时覆盖原文件的位置
+pub fn write_file(card: &mut Card, name: &str, data: &[u8], verify: bool) -> Result<FileEntry>{
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains('\0'){
+        return Err(anyhow!("文件名长度需要在1~{}字节之间", MAX_NAME_LEN));
+    }
+    if data.len() > u16::MAX as usize{
+        return Err(anyhow!("文件过长"));
+    }
+    card.bound(None, |card| {
+        let mut dir = read_directory(card)?;
+        let existing = dir.find(name);
+        let slot = match existing.or_else(|| dir.entries.iter().position(|entry| entry.is_none())){
+            Some(slot) => slot,
+            None => return Err(anyhow!("文件数已满 最多{}个", dir.entries.len())),
+        };
+        let pages = data.len().div_ceil(PAGE_SIZE) as u16;
+        let start = dir.allocate(pages, None)
+            .or_else(|| existing.and_then(|i| dir.allocate(pages, Some(i))))
+            .ok_or_else(|| anyhow!("空间不足 需要{}页", pages))?;
+
+        let mut padded = data.to_vec();
+        padded.resize(pages as usize * PAGE_SIZE, 0);
+        if !padded.is_empty(){
+            card.write_pages(start as u8, &padded)?;
+            if verify{
+                card.verify_bytes(start as usize * PAGE_SIZE, &padded)?;
+            }
+        }
+        let entry = FileEntry{ name: name.to_string(), start_page: start as u8, pages: pages as u8, len: data.len() as u16, crc: crc(data) };
+        dir.entries[slot] = Some(entry.clone());
+        write_directory(card, &dir, verify)?;
+        Ok(entry)
+    })
+}
+
+/// 删除文件，文件不存在时返回false
+pub fn delete_file(card: &mut Card, name: &str, verify: bool) -> Result<bool>{
+    card.bound(None, |card| {
+        let mut dir = read_directory(card)?;
+        match dir.find(name){
+            Some(slot) => {
+                dir.entries[slot] = None;
+                write_directory(card, &dir, verify)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn directory(names: &[&str]) -> Directory{
+        let mut entries = vec![None; 4];
+        for (i, name) in names.iter().enumerate(){
+            entries[i] = Some(FileEntry{ name: name.to_string(), start_page: 20 + i as u8, pages: 1, len: 3, crc: 0x1234 });
+        }
+        Directory{ entries, end_page: 40, seq: 0, copy: 0 }
+    }
+
+    fn names(dir: &Directory) -> Vec<String>{
+        dir.entries.iter().flatten().map(|entry| entry.name.clone()).collect()
+    }
+
+    #[test]
+    fn copy_round_trip(){
+        let bytes = directory(&["a", "config"]).to_bytes(7);
+        assert_eq!(bytes.len() % PAGE_SIZE, 0);
+        let dir = Directory::parse(&bytes, 1, 4, 40).unwrap();
+        assert_eq!(dir.seq, 7);
+        assert_eq!(dir.copy, 1);
+        assert_eq!(names(&dir), ["a", "config"]);
+        assert_eq!(dir.entries[1].as_ref().unwrap().start_page, 21);
+    }
+
+    #[test]
+    fn header_is_covered_by_crc(){
+        let mut bytes = directory(&["a"]).to_bytes(1);
+        bytes[4] ^= 1;
+        assert!(Directory::parse(&bytes, 0, 4, 40).is_none());
+        let mut bytes = directory(&["a"]).to_bytes(1);
+        bytes[3] = 3;
+        assert!(Directory::parse(&bytes, 0, 3, 40).is_none());
+    }
+
+    #[test]
+    fn selects_newer_copy(){
+        let mut bytes = directory(&["old"]).to_bytes(1);
+        bytes.extend(directory(&["old", "new"]).to_bytes(2));
+        let dir = select_directory(&bytes, 4, 40).unwrap();
+        assert_eq!((dir.copy, dir.seq), (1, 2));
+        assert_eq!(names(&dir), ["old", "new"]);
+    }
+
+    #[test]
+    fn torn_write_falls_back_to_other_copy(){
+        let size = Directory::copy_size(4);
+        let mut bytes = directory(&["old"]).to_bytes(1);
+        let mut torn = directory(&["old", "new"]).to_bytes(2);
+        //头部已写入，后面的目录项还没有写入
+        torn[PAGE_SIZE * 2..].fill(0);
+        bytes.extend(torn);
+        let dir = select_directory(&bytes, 4, 40).unwrap();
+        assert_eq!((dir.copy, dir.seq), (0, 1));
+        assert_eq!(names(&dir), ["old"]);
+
+        bytes[size + 8..].fill(0xFF);
+        bytes[8] ^= 0xFF;
+        assert!(select_directory(&bytes, 4, 40).is_none());
+    }
+
+    #[test]
+    fn sequence_wraps(){
+        assert!(newer(0, u16::MAX));
+        assert!(!newer(u16::MAX, 0));
+        let mut bytes = directory(&["old"]).to_bytes(u16::MAX);
+        bytes.extend(directory(&["new"]).to_bytes(0));
+        assert_eq!(names(&select_directory(&bytes, 4, 40).unwrap()), ["new"]);
+    }
+
+    #[test]
+    fn data_starts_after_both_copies(){
+        let dir = Directory{ end_page: 130, ..directory(&[]) };
+        assert_eq!(dir.data_start(), USER_START_PAGE as u16 + 2 * (Directory::copy_size(4) / PAGE_SIZE) as u16);
+        assert_eq!(dir.allocate(2, None), Some(dir.data_start()));
+    }
+}
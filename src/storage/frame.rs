@@ -0,0 +1,265 @@
+//! 带长度头的数据格式
+//!
+//! 从第4页开始按原始顺序存储，8字节头部加数据:
+//! 魔数"XF"(2) | 版本(1) | 内容类型(1) | 数据长度 u16 LE | CRC16-XMODEM LE(版本、类型、长度和数据)。
+//! 内容类型的最高位为1时数据后面紧跟签名。读取时不需要知道数据长度，CRC不一致时报告数据已损坏。
+
+use anyhow::{anyhow, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use crc16::{State, XMODEM};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use crate::ntag::{Card, CardType, DiffBase, PAGE_SIZE, USER_START_PAGE};
+
+const FRAME_MAGIC: [u8; 2] = *b"XF";
+/// 当前格式版本
+pub const FRAME_VERSION: u8 = 1;
+/// 头部长度
+pub const FRAME_HEADER_LEN: usize = 8;
+/// 内容类型字节中表示有签名的位
+const FRAME_SIGNED: u8 = 0x80;
+/// 数据在卡片上的字节地址
+const FRAME_OFFSET: usize = USER_START_PAGE as usize * PAGE_SIZE;
+
+/// 数据的内容类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentType{
+    /// 任意字节
+    #[default]
+    Binary,
+    /// UTF-8文本
+    Text,
+    /// JSON文本
+    Json,
+    /// CBOR编码的JSON文档
+    Cbor,
+    /// deflate压缩的CBOR
+    CborDeflate,
+    /// 加密后的数据，原内容类型在加密数据中
+    Encrypted,
+}
+
+impl ContentType{
+    pub fn to_u8(self) -> u8{
+        match self{
+            ContentType::Binary => 0,
+            ContentType::Text => 1,
+            ContentType::Json => 2,
+            ContentType::Cbor => 3,
+            ContentType::CborDeflate => 4,
+            ContentType::Encrypted => 5,
+        }
+    }
+
+    pub fn from_u8(code: u8) -> Option<ContentType>{
+        match code{
+            0 => Some(ContentType::Binary),
+            1 => Some(ContentType::Text),
+            2 => Some(ContentType::Json),
+            3 => Some(ContentType::Cbor),
+            4 => Some(ContentType::CborDeflate),
+            5 => Some(ContentType::Encrypted),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ContentType{
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self>{
+        match s.to_ascii_lowercase().as_str(){
+            "binary" => Ok(ContentType::Binary),
+            "text" => Ok(ContentType::Text),
+            "json" => Ok(ContentType::Json),
+            "cbor" => Ok(ContentType::Cbor),
+            "cbordeflate" => Ok(ContentType::CborDeflate),
+            _ => Err(anyhow!("未知的内容类型:{} 可选 binary, text, json, cbor, cbordeflate", s))
+        }
+    }
+}
+
+/// 读取到的数据
+#[derive(Debug, Clone)]
+pub struct Frame{
+    pub content_type: ContentType,
+    pub data: Vec<u8>,
+    /// 数据头中标记了数据后面有签名
+    pub signed: bool,
+}
+
+impl Frame{
+    /// 卡片上保存的完整字节，包含签名标记
+    pub fn to_bytes(&self) -> Result<Vec<u8>>{
+        let mut bytes = encode_frame(self.content_type, &self.data)?;
+        if self.signed{
+            mark_signed(&mut bytes)?;
+        }
+        Ok(bytes)
+    }
+}
+
+fn crc(header: &[u8], data: &[u8]) -> u16{
+    let mut state = State::<XMODEM>::new();
+    state.update(header);
+    state.update(data);
+    state.get()
+}
+
+/// 加上头部，返回写入卡片的完整字节
+pub fn encode_frame(content_type: ContentType, data: &[u8]) -> Result<Vec<u8>>{
+    if data.len() > u16::MAX as usize{
+        return Err(anyhow!("数据过长 最多{}字节", u16::MAX));
+    }
+    let mut bytes = vec![0u8; FRAME_HEADER_LEN];
+    bytes[..2].copy_from_slice(&FRAME_MAGIC);
+    bytes[2] = FRAME_VERSION;
+    bytes[3] = content_type.to_u8();
+    LittleEndian::write_u16(&mut bytes[4..6], data.len() as u16);
+    let checksum = crc(&bytes[2..6], data);
+    LittleEndian::write_u16(&mut bytes[6..8], checksum);
+    bytes.extend_from_slice(data);
+    Ok(bytes)
+}
+
+/// 在encode_frame的结果中标记数据后面有签名，并重新计算CRC
+pub fn mark_signed(bytes: &mut [u8]) -> Result<()>{
+    if bytes.len() < FRAME_HEADER_LEN || bytes[..2] != FRAME_MAGIC{
+        return Err(anyhow!("不是带长度头的数据"));
+    }
+    bytes[3] |= FRAME_SIGNED;
+    let checksum = crc(&bytes[2..6], &bytes[FRAME_HEADER_LEN..]);
+    LittleEndian::write_u16(&mut bytes[6..8], checksum);
+    Ok(())
+}
+
+/// 解析头部，返回内容类型、是否有签名和数据长度，没有魔数时为空
+fn parse_header(header: &[u8]) -> Result<Option<(ContentType, bool, usize)>>{
+    if header.len() < FRAME_HEADER_LEN || header[..2] != FRAME_MAGIC{
+        return Ok(None);
+    }
+    if header[2] != FRAME_VERSION{
+        return Err(anyhow!("不支持的数据格式版本:{}", header[2]));
+    }
+    let code = header[3] & !FRAME_SIGNED;
+    let content_type = ContentType::from_u8(code).ok_or_else(|| anyhow!("数据已损坏 未知的内容类型:{}", code))?;
+    Ok(Some((content_type, header[3] & FRAME_SIGNED != 0, LittleEndian::read_u16(&header[4..6]) as usize)))
+}
+
+/// 从第4页开始读取带长度头的数据，没有数据头或CRC不一致时返回失败
+pub fn read_frame(card: &mut Card) -> Result<Frame>{
+    try_read_frame(card)?.ok_or_else(|| anyhow!("没有带长度头的数据，请指定len"))
+}
+
+/// 从第4页开始读取带长度头的数据，没有数据头(空白卡片)时为空，CRC不一致时返回失败
+pub fn try_read_frame(card: &mut Card) -> Result<Option<Frame>>{
+    card.bound(None, |card| {
+        let header = card.read_bytes(FRAME_OFFSET, FRAME_HEADER_LEN)?;
+        let (content_type, signed, len) = match parse_header(&header)?{
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let data = card.read_bytes(FRAME_OFFSET + FRAME_HEADER_LEN, len)
+            .map_err(|err| anyhow!("数据已损坏 长度:{} {:?}", len, err))?;
+        if LittleEndian::read_u16(&header[6..8]) != crc(&header[2..6], &data){
+            return Err(anyhow!("数据已损坏 CRC不一致 长度:{}", len));
+        }
+        Ok(Some(Frame{ content_type, data, signed }))
+    })
+}
+
+/// 写入带长度头的数据，NTAG卡片先检查用户数据区的容量；只写入内容变化的页，返回实际写入的页数
+pub fn write_frame(card: &mut Card, content_type: ContentType, data: &[u8], base: DiffBase, verify: bool) -> Result<usize>{
+    let bytes = encode_frame(content_type, data)?;
+    card.bound(None, |card| {
+        if card.card_type() == CardType::UltraLight{
+            if let Some((_, end)) = card.detect_model()?.user_pages(){
+                let capacity = (end as usize * PAGE_SIZE).saturating_sub(FRAME_OFFSET);
+                if bytes.len() > capacity{
+                    return Err(anyhow!("数据过长 编码后{}字节 卡片最多{}字节", bytes.len(), capacity));
+                }
+            }
+        }
+        let written = card.write_bytes_diff(FRAME_OFFSET, &bytes, base)?;
+        if verify{
+            card.verify_bytes(FRAME_OFFSET, &bytes)?;
+        }
+        Ok(written)
+    })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> Result<Option<Frame>>{
+        Ok(parse_header(bytes)?.map(|(content_type, signed, len)| {
+            let data = bytes[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len].to_vec();
+            assert_eq!(LittleEndian::read_u16(&bytes[6..8]), crc(&bytes[2..6], &data));
+            Frame{ content_type, data, signed }
+        }))
+    }
+
+    #[test]
+    fn frame_round_trip(){
+        for content_type in [ContentType::Binary, ContentType::Text, ContentType::Json, ContentType::Cbor, ContentType::CborDeflate, ContentType::Encrypted]{
+            assert_eq!(ContentType::from_u8(content_type.to_u8()), Some(content_type));
+            let bytes = encode_frame(content_type, b"{\"a\":1}").unwrap();
+            assert_eq!(bytes.len(), FRAME_HEADER_LEN + 7);
+            let frame = parse(&bytes).unwrap().unwrap();
+            assert_eq!((frame.content_type, frame.data.as_slice()), (content_type, b"{\"a\":1}".as_slice()));
+        }
+    }
+
+    #[test]
+    fn header_is_checked(){
+        let bytes = encode_frame(ContentType::Text, b"abc").unwrap();
+        //空白卡片没有魔数
+        assert!(parse_header(&[0; FRAME_HEADER_LEN]).unwrap().is_none());
+        assert!(parse_header(&bytes[..FRAME_HEADER_LEN - 1]).unwrap().is_none());
+        let mut version = bytes.clone();
+        version[2] = FRAME_VERSION + 1;
+        assert!(parse_header(&version).is_err());
+        let mut content_type = bytes.clone();
+        content_type[3] = 0xFF;
+        assert!(parse_header(&content_type).is_err());
+    }
+
+    #[test]
+    fn crc_covers_length_and_data(){
+        let bytes = encode_frame(ContentType::Binary, &[1, 2, 3, 4]).unwrap();
+        let expected = LittleEndian::read_u16(&bytes[6..8]);
+        let mut data = bytes.clone();
+        data[FRAME_HEADER_LEN] ^= 0x80;
+        assert_ne!(crc(&data[2..6], &data[FRAME_HEADER_LEN..]), expected);
+        let mut len = bytes.clone();
+        LittleEndian::write_u16(&mut len[4..6], 3);
+        assert_ne!(crc(&len[2..6], &len[FRAME_HEADER_LEN..FRAME_HEADER_LEN + 3]), expected);
+    }
+
+    #[test]
+    fn signed_flag_round_trip(){
+        let mut bytes = encode_frame(ContentType::Json, b"{}").unwrap();
+        assert!(!parse(&bytes).unwrap().unwrap().signed);
+        mark_signed(&mut bytes).unwrap();
+        let frame = parse(&bytes).unwrap().unwrap();
+        assert!(frame.signed);
+        assert_eq!(frame.content_type, ContentType::Json);
+        assert_eq!(frame.to_bytes().unwrap(), bytes);
+        assert!(mark_signed(&mut [0; FRAME_HEADER_LEN]).is_err());
+    }
+
+    #[test]
+    fn oversized_data_is_rejected(){
+        assert!(encode_frame(ContentType::Binary, &vec![0; u16::MAX as usize]).is_ok());
+        assert!(encode_frame(ContentType::Binary, &vec![0; u16::MAX as usize + 1]).is_err());
+    }
+
+    #[test]
+    fn content_type_names(){
+        assert_eq!("CborDeflate".parse::<ContentType>().unwrap(), ContentType::CborDeflate);
+        assert!("encrypted".parse::<ContentType>().is_err());
+        assert!("xml".parse::<ContentType>().is_err());
+    }
+}
@@ -0,0 +1,646 @@
+//! 按字段定义的卡片数据布局
+//!
+//! 在配置中声明字段名、类型和偏移，例如 `member_id: u32 at 0`、`name: utf8[16] at 4`、
+//! `expires: date at 20`，读写时按字段名转换为JSON，不需要调用方处理字节顺序。
+//! 偏移从布局的起始页开始计算。
+
+use anyhow::{anyhow, Context, Result};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use crate::ntag::{Card, CardType, DiffBase, PAGE_SIZE, USER_START_PAGE};
+
+/// 字段类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType{
+    U8,
+    U16,
+    U32,
+    U64,
+    I8,
+    I16,
+    I32,
+    I64,
+    /// 1字节，0为false
+    Bool,
+    /// UTF-8文本，长度为len，不足时以0填充
+    Utf8,
+    /// 任意字节，长度为len，JSON中为hex字符串
+    Bytes,
+    /// 日期 "YYYY-MM-DD"，4字节: 年 u16、月、日，全0为null
+    Date,
+}
+
+impl FieldType{
+    /// 固定长度类型的字节数
+    fn fixed_size(&self) -> Option<usize>{
+        match self{
+            FieldType::U8 | FieldType::I8 | FieldType::Bool => Some(1),
+            FieldType::U16 | FieldType::I16 => Some(2),
+            FieldType::U32 | FieldType::I32 | FieldType::Date => Some(4),
+            FieldType::U64 | FieldType::I64 => Some(8),
+            FieldType::Utf8 | FieldType::Bytes => None,
+        }
+    }
+}
+
+/// 多字节整数的字节顺序
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endian{
+    #[default]
+    Little,
+    Big,
+}
+
+/// 一个字段
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Field{
+    pub name: String,
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    /// 相对布局起始页的字节偏移
+    pub offset: usize,
+    /// utf8、bytes 的字节数
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub len: Option<usize>,
+    #[serde(default)]
+    pub endian: Endian,
+}
+
+impl Field{
+    /// 占用的字节数
+    pub fn size(&self) -> usize{
+        self.field_type.fixed_size().or(self.len).unwrap_or(0)
+    }
+
+    /// 从布局数据中解码
+    fn decode(&self, bytes: &[u8]) -> Value{
+        let b = &bytes[self.offset..self.offset + self.size()];
+        let big = self.endian == Endian::Big;
+        macro_rules! int{
+            ($read:ident) => {
+                if big{ BigEndian::$read(b) }else{ LittleEndian::$read(b) }
+            }
+        }
+        match self.field_type{
+            FieldType::U8 => json_num(b[0]),
+            FieldType::I8 => json_num(b[0] as i8),
+            FieldType::Bool => Value::Bool(b[0] != 0),
+            FieldType::U16 => json_num(int!(read_u16)),
+            FieldType::I16 => json_num(int!(read_i16)),
+            FieldType::U32 => json_num(int!(read_u32)),
+            FieldType::I32 => json_num(int!(read_i32)),
+            FieldType::U64 => json_num(int!(read_u64)),
+            FieldType::I64 => json_num(int!(read_i64)),
+            FieldType::Utf8 => {
+                let end = b.iter().rposition(|c| *c != 0).map(|i| i + 1).unwrap_or(0);
+                Value::String(String::from_utf8_lossy(&b[..end]).into_owned())
+            }
+            FieldType::Bytes => Value::String(hex::encode(b)),
+            FieldType::Date => {
+                let year = int!(read_u16);
+                if year == 0 && b[2] == 0 && b[3] == 0{
+                    Value::Null
+                }else{
+                    Value::String(format!("{:04}-{:02}-{:02}", year, b[2], b[3]))
+                }
+            }
+        }
+    }
+
+    /// 把JSON值编码到布局数据中
+    fn encode(&self, bytes: &mut [u8], value: &Value) -> Result<()>{
+        let size = self.size();
+        let b = &mut bytes[self.offset..self.offset + size];
+        let big = self.endian == Endian::Big;
+        macro_rules! int{
+            ($write:ident, $ty:ty, $value:expr) => {{
+                let v = <$ty>::try_from($value).map_err(|_| anyhow!("字段{}超出{:?}的范围", self.name, self.field_type))?;
+                if big{ BigEndian::$write(b, v) }else{ LittleEndian::$write(b, v) }
+            }}
+        }
+        let unsigned = || value.as_u64().ok_or_else(|| anyhow!("字段{}需要非负整数", self.name));
+        let signed = || value.as_i64().ok_or_else(|| anyhow!("字段{}需要整数", self.name));
+        match self.field_type{
+            FieldType::U8 => b[0] = u8::try_from(unsigned()?).map_err(|_| anyhow!("字段{}超出U8的范围", self.name))?,
+            FieldType::I8 => b[0] = i8::try_from(signed()?).map_err(|_| anyhow!("字段{}超出I8的范围", self.name))? as u8,
+            FieldType::Bool => b[0] = value.as_bool().ok_or_else(|| anyhow!("字段{}需要true或false", self.name))? as u8,
+            FieldType::U16 => int!(write_u16, u16, unsigned()?),
+            FieldType::I16 => int!(write_i16, i16, signed()?),
+            FieldType::U32 => int!(write_u32, u32, unsigned()?),
+            FieldType::I32 => int!(write_i32, i32, signed()?),
+            FieldType::U64 => int!(write_u64, u64, unsigned()?),
+            FieldType::I64 => int!(write_i64, i64, signed()?),
+            FieldType::Utf8 => {
+                let text = value.as_str().ok_or_else(|| anyhow!("字段{}需要字符串", self.name))?;
+                if text.len() > size{
+                    return Err(anyhow!("字段{}过长 最多{}字节 实际{}字节", self.name, size, text.len()));
+                }
+                b.fill(0);
+                b[..text.len()].copy_from_slice(text.as_bytes());
+            }
+            FieldType::Bytes => {
+                let data = value.as_str().map(hex::decode)
+                    .ok_or_else(|| anyhow!("字段{}需要hex字符串", self.name))?
+                    .map_err(|err| anyhow!("字段{}格式错误 {:?}", self.name, err))?;
+                if data.len() != size{
+                    return Err(anyhow!("字段{}长度错误 需要{}字节", self.name, size));
+                }
+                b.copy_from_slice(&data);
+            }
+            FieldType::Date => match value{
+                Value::Null => b.fill(0),
+                Value::String(text) => {
+                    let (year, month, day) = parse_date(text).ok_or_else(|| anyhow!("字段{}日期格式错误:{} 需要YYYY-MM-DD", self.name, text))?;
+                    if big{ BigEndian::write_u16(&mut b[..2], year) }else{ LittleEndian::write_u16(&mut b[..2], year) }
+                    b[2] = month;
+                    b[3] = day;
+                }
+                _ => return Err(anyhow!("字段{}需要日期字符串或null", self.name)),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn json_num<T: Into<serde_json::Number>>(n: T) -> Value{
+    Value::Number(n.into())
+}
+
+/// 解析 "YYYY-MM-DD"
+fn parse_date(text: &str) -> Option<(u16, u8, u8)>{
+    let mut parts = text.splitn(3, '-');
+    let year: u16 = parts.next()?.parse().ok()?;
+    let month: u8 = parts.next()?.parse().ok()?;
+    let day: u8 = parts.next()?.parse().ok()?;
+    let leap = year.is_multiple_of(4) && !year.is_multiple_of(100) || year.is_multiple_of(400);
+    let days = match month{
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return None,
+    };
+    if year == 0 || day == 0 || day > days{
+        return None;
+    }
+    Some((year, month, day))
+}
+
+/// 一组字段占用的字节数
+fn fields_size(fields: &[Field]) -> usize{
+    fields.iter().map(|field| field.offset + field.size()).max().unwrap_or(0)
+}
+
+fn find_field<'a>(fields: &'a [Field], name: &str) -> Option<&'a Field>{
+    fields.iter().find(|field| field.name == name)
+}
+
+/// 检查字段名不重复、长度完整、字段之间以及和版本字节之间没有重叠
+fn check_fields(fields: &[Field], version_offset: Option<usize>) -> Result<()>{
+    let size = fields_size(fields).max(version_offset.map(|offset| offset + 1).unwrap_or(0));
+    let mut used: Vec<Option<&str>> = vec![None; size];
+    if let Some(offset) = version_offset{
+        used[offset] = Some("版本字节");
+    }
+    for field in fields{
+        if fields.iter().filter(|f| f.name == field.name).count() > 1{
+            return Err(anyhow!("字段名重复:{}", field.name));
+        }
+        if field.field_type.fixed_size().is_none() && field.len.unwrap_or(0) == 0{
+            return Err(anyhow!("字段{}需要指定len", field.name));
+        }
+        for slot in &mut used[field.offset..field.offset + field.size()]{
+            if let Some(other) = slot{
+                return Err(anyhow!("字段{}和{}重叠", field.name, other));
+            }
+            *slot = Some(&field.name);
+        }
+    }
+    Ok(())
+}
+
+/// 把布局数据解码为JSON，names为空时返回所有字段
+fn decode_fields(fields: &[Field], bytes: &[u8], names: Option<&[String]>) -> Result<Map<String, Value>>{
+    let mut values = Map::new();
+    match names{
+        Some(names) => for name in names{
+            let field = find_field(fields, name).ok_or_else(|| anyhow!("未定义的字段:{}", name))?;
+            values.insert(field.name.clone(), field.decode(bytes));
+        },
+        None => for field in fields{
+            values.insert(field.name.clone(), field.decode(bytes));
+        },
+    }
+    Ok(values)
+}
+
+/// 把JSON中的字段编码到布局数据中，没有出现的字段保持不变
+fn encode_fields(fields: &[Field], bytes: &mut [u8], values: &Map<String, Value>) -> Result<()>{
+    for (name, value) in values{
+        let field = find_field(fields, name).ok_or_else(|| anyhow!("未定义的字段:{}", name))?;
+        field.encode(bytes, value)?;
+    }
+    Ok(())
+}
+
+/// 旧版本的字段定义
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LayoutVersion{
+    pub version: u8,
+    pub fields: Vec<Field>,
+}
+
+/// 从一个版本升级到另一个版本的规则
+///
+/// 新版本中和旧版本同名(或按rename对应)的字段按新的偏移和类型重新编码，
+/// 旧版本中没有的字段使用defaults中的值，都没有时为0
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Migration{
+    pub from: u8,
+    pub to: u8,
+    /// 新字段名 -> 旧字段名
+    #[serde(default)]
+    pub rename: HashMap<String, String>,
+    /// 新增字段的默认值
+    #[serde(default)]
+    pub defaults: Map<String, Value>,
+}
+
+/// 一种卡片的字段布局
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CardLayout{
+    /// 布局的起始页
+    #[serde(default = "default_start_page")]
+    pub start_page: u8,
+    /// 当前版本，设置后卡片在version_offset处保存1字节的版本号，0表示空白卡片
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u8>,
+    /// 版本字节相对起始页的偏移
+    #[serde(default)]
+    pub version_offset: usize,
+    pub fields: Vec<Field>,
+    /// 旧版本的字段定义
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<LayoutVersion>,
+    /// 升级规则，没有规则的旧版本直接按同名字段升级到当前版本
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub migrations: Vec<Migration>,
+}
+
+fn default_start_page() -> u8{
+    USER_START_PAGE
+}
+
+/// 布局迁移结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LayoutOutcome{
+    /// 已从旧版本升级
+    Migrated{ from: u8, to: u8 },
+    /// 已是当前版本
+    Current,
+    /// 空白卡片(版本为0)，未做修改
+    Blank,
+    /// 布局没有版本
+    Unversioned,
+}
+
+impl CardLayout{
+    /// 布局占用的字节数，包含所有版本和版本字节
+    pub fn size(&self) -> usize{
+        let mut size = fields_size(&self.fields);
+        for old in &self.history{
+            size = size.max(fields_size(&old.fields));
+        }
+        if self.version.is_some(){
+            size = size.max(self.version_offset + 1);
+        }
+        size
+    }
+
+    /// 起始字节地址
+    fn offset(&self) -> usize{
+        self.start_page as usize * PAGE_SIZE
+    }
+
+    pub fn field(&self, name: &str) -> Option<&Field>{
+        find_field(&self.fields, name)
+    }
+
+    /// 版本对应的字段定义
+    fn fields_of(&self, version: u8) -> Option<&[Field]>{
+        if self.version == Some(version){
+            return Some(&self.fields);
+        }
+        self.history.iter().find(|old| old.version == version).map(|old| old.fields.as_slice())
+    }
+
+    /// 布局数据中的版本号，布局没有版本时为空
+    pub fn card_version(&self, bytes: &[u8]) -> Option<u8>{
+        self.version.map(|_| bytes[self.version_offset])
+    }
+
+    /// 检查每个版本的字段和升级规则
+    pub fn check(&self) -> Result<()>{
+        let version_offset = self.version.map(|_| self.version_offset);
+        check_fields(&self.fields, version_offset)?;
+        if self.version.is_none() && (!self.history.is_empty() || !self.migrations.is_empty()){
+            return Err(anyhow!("有旧版本或升级规则时需要设置version"));
+        }
+        if self.version == Some(0){
+            return Err(anyhow!("版本0表示空白卡片，不能作为布局版本"));
+        }
+        for old in &self.history{
+            if old.version == 0 || self.version == Some(old.version) || self.history.iter().filter(|o| o.version == old.version).count() > 1{
+                return Err(anyhow!("旧版本号错误:{}", old.version));
+            }
+            check_fields(&old.fields, version_offset).with_context(|| format!("版本{}", old.version))?;
+        }
+        for migration in &self.migrations{
+            let to = self.fields_of(migration.to).ok_or_else(|| anyhow!("升级规则的目标版本不存在:{}", migration.to))?;
+            let from = self.fields_of(migration.from).ok_or_else(|| anyhow!("升级规则的原版本不存在:{}", migration.from))?;
+            for (new, old) in &migration.rename{
+                if find_field(to, new).is_none() || find_field(from, old).is_none(){
+                    return Err(anyhow!("版本{}到{}的rename错误 {} <- {}", migration.from, migration.to, new, old));
+                }
+            }
+            //检查默认值能否编码
+            encode_fields(to, &mut vec![0u8; fields_size(to)], &migration.defaults)
+                .with_context(|| format!("版本{}到{}的默认值错误", migration.from, migration.to))?;
+        }
+        Ok(())
+    }
+
+    /// 把当前版本的布局数据解码为JSON，names为空时返回所有字段
+    pub fn decode(&self, bytes: &[u8], names: Option<&[String]>) -> Result<Map<String, Value>>{
+        decode_fields(&self.fields, bytes, names)
+    }
+
+    /// 把JSON中的字段编码到当前版本的布局数据中，没有出现的字段保持不变
+    pub fn encode(&self, bytes: &mut [u8], values: &Map<String, Value>) -> Result<()>{
+        encode_fields(&self.fields, bytes, values)
+    }
+
+    /// 把from版本的布局数据升级到当前版本，返回升级后的数据
+    pub fn upgrade(&self, bytes: &[u8], from: u8) -> Result<Vec<u8>>{
+        let current = self.version.ok_or_else(|| anyhow!("布局没有版本"))?;
+        let mut bytes = bytes.to_vec();
+        let mut version = from;
+        //防止规则循环
+        for _ in 0..=self.history.len(){
+            if version == current{
+                return Ok(bytes);
+            }
+            let old = self.fields_of(version).ok_or_else(|| anyhow!("未知的布局版本:{}", version))?;
+            let migration = self.migrations.iter().find(|migration| migration.from == version);
+            let to = migration.map(|migration| migration.to).unwrap_or(current);
+            let new = self.fields_of(to).ok_or_else(|| anyhow!("未知的布局版本:{}", to))?;
+
+            let values = decode_fields(old, &bytes, None)?;
+            let mut upgraded = Map::new();
+            for field in new{
+                let source = migration.and_then(|migration| migration.rename.get(&field.name)).unwrap_or(&field.name);
+                let value = values.get(source).or_else(|| migration.and_then(|migration| migration.defaults.get(&field.name)));
+                if let Some(value) = value{
+                    upgraded.insert(field.name.clone(), value.clone());
+                }
+            }
+            //旧字段和新字段的位置都清零，再按新版本编码，没有来源和默认值的新字段为0
+            for field in old.iter().chain(new){
+                bytes[field.offset..field.offset + field.size()].fill(0);
+            }
+            encode_fields(new, &mut bytes, &upgraded).with_context(|| format!("版本{}升级到{}失败", version, to))?;
+            bytes[self.version_offset] = to;
+            version = to;
+        }
+        Err(anyhow!("升级规则循环 版本:{}", from))
+    }
+}
+
+/// 按卡片类型配置的字段布局
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Layouts{
+    cards: HashMap<CardType, CardLayout>,
+}
+
+impl Layouts{
+    /// 从JSON文件加载，键为卡片类型，值为 [`CardLayout`]
+    pub fn load(path: impl AsRef<Path>) -> Result<Layouts>{
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("读取字段布局失败 {}", path.display()))?;
+        let layouts: Layouts = serde_json::from_str(&text).with_context(|| format!("字段布局格式错误 {}", path.display()))?;
+        for (card_type, layout) in &layouts.cards{
+            layout.check().with_context(|| format!("{:?}的字段布局错误", card_type))?;
+        }
+        Ok(layouts)
+    }
+
+    /// 卡片类型对应的布局
+    pub fn get(&self, card_type: CardType) -> Result<&CardLayout>{
+        self.cards.get(&card_type).ok_or_else(|| anyhow!("没有配置{:?}的字段布局", card_type))
+    }
+}
+
+/// 读取到的字段
+#[derive(Debug, Clone)]
+pub struct FieldValues{
+    /// 卡片上的布局版本，布局没有版本时为空
+    pub version: Option<u8>,
+    pub values: Map<String, Value>,
+}
+
+/// 读取字段，names为空时读取所有字段；卡片是旧版本时按旧版本的字段定义读取
+pub fn read_fields(card: &mut Card, layout: &CardLayout, names: Option<&[String]>) -> Result<FieldValues>{
+    let bytes = card.read_bytes(layout.offset(), layout.size())?;
+    let version = layout.card_version(&bytes);
+    let fields = match version{
+        Some(0) | None => &layout.fields,
+        Some(version) => layout.fields_of(version).ok_or_else(|| anyhow!("未知的布局版本:{}", version))?,
+    };
+    Ok(FieldValues{ version, values: decode_fields(fields, &bytes, names)? })
+}
+
+/// 写入JSON中的字段，其他字段保持不变，只写入内容变化的页，返回实际写入的页数；
+/// verify为true时回读校验。空白卡片写入时同时写入当前版本号，旧版本的卡片需要先升级
+pub fn write_fields(card: &mut Card, layout: &CardLayout, values: &Map<String, Value>, verify: bool) -> Result<usize>{
+    //先检查所有字段，有错误时不读写卡片
+    layout.encode(&mut vec![0u8; layout.size()], values)?;
+    card.bound(None, |card| {
+        let mut bytes = card.read_bytes(layout.offset(), layout.size())?;
+        match (layout.card_version(&bytes), layout.version){
+            (Some(0), Some(current)) => bytes[layout.version_offset] = current,
+            (Some(version), Some(current)) if version != current => {
+                return Err(anyhow!("卡片布局版本为{}，需要先升级到{}", version, current));
+            }
+            _ => ()
+        }
+        layout.encode(&mut bytes, values)?;
+        let written = card.write_bytes_diff(layout.offset(), &bytes, DiffBase::Cache)?;
+        if verify{
+            card.verify_bytes(layout.offset(), &bytes)?;
+        }
+        Ok(written)
+    })
+}
+
+/// 把旧版本的卡片升级到当前版本，所有字段一次写入并回读校验
+pub fn migrate_layout(card: &mut Card, layout: &CardLayout) -> Result<LayoutOutcome>{
+    let current = match layout.version{
+        Some(current) => current,
+        None => return Ok(LayoutOutcome::Unversioned),
+    };
+    card.bound(None, |card| {
+        let bytes = card.read_bytes(layout.offset(), layout.size())?;
+        let version = bytes[layout.version_offset];
+        if version == 0{
+            return Ok(LayoutOutcome::Blank);
+        }
+        if version == current{
+            return Ok(LayoutOutcome::Current);
+        }
+        let upgraded = layout.upgrade(&bytes, version)?;
+        card.write_bytes_diff(layout.offset(), &upgraded, DiffBase::Cache)?;
+        card.verify_bytes(layout.offset(), &upgraded)?;
+        Ok(LayoutOutcome::Migrated{ from: version, to: current })
+    })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn upgrade_zeroes_new_fields_without_source(){
+        let layout: CardLayout = serde_json::from_value(json!({
+            "version": 2,
+            "version_offset": 0,
+            "fields": [
+                { "name": "id", "type": "u16", "offset": 1 },
+                { "name": "points", "type": "u32", "offset": 4 },
+            ],
+            "history": [
+                { "version": 1, "fields": [{ "name": "id", "type": "u16", "offset": 1 }] },
+            ],
+        })).unwrap();
+        layout.check().unwrap();
+        //第4~7字节不属于版本1的任何字段，卡片上是残留的数据
+        let bytes = [1, 0x34, 0x12, 0, 0xAA, 0xBB, 0xCC, 0xDD];
+        let upgraded = layout.upgrade(&bytes, 1).unwrap();
+        assert_eq!(upgraded, [2, 0x34, 0x12, 0, 0, 0, 0, 0]);
+        let values = layout.decode(&upgraded, None).unwrap();
+        assert_eq!(values["id"], json!(0x1234));
+        assert_eq!(values["points"], json!(0));
+    }
+
+    fn layout(value: Value) -> CardLayout{
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn fields_round_trip(){
+        let layout = layout(json!({
+            "fields": [
+                { "name": "u8", "type": "u8", "offset": 0 },
+                { "name": "i8", "type": "i8", "offset": 1 },
+                { "name": "u16", "type": "u16", "offset": 2 },
+                { "name": "i16", "type": "i16", "offset": 4, "endian": "big" },
+                { "name": "u32", "type": "u32", "offset": 6, "endian": "big" },
+                { "name": "i32", "type": "i32", "offset": 10 },
+                { "name": "u64", "type": "u64", "offset": 14 },
+                { "name": "i64", "type": "i64", "offset": 22 },
+                { "name": "flag", "type": "bool", "offset": 30 },
+                { "name": "name", "type": "utf8", "offset": 31, "len": 8 },
+                { "name": "raw", "type": "bytes", "offset": 39, "len": 3 },
+                { "name": "expires", "type": "date", "offset": 42 },
+            ],
+        }));
+        layout.check().unwrap();
+        assert_eq!(layout.size(), 46);
+        let values = json!({
+            "u8": 255, "i8": -128, "u16": 0x1234, "i16": -2, "u32": 0x01020304u32, "i32": -100000,
+            "u64": u64::MAX, "i64": i64::MIN, "flag": true, "name": "张三", "raw": "a1b2c3", "expires": "2024-02-29",
+        });
+        let values = values.as_object().unwrap();
+        let mut bytes = vec![0u8; layout.size()];
+        layout.encode(&mut bytes, values).unwrap();
+        assert_eq!(&bytes[2..4], [0x34, 0x12]);
+        assert_eq!(&bytes[6..10], [1, 2, 3, 4]);
+        assert_eq!(&bytes[42..46], [0xE8, 0x07, 2, 29]);
+        assert_eq!(&layout.decode(&bytes, None).unwrap(), values);
+
+        let names = ["raw".to_string()];
+        assert_eq!(layout.decode(&bytes, Some(&names)).unwrap(), *json!({ "raw": "a1b2c3" }).as_object().unwrap());
+        assert!(layout.decode(&bytes, Some(&["missing".to_string()])).is_err());
+    }
+
+    #[test]
+    fn encode_rejects_bad_values(){
+        let layout = layout(json!({
+            "fields": [
+                { "name": "n", "type": "u8", "offset": 0 },
+                { "name": "s", "type": "i16", "offset": 1 },
+                { "name": "text", "type": "utf8", "offset": 3, "len": 2 },
+                { "name": "raw", "type": "bytes", "offset": 5, "len": 2 },
+                { "name": "day", "type": "date", "offset": 7 },
+            ],
+        }));
+        let mut bytes = vec![0u8; layout.size()];
+        for values in [
+            json!({ "n": 256 }), json!({ "n": -1 }), json!({ "s": 40000 }), json!({ "text": "abc" }),
+            json!({ "raw": "a1" }), json!({ "raw": "zz00" }), json!({ "day": "2023-02-29" }),
+            json!({ "day": "2024-13-01" }), json!({ "day": 20240101 }), json!({ "other": 1 }),
+        ]{
+            assert!(layout.encode(&mut bytes, values.as_object().unwrap()).is_err(), "{}", values);
+        }
+        assert_eq!(bytes, vec![0u8; layout.size()]);
+        //全0的日期为null
+        assert_eq!(layout.decode(&bytes, None).unwrap()["day"], Value::Null);
+    }
+
+    #[test]
+    fn check_rejects_bad_layouts(){
+        for value in [
+            json!({ "fields": [{ "name": "a", "type": "u32", "offset": 0 }, { "name": "b", "type": "u8", "offset": 3 }] }),
+            json!({ "fields": [{ "name": "a", "type": "u8", "offset": 0 }, { "name": "a", "type": "u8", "offset": 1 }] }),
+            json!({ "fields": [{ "name": "a", "type": "utf8", "offset": 0 }] }),
+            json!({ "version": 0, "fields": [] }),
+            json!({ "version": 1, "version_offset": 0, "fields": [{ "name": "a", "type": "u8", "offset": 0 }] }),
+            json!({ "fields": [], "history": [{ "version": 1, "fields": [] }] }),
+            json!({ "version": 2, "fields": [], "history": [{ "version": 2, "fields": [] }] }),
+            json!({ "version": 2, "fields": [], "migrations": [{ "from": 1, "to": 2 }] }),
+        ]{
+            assert!(layout(value.clone()).check().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn upgrade_renames_and_defaults(){
+        let layout = layout(json!({
+            "version": 3,
+            "version_offset": 0,
+            "fields": [
+                { "name": "member", "type": "u32", "offset": 1, "endian": "big" },
+                { "name": "level", "type": "u8", "offset": 5 },
+            ],
+            "history": [
+                { "version": 1, "fields": [{ "name": "id", "type": "u16", "offset": 1 }] },
+                { "version": 2, "fields": [{ "name": "member", "type": "u16", "offset": 2 }] },
+            ],
+            "migrations": [
+                { "from": 1, "to": 2, "rename": { "member": "id" } },
+                { "from": 2, "to": 3, "defaults": { "level": 7 } },
+            ],
+        }));
+        layout.check().unwrap();
+        let upgraded = layout.upgrade(&[1, 0x34, 0x12, 0, 0, 0], 1).unwrap();
+        assert_eq!(upgraded, [3, 0, 0, 0x12, 0x34, 7]);
+        assert_eq!(layout.upgrade(&upgraded, 3).unwrap(), upgraded);
+        assert!(layout.upgrade(&upgraded, 9).is_err());
+    }
+}
@@ -0,0 +1,15 @@
+//! 卡片用户数据区的存储格式
+
+mod document;
+mod files;
+mod frame;
+mod layout;
+mod order;
+mod slots;
+
+pub use document::{decode_document, encode_document, merge_patch, patch_document, read_document, write_document, DocumentWrite};
+pub use files::{delete_file, format_files, list_files, read_file, write_file, FileEntry, DEFAULT_MAX_FILES, MAX_NAME_LEN};
+pub use frame::{encode_frame, mark_signed, read_frame, try_read_frame, write_frame, ContentType, Frame, FRAME_HEADER_LEN, FRAME_VERSION};
+pub use layout::{migrate_layout, read_fields, write_fields, CardLayout, Endian, Field, FieldType, FieldValues, LayoutOutcome, LayoutVersion, Layouts, Migration};
+pub use order::{migrate_legacy, MigrateOutcome, StorageOrder};
+pub use slots::{read_record, write_record, Record, SlotLayout, DEFAULT_SLOT_CAPACITY};
@@ -0,0 +1,98 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use crate::ntag::{Card, DiffBase, PAGE_SIZE, USER_START_PAGE};
+
+/// 用户数据的字节顺序
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum StorageOrder{
+    /// 从第4页开始按原始顺序存储，手机和其他读卡器可以直接读取
+    #[default]
+    Natural,
+    /// 旧版格式，整段数据倒序存储
+    Legacy,
+}
+
+impl FromStr for StorageOrder{
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self>{
+        match s.to_ascii_lowercase().as_str(){
+            "natural" => Ok(StorageOrder::Natural),
+            "legacy" => Ok(StorageOrder::Legacy),
+            _ => Err(anyhow!("未知的字节顺序:{} 可选 Natural, Legacy", s))
+        }
+    }
+}
+
+impl StorageOrder{
+    /// 从第4页开始读取len个字节
+    pub fn read(&self, card: &mut Card, len: usize) -> Result<Vec<u8>>{
+        match self{
+            StorageOrder::Natural => card.read_bytes(USER_START_PAGE as usize * PAGE_SIZE, len),
+            StorageOrder::Legacy => card.read_data(len),
+        }
+    }
+
+    /// 从第4页开始写入
+    pub fn write(&self, card: &mut Card, data: &[u8]) -> Result<()>{
+        match self{
+            StorageOrder::Natural => card.write_bytes(USER_START_PAGE as usize * PAGE_SIZE, data),
+            StorageOrder::Legacy => card.write_data(data),
+        }
+    }
+
+    /// 差分写入: 只写入内容变化的页，返回实际写入的页数
+    pub fn write_diff(&self, card: &mut Card, data: &[u8], base: DiffBase) -> Result<usize>{
+        match self{
+            StorageOrder::Natural => card.write_bytes_diff(USER_START_PAGE as usize * PAGE_SIZE, data, base),
+            StorageOrder::Legacy => card.write_data_diff(data, base),
+        }
+    }
+
+    /// 回读校验刚写入的数据
+    pub fn verify(&self, card: &mut Card, data: &[u8]) -> Result<()>{
+        match self{
+            StorageOrder::Natural => card.verify_bytes(USER_START_PAGE as usize * PAGE_SIZE, data),
+            StorageOrder::Legacy => {
+                if card.read_data(data.len())? != data{
+                    return Err(anyhow!("回读校验失败"));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// 旧版卡片迁移结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum MigrateOutcome{
+    /// 检测到旧版倒序数据，已按原始顺序重写
+    Migrated,
+    /// 数据已经是原始顺序
+    AlreadyNatural,
+    /// 两种顺序都找不到数据头，未做修改
+    NotRecognized,
+}
+
+/// 检测旧版倒序存储的卡片并按原始顺序重写
+///
+/// 旧版数据没有长度信息，需要调用方提供数据长度len和数据开头的固定内容header，
+/// 按两种顺序读取后比较header来判断卡片格式。
+pub fn migrate_legacy(card: &mut Card, header: &[u8], len: usize) -> Result<MigrateOutcome>{
+    if header.is_empty() || header.len() > len{
+        return Err(anyhow!("数据头长度错误 header={} len={}", header.len(), len));
+    }
+    card.bound(None, |card| {
+        let data = StorageOrder::Natural.read(card, len)?;
+        if data.starts_with(header){
+            return Ok(MigrateOutcome::AlreadyNatural);
+        }
+        let legacy: Vec<u8> = data.into_iter().rev().collect();
+        if !legacy.starts_with(header){
+            return Ok(MigrateOutcome::NotRecognized);
+        }
+        StorageOrder::Natural.write(card, &legacy)?;
+        Ok(MigrateOutcome::Migrated)
+    })
+}
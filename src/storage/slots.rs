@@ -0,0 +1,219 @@
+//! 防撕裂的双槽存储
+//!
+//! 从start_page开始依次放两个槽，每个槽是10字节头部加数据:
+//! 魔数"TS"(2) | 序号 u32 LE | 数据长度 u16 LE | CRC16-XMODEM LE(序号、长度和数据)。
+//! 写入时总是覆盖较旧或已损坏的槽，另一个槽保持不变。卡片中途离开时被写的槽校验失败，
+//! 读取时回退到另一个槽，所以总能读到最后一条完整的记录。
+
+use anyhow::{anyhow, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use crc16::{State, XMODEM};
+use crate::ntag::{Card, PAGE_SIZE, USER_START_PAGE};
+
+const SLOT_MAGIC: [u8; 2] = *b"TS";
+const SLOT_HEADER_LEN: usize = 10;
+/// 默认每个槽的数据容量
+pub const DEFAULT_SLOT_CAPACITY: usize = 64;
+
+/// 双槽的位置和大小
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotLayout{
+    /// 第一个槽的起始页
+    pub start_page: u8,
+    /// 每个槽最多保存的数据字节数
+    pub capacity: usize,
+}
+
+impl Default for SlotLayout{
+    fn default() -> Self{
+        SlotLayout{ start_page: USER_START_PAGE, capacity: DEFAULT_SLOT_CAPACITY }
+    }
+}
+
+impl SlotLayout{
+    /// 每个槽占用的页数
+    pub fn slot_pages(&self) -> usize{
+        (SLOT_HEADER_LEN + self.capacity).div_ceil(PAGE_SIZE)
+    }
+
+    /// 槽的字节地址
+    fn slot_offset(&self, slot: usize) -> usize{
+        (self.start_page as usize + slot * self.slot_pages()) * PAGE_SIZE
+    }
+}
+
+/// 一条完整的记录
+#[derive(Debug, Clone)]
+pub struct Record{
+    /// 所在的槽 0 或 1
+    pub slot: usize,
+    /// 序号，每次写入加1
+    pub seq: u32,
+    pub data: Vec<u8>,
+}
+
+fn crc(seq_len: &[u8], data: &[u8]) -> u16{
+    let mut state = State::<XMODEM>::new();
+    state.update(seq_len);
+    state.update(data);
+    state.get()
+}
+
+/// 解析一个槽，魔数、长度或CRC不正确时为空
+fn parse_slot(slot: usize, bytes: &[u8], capacity: usize) -> Option<Record>{
+    if bytes.len() < SLOT_HEADER_LEN || bytes[..2] != SLOT_MAGIC{
+        return None;
+    }
+    let seq = LittleEndian::read_u32(&bytes[2..6]);
+    let len = LittleEndian::read_u16(&bytes[6..8]) as usize;
+    if len > capacity || SLOT_HEADER_LEN + len > bytes.len(){
+        return None;
+    }
+    let data = &bytes[SLOT_HEADER_LEN..SLOT_HEADER_LEN + len];
+    if LittleEndian::read_u16(&bytes[8..10]) != crc(&bytes[2..8], data){
+        return None;
+    }
+    Some(Record{ slot, seq, data: data.to_vec() })
+}
+
+/// 序号a是否比b新，允许序号回绕
+fn newer(a: u32, b: u32) -> bool{
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// 选出最后一条完整的记录
+fn latest(slots: [Option<Record>; 2]) -> Option<Record>{
+    match slots{
+        [Some(a), Some(b)] => Some(if newer(b.seq, a.seq){ b }else{ a }),
+        [a, b] => a.or(b),
+    }
+}
+
+/// 下一次写入的槽和序号，覆盖较旧或已损坏的槽
+fn next_slot(slots: &[Option<Record>; 2]) -> (usize, u32){
+    let (slot, seq) = match slots{
+        [Some(a), Some(b)] => if newer(b.seq, a.seq){ (0, b.seq) }else{ (1, a.seq) },
+        [Some(a), None] => (1, a.seq),
+        [None, Some(b)] => (0, b.seq),
+        [None, None] => (0, 0),
+    };
+    (slot, seq.wrapping_add(1))
+}
+
+/// 编码一个槽的头部和数据
+fn encode_slot(seq: u32, data: &[u8]) -> Vec<u8>{
+    let mut bytes = vec![0u8; SLOT_HEADER_LEN];
+    bytes[..2].copy_from_slice(&SLOT_MAGIC);
+    LittleEndian::write_u32(&mut bytes[2..6], seq);
+    LittleEndian::write_u16(&mut bytes[6..8], data.len() as u16);
+    let checksum = crc(&bytes[2..8], data);
+    LittleEndian::write_u16(&mut bytes[8..10], checksum);
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+/// 读取两个槽
+fn read_slots(card: &mut Card, layout: &SlotLayout) -> Result<[Option<Record>; 2]>{
+    let size = layout.slot_pages() * PAGE_SIZE;
+    let bytes = card.read_bytes(layout.slot_offset(0), size * 2)?;
+    Ok([
+        parse_slot(0, &bytes[..size], layout.capacity),
+        parse_slot(1, &bytes[size..], layout.capacity),
+    ])
+}
+
+/// 读取最后一条完整的记录，两个槽都无效时为空
+pub fn read_record(card: &mut Card, layout: &SlotLayout) -> Result<Option<Record>>{
+    Ok(latest(read_slots(card, layout)?))
+}
+
+/// 写入一条新记录，覆盖较旧的槽；verify为true时回读校验
+pub fn write_record(card: &mut Card, layout: &SlotLayout, data: &[u8], verify: bool) -> Result<Record>{
+    if data.len() > layout.capacity{
+        return Err(anyhow!("数据过长 每个槽最多{}字节", layout.capacity));
+    }
+    //选槽和写入必须是同一张卡片
+    card.bound(None, |card| {
+        let (slot, seq) = next_slot(&read_slots(card, layout)?);
+        let bytes = encode_slot(seq, data);
+
+        let offset = layout.slot_offset(slot);
+        card.write_bytes(offset, &bytes)?;
+        if verify{
+            card.verify_bytes(offset, &bytes)?;
+        }
+        Ok(Record{ slot, seq, data: data.to_vec() })
+    })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn parse(slot: usize, bytes: &[u8]) -> Option<Record>{
+        parse_slot(slot, bytes, DEFAULT_SLOT_CAPACITY)
+    }
+
+    #[test]
+    fn slot_round_trip(){
+        let record = parse(1, &encode_slot(7, b"hello")).unwrap();
+        assert_eq!((record.slot, record.seq, record.data.as_slice()), (1, 7, b"hello".as_slice()));
+        assert_eq!(parse(0, &encode_slot(1, &[])).unwrap().data, b"");
+    }
+
+    #[test]
+    fn corrupted_slot_is_rejected(){
+        let bytes = encode_slot(3, b"data");
+        for i in 0..bytes.len(){
+            let mut corrupted = bytes.clone();
+            corrupted[i] ^= 0x01;
+            assert!(parse(0, &corrupted).is_none(), "{}", i);
+        }
+        assert!(parse(0, &bytes[..bytes.len() - 1]).is_none());
+        assert!(parse_slot(0, &encode_slot(3, &[0; 65]), DEFAULT_SLOT_CAPACITY).is_none());
+    }
+
+    #[test]
+    fn sequence_wraps(){
+        assert!(newer(1, 0));
+        assert!(newer(0, u32::MAX));
+        assert!(!newer(u32::MAX, 0));
+        assert!(!newer(5, 5));
+        let old = parse(0, &encode_slot(u32::MAX, b"old"));
+        let new = parse(1, &encode_slot(0, b"new"));
+        assert_eq!(latest([old, new]).unwrap().data, b"new");
+    }
+
+    #[test]
+    fn writes_alternate_slots(){
+        let mut slots = [None, None];
+        for expected in [(0, 1), (1, 2), (0, 3), (1, 4)]{
+            let (slot, seq) = next_slot(&slots);
+            assert_eq!((slot, seq), expected);
+            slots[slot] = parse(slot, &encode_slot(seq, &[seq as u8]));
+            assert_eq!(latest(slots.clone()).unwrap().seq, seq);
+        }
+    }
+
+    #[test]
+    fn torn_write_falls_back(){
+        let a = encode_slot(1, b"first");
+        let b = encode_slot(2, b"second");
+        //第二次写入只写了一半
+        let mut torn = b.clone();
+        torn[b.len() / 2..].fill(0);
+        let slots = [parse(0, &a), parse(1, &torn)];
+        assert_eq!(latest(slots.clone()).unwrap().data, b"first");
+        //重新写入时覆盖损坏的槽，而不是唯一有效的槽
+        assert_eq!(next_slot(&slots), (1, 2));
+        assert_eq!(latest([parse(0, &a), parse(1, &b)]).unwrap().data, b"second");
+        assert!(latest([None, None]).is_none());
+    }
+
+    #[test]
+    fn slot_pages_round_up(){
+        let layout = SlotLayout{ start_page: 4, capacity: 7 };
+        assert_eq!(layout.slot_pages(), 5);
+        assert_eq!(layout.slot_offset(1), (4 + 5) * PAGE_SIZE);
+    }
+}
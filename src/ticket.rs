@@ -0,0 +1,176 @@
+//! NTAG21x一次性票券
+//!
+//! 发票时记录卡片UID、原厂签名(READ_SIG)和NFC计数器(READ_CNT)，刷卡验票时:
+//! UID不在票券库中为unknown；签名和发票时不同说明UID被复制到了其他芯片，为cloned；
+//! NFC计数器没有比上次增加说明是重放的通信记录，为replayed；已经使用过为used；否则标记为已使用，为accepted；
+//! 读取计数器或签名失败时为error。
+//! 计数器在卡片上电后第一次READ时加1，验票时先读取一页再读取计数器。
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use crate::crypto::card_uid;
+use crate::mirror::read_mirror;
+use crate::ntag::{now_ms, Card, TagModel};
+
+/// 票券库中的一张票
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Ticket{
+    /// 发票时读取的原厂签名
+    #[serde(with = "crate::hex_serde")]
+    pub signature: Vec<u8>,
+    /// 最近一次读取到的NFC计数器
+    pub counter: u32,
+    /// 发票时间(UNIX毫秒)
+    pub issued_at: u64,
+    /// 使用时间(UNIX毫秒)，未使用时为空
+    pub used_at: Option<u64>,
+}
+
+/// 验票结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TicketResult{
+    /// 有效，已标记为已使用
+    Accepted,
+    /// 已经使用过
+    Used,
+    /// 不在票券库中
+    Unknown,
+    /// NFC计数器没有增加，重放的通信记录
+    Replayed,
+    /// 原厂签名和发票时不同，UID被复制到了其他芯片
+    Cloned,
+    /// 读取计数器或签名失败，例如卡片中途离开、不是NTAG213/215/216
+    Error,
+}
+
+impl TicketResult{
+    /// 是否允许通过
+    pub fn accepted(self) -> bool{
+        self == TicketResult::Accepted
+    }
+}
+
+/// 一次验票
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TicketCheck{
+    /// UID(hex)
+    pub uid: String,
+    pub result: TicketResult,
+    /// 读取到的NFC计数器，unknown时为空
+    pub counter: Option<u32>,
+    /// 验票时间(UNIX毫秒)
+    pub checked_at: u64,
+    /// result为error时的错误信息
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl TicketCheck{
+    /// 验票出错时的结果
+    pub fn failed(uid: &[u8], err: &anyhow::Error) -> TicketCheck{
+        TicketCheck{ uid: hex::encode(uid), result: TicketResult::Error, counter: None, checked_at: now_ms(), error: Some(format!("{:#}", err)) }
+    }
+}
+
+/// 票券库，UID(hex) -> 票，修改后立即保存到文件
+#[derive(Debug, Default)]
+pub struct TicketDb{
+    path: Option<PathBuf>,
+    tickets: BTreeMap<String, Ticket>,
+}
+
+/// 读取任意一页让NFC计数器加1，再读取计数器
+fn read_counter(card: &mut Card) -> Result<u32>{
+    let model = card.detect_model()?;
+    if !matches!(model, TagModel::Ntag213 | TagModel::Ntag215 | TagModel::Ntag216){
+        return Err(anyhow!("票券只支持NTAG213/215/216卡片，当前型号:{:?}", model));
+    }
+    card.read_counter()
+}
+
+fn lock(db: &Mutex<TicketDb>) -> Result<MutexGuard<'_, TicketDb>>{
+    db.lock().map_err(|err| anyhow!("票券库锁定失败:{:?}", err))
+}
+
+impl TicketDb{
+    /// 从JSON文件加载，文件不存在时为空，之后的修改保存到这个文件
+    pub fn load(path: impl AsRef<Path>) -> Result<TicketDb>{
+        let path = path.as_ref();
+        let tickets = match std::fs::read_to_string(path){
+            Ok(text) => serde_json::from_str(&text).with_context(|| format!("票券库格式错误 {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(anyhow!("读取票券库失败 {} {:?}", path.display(), err)),
+        };
+        Ok(TicketDb{ path: Some(path.to_path_buf()), tickets })
+    }
+
+    fn save(&self) -> Result<()>{
+        if let Some(path) = &self.path{
+            let text = serde_json::to_string_pretty(&self.tickets)?;
+            std::fs::write(path, text).with_context(|| format!("保存票券库失败 {}", path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// 所有票
+    pub fn tickets(&self) -> &BTreeMap<String, Ticket>{
+        &self.tickets
+    }
+
+    /// 把当前卡片登记为一张未使用的票，已经登记过时重新发票；卡片需要打开NFC计数器。
+    /// 只在保存时锁定票券库，和卡片通信期间 /tickets 等请求不会被阻塞
+    pub fn issue(db: &Mutex<TicketDb>, card: &mut Card) -> Result<(String, Ticket)>{
+        card.bound(None, |card| {
+            let uid = hex::encode(card_uid(card)?);
+            if !read_mirror(card)?.counter_enabled{
+                return Err(anyhow!("卡片没有打开NFC计数器，先用 /mirror/set?counter=true 打开"));
+            }
+            let counter = read_counter(card)?;
+            let signature = card.read_signature()?;
+            let ticket = Ticket{ signature, counter, issued_at: now_ms(), used_at: None };
+            let mut db = lock(db)?;
+            db.tickets.insert(uid.clone(), ticket.clone());
+            db.save()?;
+            Ok((uid, ticket))
+        })
+    }
+
+    /// 验票，有效时标记为已使用。只在查找和更新票时锁定票券库，和卡片通信期间 /tickets 等请求不会被阻塞
+    pub fn validate(db: &Mutex<TicketDb>, card: &mut Card) -> Result<TicketCheck>{
+        card.bound(None, |card| {
+            let uid = hex::encode(card_uid(card)?);
+            let check = |result, counter| TicketCheck{ uid: uid.clone(), result, counter, checked_at: now_ms(), error: None };
+            if !lock(db)?.tickets.contains_key(&uid){
+                return Ok(check(TicketResult::Unknown, None));
+            }
+            let counter = read_counter(card)?;
+            let signature = card.read_signature()?;
+
+            //读卡期间票可能被重新发票或删除，按锁定后的记录判断
+            let mut db = lock(db)?;
+            let ticket = match db.tickets.get_mut(&uid){
+                Some(ticket) => ticket,
+                None => return Ok(check(TicketResult::Unknown, Some(counter))),
+            };
+            if signature != ticket.signature{
+                return Ok(check(TicketResult::Cloned, Some(counter)));
+            }
+            if counter <= ticket.counter{
+                return Ok(check(TicketResult::Replayed, Some(counter)));
+            }
+            ticket.counter = counter;
+            let result = if ticket.used_at.is_some(){
+                TicketResult::Used
+            }else{
+                ticket.used_at = Some(now_ms());
+                TicketResult::Accepted
+            };
+            db.save()?;
+            Ok(check(result, Some(counter)))
+        })
+    }
+}